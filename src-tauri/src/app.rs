@@ -2,6 +2,7 @@ use serde::Deserialize;
 use serde::Serialize;
 use serde_json::json;
 use serde_json::to_writer_pretty;
+use serde_json::Map;
 use serde_json::Value;
 use tauri::AppHandle;
 use tauri::Emitter;
@@ -10,26 +11,40 @@ use tauri_plugin_store::StoreExt;
 use tokio::runtime::Runtime;
 use std::fs::File;
 use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::Mutex;
 use std::sync::OnceLock;
 use lazy_static::lazy_static;
 
-use crate::devices::delay::Delay;
+use crate::devices::clock::Clock;
+use crate::devices::divider::Divider;
+use crate::devices::delay::{Delay, Feel};
 use crate::devices::device::Device;
 use crate::devices::input::Input;
-use crate::devices::mapper::Mapper;
+use crate::devices::mapper::{MatchMode, Mapper, Rule};
 use crate::devices::monitor::Monitor;
 use crate::devices::output::Output;
+use crate::devices::player::Player;
+use crate::devices::recorder::Recorder;
 use crate::devices::script::Script;
 use crate::devices::splitter::Splitter;
-use crate::devices::trigger::Trigger;
+use crate::devices::trigger::{Trigger, TriggerMessage, TriggerMode};
 #[cfg(not(windows))]
 use crate::devices::virtual_c::VirtualC;
 use crate::globals::EVT_ERROR;
+use crate::globals::PREFIX_INPUT;
+use crate::globals::PREFIX_OUTPUT;
+use crate::globals::EVT_SCRIPT_ERROR;
+use crate::globals::EVT_SCRIPT_LOG;
 use crate::globals::EVT_SETTINGS_CHANGE;
 use crate::hub::Connector;
 use crate::hub::Hub;
+use crate::hub::DEFAULT_MAX_HOPS;
+use crate::lua_library;
+use crate::shared;
 use crate::utils;
+use crate::utils::PortMatch;
 use crate::Settings;
 use crate::State;
 use crate::SETTINGS_FILE;
@@ -39,17 +54,150 @@ pub fn get_app<'a>() -> Option<&'a AppHandle> {
     APP_HANDLE.get()
 }
 
+// Set when running without the frontend, errors and script output are printed to stdout instead
+pub static HEADLESS: AtomicBool = AtomicBool::new(false);
+pub fn is_headless() -> bool {
+    HEADLESS.load(Ordering::Relaxed)
+}
+
+// device fields changed by the backend that the frontend nodes do not track
+const RUNTIME_FIELDS: [&str; 2] = ["store", "online"];
+
 // Initialize the Tokio runtime statically, using lazy_static
 lazy_static! {
     pub static ref TOKIO_RUNTIME: Mutex<Runtime> = Mutex::new(Runtime::new().unwrap());
 }
 
+/**
+ * Current project format, older projects are upgraded by MIGRATIONS
+ */
+pub const PROJECT_VERSION: u32 = 2;
+
+// migration from the version at each index to the next
+const MIGRATIONS: [fn(&mut Project); PROJECT_VERSION as usize] = [
+    migrate_mapper_rules,
+    migrate_io_ports,
+];
+
 // Project file frontend settings
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct Project {
+    #[serde(default)]
+    pub version: u32, // missing in projects saved before versioning
     pub preferences: Value,
     pub devices: Vec<Value>,
     pub connectors: Vec<Value>,
+    #[serde(default)]
+    pub shared: Map<String, Value>, // scripts shared key/value store
+    #[serde(default)]
+    pub library: Map<String, Value>, // embedded lua modules by name
+}
+
+/**
+ * Saved device fields used by the backend, frontend fields like positions are ignored
+ */
+#[derive(Deserialize)]
+#[serde(tag = "class", rename_all = "lowercase")]
+pub enum DeviceConfig {
+    Input(PortConfig),
+    Output(PortConfig),
+    Virtual {},
+    Monitor {},
+    Split {},
+    Map(MapperConfig),
+    Delay(DelayConfig),
+    Trigger(TriggerConfig),
+    Recorder(RecorderConfig),
+    Player(PlayerConfig),
+    Clock(ClockConfig),
+    Divider(DividerConfig),
+    Note {},
+    Script(ScriptConfig),
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct PortConfig {
+    pub label: Option<String>,
+    pub port: Option<String>,
+    pub port_match: Option<PortMatch>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct MapperConfig {
+    pub rules: Option<Vec<Rule>>,
+    pub mode: Option<MatchMode>,
+    pub pass_through: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct DelayConfig {
+    pub delay: Option<u64>,
+    pub sync: Option<bool>,
+    pub division: Option<u64>,
+    pub feel: Option<Feel>,
+    pub bpm: Option<f64>,
+    pub follow_clock: Option<bool>,
+    pub repeats: Option<u64>,
+    pub decay: Option<f64>,
+    pub transpose: Option<i64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct TriggerConfig {
+    pub messages: Option<Vec<TriggerMessage>>,
+    pub mode: Option<TriggerMode>,
+    pub interval: Option<u64>,
+    pub input: Option<Vec<u8>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct RecorderConfig {
+    pub format: Option<u64>,
+    pub ppq: Option<u64>,
+    pub bpm: Option<f64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct PlayerConfig {
+    pub path: Option<String>,
+    pub port_mode: Option<String>,
+    #[serde(rename = "loop")]
+    pub looping: Option<bool>,
+    pub tempo_scale: Option<f64>,
+    pub follow_clock: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ClockConfig {
+    pub bpm: Option<f64>,
+    pub swing: Option<f64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct DividerConfig {
+    pub multiply: Option<u64>,
+    pub divide: Option<u64>,
+    pub phase: Option<u64>,
+    pub smoothing: Option<f64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ScriptConfig {
+    pub script: Option<String>,
+    pub sandbox: Option<String>,
+    pub time_limit: Option<u64>,
+    pub instruction_limit: Option<u64>,
+    pub memory_limit: Option<u64>,
+    pub store: Option<Map<String, Value>>,
 }
 
 pub fn emit<T: Serialize + Clone>(event: &str, payload: T) {
@@ -57,11 +205,28 @@ pub fn emit<T: Serialize + Clone>(event: &str, payload: T) {
         app.emit(event, payload).unwrap_or_else(|e|
             eprintln!("failed to emit {} {:?}", event, e)
         );
+    } else if is_headless() {
+        print_event(event, serde_json::to_value(payload).unwrap_or_default());
+    }
+}
+
+/**
+ * Headless replacement for the frontend, only logs and errors are printed
+ */
+fn print_event(event: &str, payload: Value) {
+    let field = |key: &str| payload.get(key).and_then(Value::as_str).unwrap_or_default().to_string();
+    match event {
+        EVT_ERROR => println!("ERROR: {}", payload.as_str().unwrap_or_default()),
+        EVT_SCRIPT_LOG => println!("[{}] {}", field("id"), field("message")),
+        EVT_SCRIPT_ERROR => println!("[{}] ERROR: {}", field("id"), field("error")),
+        _ => {}
     }
 }
 
 pub fn emit_error(error: &str) {
-    eprintln!("ERROR: {}", error);
+    if !is_headless() {
+        eprintln!("ERROR: {}", error);
+    }
     emit(EVT_ERROR, json!(error));
 }
 
@@ -94,6 +259,7 @@ pub fn save_project_file() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 pub fn set_settings(settings: Settings) -> Result<(), Box<dyn std::error::Error>> {
+    lua_library::set_dirs(lua_library::library_dirs(&settings.project_path, &settings.script_library_path));
     if let Some(app) = get_app() {
         let store = app.store(SETTINGS_FILE).unwrap();
         store.set(String::from("settings"), serde_json::to_value(settings)?);
@@ -120,141 +286,337 @@ pub fn set_project_path(path: &str) -> Result<(), Box<dyn std::error::Error>> {
 }
 
 /**
+ * Saves the frontend project along with the backend runtime state
+ * and the script library modules
  */
-pub fn save_current_project(project: Project) -> Result<(), Box<dyn std::error::Error>> {
+pub fn save_current_project(mut project: Project) -> Result<(), Box<dyn std::error::Error>> {
+    project.version = PROJECT_VERSION; // saved from the current frontend nodes
+    merge_runtime_fields(&mut project);
+    project.library = if get_settings().embed_script_library {
+        lua_library::sources()
+    } else {
+        lua_library::embedded()
+    };
     let app = get_app().unwrap();
     let store = app.store(SETTINGS_FILE).unwrap();
     store.set("project", serde_json::to_value(project)?);
     Ok(())
 }
 
+/**
+ * Updates a project with the shared values and the device fields that only change in the backend
+ */
+pub fn merge_runtime_fields(project: &mut Project) {
+    project.shared = shared::snapshot();
+    let devices = Hub::call(|hub| hub.serialize_devices().unwrap_or_default()).unwrap_or_default();
+    for device in project.devices.iter_mut() {
+        let id = device.get("id").and_then(Value::as_str).unwrap_or_default();
+        let Some(current) = devices.iter().find(|d| d.get("id").and_then(Value::as_str) == Some(id)) else {
+            continue;
+        };
+        for field in RUNTIME_FIELDS {
+            if let (Some(value), Some(device)) = (current.get(field), device.as_object_mut()) {
+                device.insert(field.to_string(), value.clone());
+            }
+        }
+    }
+}
+
 pub fn new_empty_project() -> Result<(), Box<dyn std::error::Error>> {
-    let hub_instance = Hub::get_instance();
-    let mut hub = hub_instance.lock().unwrap();
-    hub.destroy();
+    Hub::call(|hub| hub.destroy())?;
+    shared::replace(Map::new());
+    lua_library::set_embedded(&Map::new());
 
     Ok(())
 }
 
 pub fn set_hub_paused(paused: bool) -> Result<(), Box<dyn std::error::Error>> {
-    let hub_instance = Hub::get_instance();
-    let mut hub = hub_instance.lock().unwrap();
-    hub.set_paused(paused);
+    Hub::call(move |hub| hub.set_paused(paused))?;
     let mut settings = get_settings();
     settings.hub_paused = paused;
     set_settings(settings)?;
     Ok(())
 }
 
+pub fn set_hub_max_hops(max_hops: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let hops = if max_hops > 0 { max_hops as usize } else { DEFAULT_MAX_HOPS };
+    Hub::call(move |hub| hub.set_max_hops(hops))?;
+    let mut settings = get_settings();
+    settings.hub_max_hops = max_hops;
+    set_settings(settings)?;
+    Ok(())
+}
+
 pub fn new_devices_project() -> Result<(), Box<dyn std::error::Error>> {
-    let hub_instance = Hub::get_instance();
-    let mut hub = hub_instance.lock().unwrap();
-    hub.destroy();
+    Hub::call(|hub| hub.destroy())?;
+    shared::replace(Map::new());
+    lua_library::set_embedded(&Map::new());
+    let devices = create_port_devices()?;
+    Hub::call(move |hub| {
+        for device in devices {
+            hub.add_device(device);
+        }
+    })?;
+    Ok(())
+}
 
+/**
+ * Opens every available port outside the hub dispatch thread
+ */
+fn create_port_devices() -> Result<Vec<Box<dyn Device>>, Box<dyn std::error::Error>> {
     let ports = utils::get_valid_midi_ports()?;
+    let mut devices: Vec<Box<dyn Device>> = vec![];
 
-    for name in ports.inputs {
-        let mut input = Input::new(&name);
+    for (i, name) in ports.inputs.iter().enumerate() {
+        let mut input = Input::with_port(&format!("Input {}", i + 1), name);
         input.init()?;
-        hub.add_device(Box::new(input));
+        devices.push(Box::new(input));
     }
 
-    for name in ports.outputs {
-        let mut output = Output::new(&name);
+    for (i, name) in ports.outputs.iter().enumerate() {
+        let mut output = Output::with_port(&format!("Output {}", i + 1), name);
         output.init()?;
-        hub.add_device(Box::new(output));
+        devices.push(Box::new(output));
     }
 
-    Ok(())
+    Ok(devices)
+}
+/**
+ * Creates devices and connectors from project file, devices that fail to load
+ * are reported and skipped, returns the project upgraded to the current version
+ */
+pub fn load_project(project: Project) -> Result<Project, Box<dyn std::error::Error>> {
+    let project = migrate_project(project)?;
+    for error in replace_project_devices(project.clone())? {
+        emit_error(&error);
+    }
+    Ok(project)
+}
+
+/**
+ * Upgrades older projects by running the migrations from their version onwards
+ */
+pub fn migrate_project(mut project: Project) -> Result<Project, String> {
+    if project.version > PROJECT_VERSION {
+        Err(format!("Project version {} is newer than the supported version {}", project.version, PROJECT_VERSION))?
+    }
+    for migration in &MIGRATIONS[project.version as usize..] {
+        migration(&mut project);
+    }
+    project.version = PROJECT_VERSION;
+    Ok(project)
+}
+
+fn class_of(device: &Value) -> &str {
+    device.get("class").and_then(Value::as_str).unwrap_or_default()
+}
+
+// version 1, mappers have a list of rules instead of a single rule
+fn migrate_mapper_rules(project: &mut Project) {
+    for device in project.devices.iter_mut().filter(|d| class_of(d) == "map") {
+        if let Some(device) = device.as_object_mut() {
+            if let Some(rule) = device.remove("rule").filter(|_| !device.contains_key("rules")) {
+                device.insert("rules".to_string(), json!([rule]));
+            }
+        }
+    }
+}
+
+// version 2, inputs and outputs store their midi port apart from the id
+fn migrate_io_ports(project: &mut Project) {
+    for device in project.devices.iter_mut() {
+        let prefix = match class_of(device) {
+            "input" => PREFIX_INPUT,
+            "output" => PREFIX_OUTPUT,
+            _ => continue
+        };
+        let id = device.get("id").and_then(Value::as_str).unwrap_or_default();
+        let port = id.strip_prefix(prefix).unwrap_or(id).to_string();
+        if let Some(device) = device.as_object_mut() {
+            device.entry("port").or_insert(json!(port));
+        }
+    }
+}
+
+fn set_config<T: Serialize>(device: &mut dyn Device, key: &str, value: Option<T>) -> Result<(), String> {
+    match value {
+        Some(value) => device.set_data(key.to_string(), json!(value)),
+        None => Ok(())
+    }
 }
+
 /**
- * Creates devices and connectors from project file
+ * Creates a device from its saved config, notes only exist in the frontend
  */
-pub fn load_project(project: Project) -> Result<(), Box<dyn std::error::Error>> {
-    let hub_instance = Hub::get_instance();
-    let mut hub = hub_instance.lock().unwrap();
-    hub.destroy();
+fn create_device(id: &str, config: DeviceConfig) -> Result<Option<Box<dyn Device>>, String> {
+    let device: Box<dyn Device> = match config {
+        DeviceConfig::Input(config) => {
+            let mut input = Input::new(id);
+            set_config(&mut input, "label", config.label)?;
+            set_config(&mut input, "port", config.port)?;
+            set_config(&mut input, "port_match", config.port_match)?;
+            if !input.online {
+                let _ = input.init(); // offline until the port watcher finds a matching port
+            }
+            Box::new(input)
+        },
+        DeviceConfig::Output(config) => {
+            let mut output = Output::new(id);
+            set_config(&mut output, "label", config.label)?;
+            set_config(&mut output, "port", config.port)?;
+            set_config(&mut output, "port_match", config.port_match)?;
+            if !output.online {
+                let _ = output.init(); // offline until the port watcher finds a matching port
+            }
+            Box::new(output)
+        },
+        #[cfg(not(windows))]
+        DeviceConfig::Virtual {} => {
+            let mut virtual_c = VirtualC::new(id);
+            virtual_c.init().map_err(|e| e.to_string())?;
+            Box::new(virtual_c)
+        },
+        #[cfg(windows)]
+        DeviceConfig::Virtual {} => Err("Virtual devices are not supported on windows")?,
+        DeviceConfig::Monitor {} => Box::new(Monitor::new(id)),
+        DeviceConfig::Split {} => Box::new(Splitter::new(id)),
+        DeviceConfig::Map(config) => {
+            let mut mapper = Mapper::new(id);
+            set_config(&mut mapper, "rules", config.rules)?;
+            set_config(&mut mapper, "mode", config.mode)?;
+            set_config(&mut mapper, "pass_through", config.pass_through)?;
+            Box::new(mapper)
+        },
+        DeviceConfig::Delay(config) => {
+            let mut delay = Delay::new(id);
+            set_config(&mut delay, "delay", config.delay)?;
+            set_config(&mut delay, "sync", config.sync)?;
+            set_config(&mut delay, "division", config.division)?;
+            set_config(&mut delay, "feel", config.feel)?;
+            set_config(&mut delay, "bpm", config.bpm)?;
+            set_config(&mut delay, "follow_clock", config.follow_clock)?;
+            set_config(&mut delay, "repeats", config.repeats)?;
+            set_config(&mut delay, "decay", config.decay)?;
+            set_config(&mut delay, "transpose", config.transpose)?;
+            Box::new(delay)
+        },
+        DeviceConfig::Trigger(config) => {
+            let mut trigger = Trigger::new(id);
+            set_config(&mut trigger, "messages", config.messages)?;
+            set_config(&mut trigger, "mode", config.mode)?;
+            set_config(&mut trigger, "interval", config.interval)?;
+            set_config(&mut trigger, "input", config.input)?;
+            Box::new(trigger)
+        },
+        DeviceConfig::Recorder(config) => {
+            let mut recorder = Recorder::new(id);
+            set_config(&mut recorder, "format", config.format)?;
+            set_config(&mut recorder, "ppq", config.ppq)?;
+            set_config(&mut recorder, "bpm", config.bpm)?;
+            Box::new(recorder)
+        },
+        DeviceConfig::Player(config) => {
+            let mut player = Player::new(id);
+            set_config(&mut player, "port_mode", config.port_mode)?;
+            set_config(&mut player, "loop", config.looping)?;
+            set_config(&mut player, "tempo_scale", config.tempo_scale)?;
+            set_config(&mut player, "follow_clock", config.follow_clock)?;
+            // a missing file should not prevent the rest of the project from loading
+            if let Some(path) = config.path.filter(|p| !p.is_empty()) {
+                if let Err(e) = player.set_data("path".to_string(), json!(path)) {
+                    emit_error(&e);
+                }
+            }
+            Box::new(player)
+        },
+        DeviceConfig::Clock(config) => {
+            let mut clock = Clock::new(id);
+            set_config(&mut clock, "bpm", config.bpm)?;
+            set_config(&mut clock, "swing", config.swing)?;
+            Box::new(clock)
+        },
+        DeviceConfig::Divider(config) => {
+            let mut divider = Divider::new(id);
+            set_config(&mut divider, "multiply", config.multiply)?;
+            set_config(&mut divider, "divide", config.divide)?;
+            set_config(&mut divider, "phase", config.phase)?;
+            set_config(&mut divider, "smoothing", config.smoothing)?;
+            Box::new(divider)
+        },
+        DeviceConfig::Note {} => return Ok(None),
+        DeviceConfig::Script(config) => {
+            let mut script = Script::new(id);
+            script.init().map_err(|e| e.to_string())?;
+            set_config(&mut script, "sandbox", config.sandbox)?;
+            set_config(&mut script, "time_limit", config.time_limit)?;
+            set_config(&mut script, "instruction_limit", config.instruction_limit)?;
+            set_config(&mut script, "memory_limit", config.memory_limit)?;
+            set_config(&mut script, "store", config.store)?;
+            let _ = script.set_data("script".to_string(), json!(config.script.unwrap_or_default())); // compile errors are reported as script errors
+            Box::new(script)
+        },
+    };
+    Ok(Some(device))
+}
 
-    let mut devices = project.devices.clone();
+/**
+ * Replaces the hub devices with the ones of a project, devices are created outside
+ * the hub dispatch thread since opening ports, loading files and running scripts
+ * would hold up live midi, the dispatch thread only swaps them in
+ */
+fn replace_project_devices(project: Project) -> Result<Vec<String>, String> {
+    Hub::call(|hub| hub.destroy())?;
+    shared::replace(project.shared.clone());
+    lua_library::set_embedded(&project.library);
+
+    let mut errors = vec![];
+    let mut configs = project.devices.clone();
     // sort devices such that virtual devices are added first
-    devices.sort_by(|a, b| {
-        let a_is_virtual = a.get("class").and_then(Value::as_str) == Some("virtual");
-        let b_is_virtual = b.get("class").and_then(Value::as_str) == Some("virtual");
+    configs.sort_by(|a, b| {
+        let a_is_virtual = class_of(a) == "virtual";
+        let b_is_virtual = class_of(b) == "virtual";
         b_is_virtual.cmp(&a_is_virtual)
     });
 
-    for d in devices {
-        let id = d.get("id").and_then(Value::as_str).unwrap_or_default();
-        let class = d.get("class").and_then(Value::as_str).unwrap_or_default();
-        match class {
-            "input" => {
-                let mut input = Input::new(id);
-                input.init().or_else(|_| -> Result<(), Box<dyn std::error::Error>> {
-                    input.destroy();
-                    Ok(())
-                })?;
-                hub.add_device(Box::new(input));
-            },
-            "output" => {
-                let mut output = Output::new(id);
-                output.init().or_else(|_| -> Result<(), Box<dyn std::error::Error>> {
-                    output.destroy();
-                    Ok(())
-                })?;
-                hub.add_device(Box::new(output));
-            },
-            #[cfg(not(windows))]
-            "virtual" => {
-                let mut virtual_c = VirtualC::new(id);
-                virtual_c.init().and_then(|_| {
-                    hub.add_device(Box::new(virtual_c));
-                    Ok(())
-                })?;
-            },
-            "monitor" => {
-                let monitor = Monitor::new(id);
-                hub.add_device(Box::new(monitor));
-            },
-            "split" => {
-                let splitter = Splitter::new(id);
-                hub.add_device(Box::new(splitter));
-            },
-            "map" => {
-                let mut mapper = Mapper::new(id);
-                mapper.set_data("rule".to_string(), d.get("rule").unwrap().clone())?;
-                hub.add_device(Box::new(mapper));
-            },
-            "delay" => {
-                let mut delay = Delay::new(id);
-                delay.set_data("delay".to_string(), d.get("delay").unwrap().clone())?;
-                hub.add_device(Box::new(delay));
-            },
-            "trigger" => {
-                let trigger = Trigger::new(id);
-                hub.add_device(Box::new(trigger));
-            },
-            "note" => {},
-            "script" => {
-                let mut script = Script::new(id);
-                script.init()?;
-                let empty = json!("");
-                let code = d.get("script").unwrap_or(&empty);
-                script.set_data("script".to_string(), code.clone())?;
-                hub.add_device(Box::new(script));
-            }
-            _ => {
-                eprintln!("Load file invalid device class {}", class);
-            }
+    let mut devices = vec![];
+    for d in configs {
+        let id = d.get("id").and_then(Value::as_str).unwrap_or_default().to_string();
+        let result = serde_json::from_value::<DeviceConfig>(d)
+            .map_err(|e| e.to_string())
+            .and_then(|config| create_device(&id, config));
+        match result {
+            Ok(Some(device)) => devices.push(device),
+            Ok(None) => {},
+            Err(err) => errors.push(format!("Failed to load device {}: {}", id, err)),
         }
     }
 
-    for connector in project.connectors {
+    let connectors = project.connectors;
+    errors.extend(Hub::call(move |hub| add_project_devices(hub, devices, connectors))?);
+    Ok(errors)
+}
+
+fn add_project_devices(hub: &mut Hub, devices: Vec<Box<dyn Device>>, connectors: Vec<Value>) -> Vec<String> {
+    let mut errors = vec![];
+    for device in devices {
+        hub.add_device(device);
+    }
+
+    for connector in connectors {
         if let Ok(c) = serde_json::from_value::<Connector>(connector.clone()) {
-            hub.connect(&c.from, &c.to, &c.from_port, &c.to_port);
+            if hub.creates_cycle(&c.from, &c.to, &c.from_port) {
+                errors.push(format!("Connector {} skipped, it creates a loop without a delay", c.id));
+                continue;
+            }
+            if let Some(added) = hub.connect(&c.from, &c.to, &c.from_port, &c.to_port) {
+                if let Err(err) = hub.set_connector_settings(&added.id, c.settings) {
+                    errors.push(format!("Invalid settings of connector {}: {}", c.id, err));
+                }
+            }
         }
     }
 
-    Ok(())
+    errors
 }
 
 #[cfg(test)]
@@ -269,23 +631,73 @@ mod tests {
     #[serial]
     fn new_empty_project () {
         super::new_empty_project().expect("");
-        let hub_instance = Hub::get_instance();
-        let hub = hub_instance.lock().unwrap();
-        let devices = hub.serialize_devices().expect("");
-        let connectors = hub.serialize_connectors().expect("");
+        let (devices, connectors) = Hub::call(|hub| {
+            (hub.serialize_devices().expect(""), hub.serialize_connectors().expect(""))
+        }).expect("");
 
         assert_eq!(devices.len(), 0);
         assert_eq!(connectors.len(), 0);
     }
 
+    #[test]
+    fn migrate_project () {
+        let project: Project = serde_json::from_value(json!({
+            "preferences": {},
+            "devices": [
+                { "id": "Map 1", "class": "map", "rule": { "input": {}, "output": {} } },
+                { "id": "Mdash In - Keys 24:0", "class": "input" },
+                { "id": "Mdash Out - Synth", "class": "output", "port": "Synth 2" }
+            ],
+            "connectors": []
+        })).expect("");
+        assert_eq!(project.version, 0);
+        let project = super::migrate_project(project).expect("");
+        assert_eq!(project.version, PROJECT_VERSION);
+        assert_eq!(project.devices[0]["rules"], json!([{ "input": {}, "output": {} }]));
+        assert!(project.devices[0].get("rule").is_none());
+        assert_eq!(project.devices[1]["port"], json!("Keys 24:0"));
+        assert_eq!(project.devices[2]["port"], json!("Synth 2"));
+
+        let newer = Project { version: PROJECT_VERSION + 1, ..Project::default() };
+        assert!(super::migrate_project(newer).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn load_project_errors () {
+        let project: Project = serde_json::from_value(json!({
+            "preferences": {},
+            "devices": [
+                { "id": "Delay 1", "class": "delay", "delay": "soon" },
+                { "id": "Clock 1", "class": "clock", "bpm": 5000 },
+                { "id": "Unknown 1", "class": "unknown" },
+                { "id": "Note 1", "class": "note", "note": "hello" },
+                { "id": "Monitor 1", "class": "monitor", "x": 10, "y": 20 },
+                { "id": "Delay 2", "class": "delay", "delay": 100, "name": "100ms" }
+            ],
+            "connectors": [
+                { "id": "", "from": "Monitor 1", "to": "Delay 2", "from_port": "*", "to_port": "*", "channels": 1 }
+            ]
+        })).expect("");
+        let errors = replace_project_devices(super::migrate_project(project).expect("")).expect("");
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors[0].starts_with("Failed to load device Delay 1"));
+        let (devices, connectors) = Hub::call(|hub| {
+            (hub.serialize_devices().expect(""), hub.serialize_connectors().expect(""))
+        }).expect("");
+        let ids: Vec<&str> = devices.iter().map(|d| d["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, vec!["Monitor 1", "Delay 2"]);
+        assert_eq!(connectors.len(), 1);
+        assert_eq!(connectors[0].settings.channels, 1);
+        super::new_empty_project().expect("");
+    }
+
     #[test]
     #[serial]
     fn new_devices_project () {
         let ports = utils::get_valid_midi_ports().expect("");
         super::new_devices_project().expect("");
-        let hub_instance = Hub::get_instance();
-        let hub = hub_instance.lock().unwrap();
-        let devices = hub.serialize_devices().expect("");
+        let devices = Hub::call(|hub| hub.serialize_devices().expect("")).expect("");
         assert_eq!(devices.len(), ports.inputs.len() + ports.outputs.len());
     }
 }
\ No newline at end of file
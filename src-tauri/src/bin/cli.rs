@@ -0,0 +1,56 @@
+use std::{fs, process::ExitCode, sync::atomic::Ordering};
+
+use mididash_lib::{app::{self, Project}, hub::Hub, lua_library, port_watcher};
+
+/**
+ * Runs a saved project without the frontend, usage:
+ * mididash-cli <project.json>
+ */
+fn main() -> ExitCode {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("Usage: mididash-cli <project.json>");
+        return ExitCode::FAILURE;
+    };
+
+    app::HEADLESS.store(true, Ordering::Relaxed);
+
+    if let Err(e) = run(&path) {
+        eprintln!("ERROR: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+fn run(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let file_content = fs::read_to_string(path).map_err(|e| format!("Failed to open file {}: {}", path, e))?;
+    let project: Project = serde_json::from_str(&file_content).map_err(|e| format!("Failed to parse JSON: {}", e))?;
+    lua_library::set_dirs(lua_library::library_dirs(path, ""));
+    app::load_project(project).map_err(|e| format!("Failed to load project: {}", e))?;
+    println!("Loaded project {}", path);
+    port_watcher::start();
+
+    // a separate runtime is used to wait for signals, the shared one is locked by devices when spawning tasks
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    runtime.block_on(wait_for_shutdown())?;
+
+    println!("Shutting down");
+    Hub::call(|hub| hub.destroy())?;
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        res = tokio::signal::ctrl_c() => res?,
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
@@ -1,13 +1,17 @@
 use crate::devices::delay::Delay;
 use crate::devices::script::Script;
 use crate::devices::trigger::Trigger;
+use crate::devices::recorder::Recorder;
+use crate::devices::player::Player;
+use crate::devices::clock::Clock;
+use crate::devices::divider::Divider;
 use crate::{app, utils};
 use std::fs;
-use crate::devices::{device::Device, input::Input, mapper::Mapper, monitor::Monitor, output::Output, splitter::Splitter};
+use crate::devices::{device::{Device, Job}, input::Input, mapper::Mapper, monitor::Monitor, output::Output, splitter::Splitter};
 use app::Project;
 use tauri_plugin_store::StoreExt;
 use crate::globals::EVT_PROJECT_NEW;
-use crate::hub::{Connector, Hub};
+use crate::hub::{Connector, ConnectorSettings, Hub};
 use serde_json::{json, Value};
 use crate::Settings;
 use crate::SETTINGS_FILE;
@@ -18,10 +22,9 @@ use crate::devices::virtual_c::VirtualC;
 pub fn new_devices_project() -> Result<(), String> {
     app::new_devices_project()
         .and_then(|_| {
-            let hub_instance = Hub::get_instance();
-            let hub = hub_instance.lock().unwrap();
-            let devices = hub.serialize_devices()?;
-            let connectors = hub.serialize_connectors()?;
+            let (devices, connectors) = Hub::call(|hub| {
+                Ok::<_, serde_json::Error>((hub.serialize_devices()?, hub.serialize_connectors()?))
+            })??;
             let mut project = Project::default();
             project.devices = devices;
             project.connectors = connectors.into_iter()
@@ -69,27 +72,39 @@ pub fn set_settings(settings: Settings) -> Result<(), String> {
 
 #[tauri::command]
 pub fn connect(from: String, to: String, from_port: String, to_port: String) -> Result<Connector, String> {
-    let hub_instance = Hub::get_instance();
-    let mut hub = hub_instance.lock().unwrap();
-    let connector = hub.connect(&from, &to, &from_port, &to_port);
+    let (connector, cycle) = Hub::call(move |hub| {
+        let cycle = hub.creates_cycle(&from, &to, &from_port);
+        (hub.connect(&from, &to, &from_port, &to_port), cycle)
+    })?;
     if let Some(connector) = connector {
         Ok(connector)
+    } else if cycle {
+        Err("Connector creates a loop without a delay".to_string())
     } else {
         Err("Duplicate connector".to_string())
     }
 }
 
+#[tauri::command]
+pub fn set_connector_settings(id: String, settings: ConnectorSettings) -> Result<Connector, String> {
+    Hub::call(move |hub| hub.set_connector_settings(&id, settings))?
+}
+
 #[tauri::command]
 pub fn set_hub_paused(paused: bool) -> Result<(), String> {
     app::set_hub_paused(paused).map_err(|err| format!("{}", err))?;
     Ok(())
 }
 
+#[tauri::command]
+pub fn set_hub_max_hops(max_hops: u64) -> Result<(), String> {
+    app::set_hub_max_hops(max_hops).map_err(|err| format!("{}", err))?;
+    Ok(())
+}
+
 #[tauri::command]
 pub fn disconnect(from: String, to: String, from_port: String, to_port: String) -> Result<(), String> {
-    let hub_instance = Hub::get_instance();
-    let mut hub = hub_instance.lock().unwrap();
-    if !hub.disconnect(&from, &to, &from_port, &to_port) {
+    if !Hub::call(move |hub| hub.disconnect(&from, &to, &from_port, &to_port))? {
         Err("Connector not found".to_string())
     } else {
         Ok(())
@@ -118,8 +133,8 @@ pub fn set_project_path(path: String) -> Result<Value, String> {
 pub fn open_project(path: String) -> Result<Value, String> {
     let file_content = fs::read_to_string(path.clone()).or_else(|_| Err("Failed to open file".to_string()))?;
     let project:Project = serde_json::from_str(&file_content).or_else(|_| Err("Failed to parse JSON"))?;
-    app::load_project(project.clone()).map_err(|e| format!("Failed to load project: {}", e))?;
-    app::save_current_project(project.clone()).map_err(|e| format!("Failed to save current project {}", e))?;
+    let project = app::load_project(project).map_err(|e| format!("Failed to load project: {}", e))?;
+    app::save_current_project(project).map_err(|e| format!("Failed to save current project {}", e))?;
     app::set_project_path(&path).or_else(|_| Err("Failed to set project path".to_string()))?;
     app::emit(EVT_PROJECT_NEW, json!(null));
     Ok(json!(true))
@@ -129,9 +144,10 @@ pub fn open_project(path: String) -> Result<Value, String> {
 pub fn get_project() -> Result<Value, String> {
     let app = app::get_app().unwrap();
     let store = app.store(SETTINGS_FILE).unwrap();
-    let project = store.get("project");
-    if let Some(project) = project {
-        Ok(project)
+    let project = store.get("project").and_then(|p| serde_json::from_value::<Project>(p).ok());
+    if let Some(mut project) = project {
+        app::merge_runtime_fields(&mut project); // devices online state may have changed since the project was saved
+        serde_json::to_value(project).map_err(|e| e.to_string())
     } else {
         Err("Failed to get latest project".to_string())?
     }
@@ -145,7 +161,7 @@ pub fn get_midi_ports() -> Result<Value, String> {
 }
 
 #[tauri::command]
-pub fn add_device(id: String, class: String) -> Result<Value, String> {
+pub fn add_device(id: String, class: String, port: Option<String>) -> Result<Value, String> {
     let mut device: Option<Box<dyn Device>> = None;
     match class.as_str() {
         "virtual" => {
@@ -159,10 +175,16 @@ pub fn add_device(id: String, class: String) -> Result<Value, String> {
             }
         },
         "input" => {
-            device = Some(Box::new(Input::new(&id)));
+            device = Some(match port {
+                Some(ref port) => Box::new(Input::with_port(&id, port)),
+                None => Box::new(Input::new(&id)) // id is the port
+            });
         },
         "output" => {
-            device = Some(Box::new(Output::new(&id)));
+            device = Some(match port {
+                Some(ref port) => Box::new(Output::with_port(&id, port)),
+                None => Box::new(Output::new(&id)) // id is the port
+            });
         },
         "monitor" => {
             device = Some(Box::new(Monitor::new(&id)));
@@ -181,6 +203,18 @@ pub fn add_device(id: String, class: String) -> Result<Value, String> {
         },
         "script" => {
             device = Some(Box::new(Script::new(&id)));
+        },
+        "recorder" => {
+            device = Some(Box::new(Recorder::new(&id)));
+        },
+        "player" => {
+            device = Some(Box::new(Player::new(&id)));
+        },
+        "clock" => {
+            device = Some(Box::new(Clock::new(&id)));
+        },
+        "divider" => {
+            device = Some(Box::new(Divider::new(&id)));
         }
         _ => Err(format!("Unknown device type {}", class))?
     };
@@ -189,55 +223,73 @@ pub fn add_device(id: String, class: String) -> Result<Value, String> {
         .or_else(|e| Err(format!("{:?}", e)))?;
     let res = device.serialize()
         .map_err(|e| format!("failed to serialize device {:?} ", e))?;
-    let hub_instance = Hub::get_instance();
-    let mut hub = hub_instance
-        .lock()
-        .map_err(|e| format!("Failed to acquire hub lock: {:?}", e))?;
-    if !hub.add_device(device) {
+    if !Hub::call(move |hub| hub.add_device(device))? {
         Err(format!("Device already exists {}", id))?;
     }
     Ok(res)
 }
 
-#[tauri::command]
-pub fn set_device_data(id: String, key: String, data: Value) -> Result<(), String> {
-    let hub_instance = Hub::get_instance();
-    let mut hub = hub_instance
-        .lock()
-        .map_err(|e| format!("Failed to acquire hub lock: {:?}", e))?;
+enum DeviceData {
+    Done(Option<Value>),
+    Pending(Job),
+}
 
-    hub.set_device_data(id, key, data)
+/**
+ * Gets or sets device data on the dispatch thread, the jobs of slow keys run here
+ * in between so live midi is not held up
+ */
+fn device_data(id: String, key: String, data: Option<Value>) -> Result<Option<Value>, String> {
+    let res = Hub::call({
+        let (id, key) = (id.clone(), key.clone());
+        move |hub| {
+            if let Some(job) = hub.start_device_data(&id, &key, data.as_ref().unwrap_or(&Value::Null))? {
+                return Ok(DeviceData::Pending(job));
+            }
+            match data {
+                Some(data) => hub.set_device_data(id, key, data).map(|_| DeviceData::Done(None)),
+                None => hub.get_device_data(id, key).map(DeviceData::Done),
+            }
+        }
+    })?;
+    match res? {
+        DeviceData::Done(value) => Ok(value),
+        DeviceData::Pending(job) => {
+            let result = job()?;
+            Hub::call(move |hub| hub.finish_device_data(&id, &key, result))?
+        }
+    }
 }
 
 #[tauri::command]
-pub fn hub_process(ts: u64, bytes: Vec<u8>, from: String, to: String, from_port: String, to_port: String) -> Result<(), String> {
-    let hub_instance = Hub::get_instance();
-    let mut hub = hub_instance
-        .lock()
-        .map_err(|e| format!("Failed to acquire hub lock: {:?}", e))?;
+pub fn set_device_data(id: String, key: String, data: Value) -> Result<(), String> {
+    device_data(id, key, Some(data)).map(|_| ())
+}
 
-    hub.process(ts, &bytes, &from, &to, &from_port, &to_port);
+/**
+ * Messages sent by the frontend are stamped on arrival with the hub clock
+ */
+#[tauri::command]
+pub fn hub_process(bytes: Vec<u8>, from: String, to: String, from_port: String, to_port: String) -> Result<(), String> {
+    Hub::send(utils::now_micros(), bytes, &from, &to, &from_port, &to_port);
     Ok(())
 }
 
 #[tauri::command]
-pub fn get_device_data(id: String, key: String) -> Result<Option<Value>, String> {
-    let hub_instance = Hub::get_instance();
-    let mut hub = hub_instance
-        .lock()
-        .map_err(|e| format!("Failed to acquire hub lock: {:?}", e))?;
+pub fn get_clock_origin() -> u64 {
+    utils::clock_origin_millis()
+}
 
-    hub.get_device_data(id, key)
+#[tauri::command]
+pub fn get_device_data(id: String, key: String) -> Result<Option<Value>, String> {
+    device_data(id, key, None)
 }
 
 #[tauri::command]
 pub fn get_device(id: String) -> Result<Value, String> {
-    let hub_instance = Hub::get_instance();
-    let hub = hub_instance
-        .lock()
-        .map_err(|e| format!("Failed to acquire hub lock: {:?}", e))?;
-
-    if let Some(device) = hub.serialize_device(&id) {
+    if let Some(device) = Hub::call({
+        let id = id.clone();
+        move |hub| hub.serialize_device(&id)
+    })? {
         Ok(device)
     } else {
         Err(format!("Device not found {}", id))
@@ -246,19 +298,24 @@ pub fn get_device(id: String) -> Result<Value, String> {
 
 #[tauri::command]
 pub fn reconnect_device(id: String) -> Result<Value, String> {
-    let hub_instance = Hub::get_instance();
-    let mut hub = hub_instance.lock().map_err(|e| format!("Failed to acquire hub lock: {:?}", e))?;
-    hub.reconnect_device(&id).map_err(|e| format!("Failed to reconnect device {:?}", e))?;
+    Hub::call(move |hub| hub.reconnect_device(&id).map_err(|e| format!("Failed to reconnect device {:?}", e)))??;
     Ok(json!(true))
 }
 
+#[tauri::command]
+pub fn rebind_device(id: String, port: String) -> Result<Value, String> {
+    Hub::call(move |hub| {
+        hub.rebind_device(&id, &port)?;
+        hub.serialize_device(&id).ok_or(format!("Device not found {}", id))
+    })?
+}
+
 #[tauri::command]
 pub fn remove_device(id: String) -> Result<Value, String> {
-    let hub_instance = Hub::get_instance();
-    let mut hub = hub_instance
-        .lock()
-        .map_err(|e| format!("Failed to acquire hub lock: {:?}", e))?;
-    if !hub.remove_device(&id) {
+    if !Hub::call({
+        let id = id.clone();
+        move |hub| hub.remove_device(&id)
+    })? {
         Err(format!("Failed to remove device {}", id))?;
     }
     Ok(json!(true))
@@ -0,0 +1,347 @@
+use serde::Serialize;
+use serde_json::{json, Value, Error};
+use std::{error::Error as StdErr, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+use crate::{devices::device::Device, hub::Hub, utils::{self, MidiMessage}};
+
+/*
+ * Generates midi clock at 24 pulses per quarter note with transport messages
+ */
+
+pub const CLOCK_PPQN: u64 = 24;
+const SPIN_THRESHOLD: Duration = Duration::from_micros(1500); // busy wait the last moments before a tick
+
+#[derive(Default)]
+struct JitterStats {
+    ticks: u64,
+    sum: f64,
+    sum_sq: f64,
+    max: f64,
+}
+
+/**
+ * State shared with the timer thread
+ */
+struct Shared {
+    running: AtomicBool,
+    bpm: AtomicU64, // f64 bits
+    swing: AtomicU64, // f64 bits
+    position: AtomicU64, // clock ticks since song start
+    stats: Mutex<JitterStats>,
+}
+
+#[derive(Serialize)]
+pub struct Clock {
+    pub id: String,
+    pub class: String,
+    pub bpm: f64,
+    pub swing: f64, // percentage of an 8th note taken by its first 16th, 50 is straight
+    #[serde(skip_serializing)]
+    shared: Arc<Shared>,
+    #[serde(skip_serializing)]
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Clock {
+    pub fn new(id: &str) -> Self {
+        let bpm = 120.0;
+        let swing = 50.0;
+        Clock {
+            id: String::from(id),
+            class: String::from("clock"),
+            bpm,
+            swing,
+            shared: Arc::new(Shared {
+                running: AtomicBool::new(false),
+                bpm: AtomicU64::new(f64::to_bits(bpm)),
+                swing: AtomicU64::new(f64::to_bits(swing)),
+                position: AtomicU64::new(0),
+                stats: Mutex::new(JitterStats::default()),
+            }),
+            thread: None,
+        }
+    }
+
+    fn send(&self, msg: MidiMessage) {
+        Hub::send(utils::now_micros(), msg.to_bytes(), &self.id, "*", "*", "*");
+    }
+
+    fn run(&mut self) {
+        if self.thread.is_some() {
+            return;
+        }
+        *self.shared.stats.lock().unwrap() = JitterStats::default();
+        self.shared.running.store(true, Ordering::Relaxed);
+        let shared = Arc::clone(&self.shared);
+        let id = self.id.clone();
+        let thread = thread::Builder::new()
+            .name(format!("clock-{}", id))
+            .spawn(move || {
+                let mut deadline = Instant::now();
+                while shared.running.load(Ordering::Relaxed) {
+                    if !wait_until(deadline, &shared.running) {
+                        break;
+                    }
+                    let now = Instant::now();
+                    Hub::send(utils::now_micros(), MidiMessage::Clock.to_bytes(), &id, "*", "*", "*");
+                    deadline = advance(&shared, deadline, now);
+                }
+            });
+        match thread {
+            Ok(thread) => self.thread = Some(thread),
+            Err(e) => {
+                self.shared.running.store(false, Ordering::Relaxed);
+                eprintln!("Failed to start clock thread {}", e);
+            }
+        }
+    }
+
+    fn halt(&mut self) {
+        self.shared.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+
+    fn stats(&self) -> Value {
+        let stats = self.shared.stats.lock().unwrap();
+        let count = stats.ticks.max(1) as f64;
+        let mean = stats.sum / count;
+        json!({
+            "ticks": stats.ticks,
+            "mean": mean,
+            "max": stats.max,
+            "deviation": (stats.sum_sq / count - mean * mean).max(0.0).sqrt(),
+        })
+    }
+}
+
+/**
+ * Time between a clock tick and the next, swing delays every second 16th note
+ */
+pub fn tick_interval(bpm: f64, swing: f64, tick: u64) -> Duration {
+    let interval = 60.0 / (bpm.max(1.0) * CLOCK_PPQN as f64);
+    let swing = swing.clamp(0.0, 100.0) / 100.0;
+    let ratio = if tick % 12 < 6 { swing } else { 1.0 - swing };
+    Duration::from_secs_f64(interval * 2.0 * ratio)
+}
+
+/**
+ * Records how late a tick was sent and returns the deadline of the next one,
+ * deadlines follow the tempo so late ticks do not delay the following ones
+ */
+fn advance(shared: &Shared, deadline: Instant, now: Instant) -> Instant {
+    let late = now.saturating_duration_since(deadline).as_secs_f64() * 1_000_000.0;
+    let tick = shared.position.fetch_add(1, Ordering::Relaxed);
+    {
+        let mut stats = shared.stats.lock().unwrap();
+        stats.ticks += 1;
+        stats.sum += late;
+        stats.sum_sq += late * late;
+        stats.max = stats.max.max(late);
+    }
+    let bpm = f64::from_bits(shared.bpm.load(Ordering::Relaxed));
+    let swing = f64::from_bits(shared.swing.load(Ordering::Relaxed));
+    deadline + tick_interval(bpm, swing, tick)
+}
+
+/**
+ * Sleeps until close to the deadline and spins the remaining time,
+ * returns false if the clock was stopped while waiting
+ */
+fn wait_until(deadline: Instant, running: &AtomicBool) -> bool {
+    loop {
+        if !running.load(Ordering::Relaxed) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        let remaining = deadline - now;
+        if remaining > SPIN_THRESHOLD {
+            thread::park_timeout(remaining - SPIN_THRESHOLD);
+        } else {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+impl Device for Clock {
+    fn get_id(&self) -> &str { &self.id }
+    fn get_class(&self) -> &str { &self.class }
+    fn destroy(&mut self) {
+        self.halt();
+    }
+    fn get_data(&mut self, key: String) -> Result<Option<Value>, String> {
+        match key.as_str() {
+            "stats" => Ok(Some(self.stats())),
+            "status" => Ok(Some(json!({
+                "running": self.shared.running.load(Ordering::Relaxed),
+                "position": self.shared.position.load(Ordering::Relaxed) / 6, // 16th notes
+            }))),
+            _ => Ok(None)
+        }
+    }
+    fn set_data(&mut self, key: String, data: Value) -> Result<(), String> {
+        match key.as_str() {
+            "bpm" => {
+                self.bpm = data.as_f64().filter(|b| *b >= 1.0 && *b <= 1000.0).ok_or("Invalid bpm")?;
+                self.shared.bpm.store(f64::to_bits(self.bpm), Ordering::Relaxed);
+            },
+            "swing" => {
+                self.swing = data.as_f64().filter(|s| *s >= 50.0 && *s <= 75.0).ok_or("Invalid swing, expected 50 to 75")?;
+                self.shared.swing.store(f64::to_bits(self.swing), Ordering::Relaxed);
+            },
+            "start" => {
+                self.halt();
+                self.shared.position.store(0, Ordering::Relaxed);
+                self.send(MidiMessage::Start);
+                self.run();
+            },
+            "continue" if self.thread.is_none() => {
+                self.send(MidiMessage::Continue);
+                self.run();
+            },
+            "stop" => {
+                self.halt();
+                self.send(MidiMessage::Stop);
+            },
+            "position" => { // song position in 16th notes, only sent while stopped
+                let position = data.as_u64().filter(|p| *p < 0x4000).ok_or("Invalid song position")?;
+                if self.thread.is_some() {
+                    Err("Song position can only be set while stopped")?
+                }
+                self.shared.position.store(position * 6, Ordering::Relaxed);
+                self.send(MidiMessage::SongPosition(position as u16));
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+    fn delete_data(&mut self, _key: String) -> Result<(), String> { Ok(()) }
+    fn init(&mut self) -> Result<(), Box<dyn StdErr>> { Ok(()) }
+
+    fn serialize(&self) -> Result<Value, Error> {
+        serde_json::to_value(self)
+    }
+
+    fn process(
+        &mut self,
+        _ts: u64,
+        _msg: &MidiMessage,
+        _from: &str,
+        _to: &str,
+        _from_port: &str,
+        _to_port: &str
+    ) -> Vec<(String, Vec<u8>)> {
+        vec![]
+    }
+}
+
+// the timer thread must not outlive the device
+impl Drop for Clock {
+    fn drop(&mut self) {
+        self.halt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intervals () {
+        let tick = tick_interval(120.0, 50.0, 0);
+        assert_eq!(tick.as_micros(), 20833);
+        let long = tick_interval(120.0, 75.0, 5);
+        let short = tick_interval(120.0, 75.0, 6);
+        assert_eq!(long.as_micros(), 31250);
+        assert_eq!(short.as_micros(), 10416);
+        let eighth: Duration = (0..12).map(|t| tick_interval(120.0, 66.0, t)).sum();
+        assert_eq!(eighth.as_millis(), 250);
+    }
+
+    #[test]
+    fn validate () {
+        let mut clock = Clock::new("");
+        assert!(clock.set_data("bpm".to_string(), json!(0)).is_err());
+        assert!(clock.set_data("swing".to_string(), json!(90)).is_err());
+        clock.set_data("bpm".to_string(), json!(98.5)).unwrap();
+        clock.set_data("position".to_string(), json!(16)).unwrap();
+        let status = clock.get_data("status".to_string()).unwrap().unwrap();
+        assert_eq!(status["position"], 16);
+        assert_eq!(Device::serialize(&clock).unwrap()["bpm"], 98.5);
+    }
+
+    #[test]
+    fn ticks () {
+        let mut clock = Clock::new("");
+        clock.set_data("bpm".to_string(), json!(600)).unwrap();
+        clock.set_data("swing".to_string(), json!(75)).unwrap();
+        let start = Instant::now();
+        let mut deadline = start;
+        for late in [0, 100, 500, 0, 200, 0, 0] {
+            deadline = advance(&clock.shared, deadline, deadline + Duration::from_micros(late));
+        }
+        // lateness is measured but does not move the next ticks
+        let expected: Duration = (0..7).map(|t| tick_interval(600.0, 75.0, t)).sum();
+        assert_eq!(deadline - start, expected);
+        assert_eq!(clock.shared.position.load(Ordering::Relaxed), 7);
+        let stats = clock.get_data("stats".to_string()).unwrap().unwrap();
+        assert_eq!(stats["ticks"], 7);
+        assert_eq!(stats["mean"], 800.0 / 7.0);
+        assert_eq!(stats["max"], 500.0);
+    }
+
+    #[test]
+    fn run () {
+        let mut clock = Clock::new("clock-test");
+        clock.set_data("bpm".to_string(), json!(600)).unwrap();
+        clock.set_data("start".to_string(), json!(null)).unwrap();
+        assert!(clock.set_data("position".to_string(), json!(0)).is_err());
+        let ticks = |clock: &mut Clock| clock.get_data("stats".to_string()).unwrap().unwrap()["ticks"].as_u64().unwrap();
+        for _ in 0..1000 {
+            if ticks(&mut clock) >= 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        clock.set_data("stop".to_string(), json!(null)).unwrap();
+        let stopped = ticks(&mut clock);
+        assert!(stopped >= 3);
+        let status = clock.get_data("status".to_string()).unwrap().unwrap();
+        assert_eq!(status["running"], false);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(ticks(&mut clock), stopped); // the timer thread has ended
+    }
+
+    #[test]
+    fn remove_running () {
+        let mut hub = crate::hub::Hub::new();
+        let mut clock = Clock::new("clock-test-remove");
+        clock.set_data("bpm".to_string(), json!(600)).unwrap();
+        clock.set_data("start".to_string(), json!(null)).unwrap();
+        let shared = Arc::clone(&clock.shared);
+        let ticks = || shared.stats.lock().unwrap().ticks;
+        hub.add_device(Box::new(clock));
+        for _ in 0..1000 {
+            if ticks() >= 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(hub.remove_device("clock-test-remove"));
+        assert!(!shared.running.load(Ordering::Relaxed));
+        let removed = ticks();
+        assert!(removed >= 2);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(ticks(), removed); // the timer thread has ended
+
+        let mut clock = Clock::new("clock-test-drop");
+        clock.set_data("start".to_string(), json!(null)).unwrap();
+        let shared = Arc::clone(&clock.shared);
+        drop(clock);
+        assert!(!shared.running.load(Ordering::Relaxed));
+    }
+}
@@ -1,18 +1,44 @@
-use serde::Serialize;
-use serde_json::{Value, Error};
-use tokio::time::sleep;
-use std::{error::Error as StdErr, time::Duration};
-use crate::{app::TOKIO_RUNTIME, devices::device::Device, hub::Hub};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value, Error};
+use std::error::Error as StdErr;
+use crate::{devices::{clock::CLOCK_PPQN, device::Device}, globals::PORT_DRY, scheduler, utils::MidiMessage};
 
 /*
- * Delays midi inputs and outputs to display on the viewport
+ * Delays midi messages by a fixed time or a note value, repeats are sent on the default
+ * port while the dry port passes the input through right away
  */
 
+const MAX_REPEATS: u64 = 32;
+const MAX_TRANSPOSE: i64 = 24;
+const CLOCK_SMOOTHING: f64 = 0.2; // weight of the latest clock interval in the tempo average
+const MAX_CLOCK_GAP: u64 = 250_000; // micros, longer gaps between clock ticks restart the tempo measure
+
+#[derive(Default, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Feel {
+    #[default]
+    Straight,
+    Dotted,
+    Triplet,
+}
+
 #[derive(Serialize)]
 pub struct Delay {
     pub id: String,
     pub class: String,
-    pub delay: u64,
+    pub delay: u64, // millis, used when not synced
+    pub sync: bool, // delay by a note value instead of millis
+    pub division: u64, // note value, 4 is a quarter note and 8 an eighth
+    pub feel: Feel,
+    pub bpm: f64,
+    pub follow_clock: bool, // measure the tempo from incoming clock
+    pub repeats: u64,
+    pub decay: f64, // fraction of the velocity lost on each repeat
+    pub transpose: i64, // semitones added on each repeat
+    #[serde(skip_serializing)]
+    last_clock: Option<u64>,
+    #[serde(skip_serializing)]
+    clock_interval: Option<f64>, // smoothed micros between clock ticks
 }
 
 impl Delay {
@@ -21,6 +47,80 @@ impl Delay {
             id: String::from(id),
             class: String::from("delay"),
             delay: 1000,
+            sync: false,
+            division: 4,
+            feel: Feel::Straight,
+            bpm: 120.0,
+            follow_clock: false,
+            repeats: 1,
+            decay: 0.0,
+            transpose: 0,
+            last_clock: None,
+            clock_interval: None,
+        }
+    }
+
+    /**
+     * Measured clock tempo when following the clock, the internal bpm otherwise
+     */
+    pub fn tempo(&self) -> f64 {
+        match self.clock_interval.filter(|_| self.follow_clock) {
+            Some(interval) => 60_000_000.0 / (interval * CLOCK_PPQN as f64),
+            None => self.bpm
+        }
+    }
+
+    /**
+     * Time between repeats in micros
+     */
+    pub fn interval(&self) -> u64 {
+        if !self.sync {
+            return self.delay * 1000;
+        }
+        let quarter = 60_000_000.0 / self.tempo();
+        let feel = match self.feel {
+            Feel::Straight => 1.0,
+            Feel::Dotted => 1.5,
+            Feel::Triplet => 2.0 / 3.0,
+        };
+        (quarter * 4.0 / self.division as f64 * feel).round() as u64
+    }
+
+    fn clock_tick(&mut self, ts: u64) {
+        if let Some(interval) = self.last_clock.map(|last| ts.saturating_sub(last)) {
+            if interval == 0 || interval > MAX_CLOCK_GAP {
+                self.clock_interval = None;
+            } else {
+                let interval = interval as f64;
+                self.clock_interval = Some(match self.clock_interval {
+                    Some(average) => average + (interval - average) * CLOCK_SMOOTHING,
+                    None => interval
+                });
+            }
+        }
+        self.last_clock = Some(ts);
+    }
+
+    /**
+     * Message sent on a repeat, None if the transposed note is out of range
+     */
+    fn echo(&self, msg: &MidiMessage, repeat: u64) -> Option<MidiMessage> {
+        let shift = self.transpose * repeat as i64;
+        let transpose = |note: u8| u8::try_from(note as i64 + shift).ok().filter(|n| *n < 128);
+        match *msg {
+            MidiMessage::NoteOn { channel, note, velocity } => {
+                let gain = (1.0 - self.decay).powi(repeat as i32);
+                // zero velocity is a note off, decayed notes keep sounding
+                let velocity = if velocity == 0 { 0 } else { (velocity as f64 * gain).round().clamp(1.0, 127.0) as u8 };
+                Some(MidiMessage::NoteOn { channel, note: transpose(note)?, velocity })
+            },
+            MidiMessage::NoteOff { channel, note, velocity } => {
+                Some(MidiMessage::NoteOff { channel, note: transpose(note)?, velocity })
+            },
+            MidiMessage::Aftertouch { channel, note, pressure } => {
+                Some(MidiMessage::Aftertouch { channel, note: transpose(note)?, pressure })
+            },
+            _ => Some(msg.clone())
         }
     }
 }
@@ -32,11 +132,53 @@ impl Device for Delay {
     fn get_class(&self) -> &str {
         return &self.class;
     }
-    fn destroy(&mut self) {}
-    fn get_data(&mut self, _key: String) -> Result<Option<Value>, String> { Ok(None) }
+    fn destroy(&mut self) {
+        scheduler::cancel(&self.id);
+    }
+    fn defers_output(&self, port: &str) -> bool {
+        port != PORT_DRY
+    }
+    fn get_data(&mut self, key: String) -> Result<Option<Value>, String> {
+        match key.as_str() {
+            "status" => Ok(Some(json!({
+                "bpm": self.tempo(),
+                "interval": self.interval() / 1000,
+            }))),
+            _ => Ok(None)
+        }
+    }
     fn set_data(&mut self, key: String, data:Value) -> Result<(), String> {
-        if key == "delay" {
-            self.delay = data.as_u64().unwrap();
+        match key.as_str() {
+            "delay" => {
+                self.delay = data.as_u64().ok_or("Invalid delay")?;
+            },
+            "sync" => {
+                self.sync = data.as_bool().ok_or("Invalid sync value")?;
+            },
+            "division" => {
+                self.division = data.as_u64().filter(|d| [1, 2, 4, 8, 16, 32, 64].contains(d)).ok_or("Invalid note value")?;
+            },
+            "feel" => {
+                self.feel = serde_json::from_value(data).map_err(|_| "Invalid feel, expected straight, dotted or triplet")?;
+            },
+            "bpm" => {
+                self.bpm = data.as_f64().filter(|b| *b >= 1.0 && *b <= 1000.0).ok_or("Invalid bpm")?;
+            },
+            "follow_clock" => {
+                self.follow_clock = data.as_bool().ok_or("Invalid follow clock value")?;
+                self.last_clock = None;
+                self.clock_interval = None;
+            },
+            "repeats" => {
+                self.repeats = data.as_u64().filter(|r| *r >= 1 && *r <= MAX_REPEATS).ok_or("Invalid repeats")?;
+            },
+            "decay" => {
+                self.decay = data.as_f64().filter(|d| (0.0..=1.0).contains(d)).ok_or("Invalid decay, expected 0 to 1")?;
+            },
+            "transpose" => {
+                self.transpose = data.as_i64().filter(|t| t.abs() <= MAX_TRANSPOSE).ok_or("Invalid transpose")?;
+            },
+            _ => {}
         }
         Ok(())
     }
@@ -50,24 +192,93 @@ impl Device for Delay {
     }
     fn process(
         &mut self,
-        bytes: &Vec<u8>,
+        ts: u64,
+        msg: &MidiMessage,
         _from: &str,
         _to: &str,
         _from_port: &str,
         _to_port: &str
     ) -> Vec<(String, Vec<u8>)> {
-        let delay = self.delay;
-        let bytes = bytes.clone();
-        let id = self.id.clone();
-        let runtime = TOKIO_RUNTIME.lock().unwrap();
+        let dry = vec![(PORT_DRY.to_string(), msg.to_bytes())];
+        if self.follow_clock && *msg == MidiMessage::Clock {
+            self.clock_tick(ts); // the tempo source is not repeated
+            return dry;
+        }
+        // scheduled from the message timestamp so processing latency does not add up
+        let interval = self.interval();
+        for repeat in 1..=self.repeats {
+            if let Some(echo) = self.echo(msg, repeat) {
+                scheduler::schedule(ts + interval * repeat, echo.to_bytes(), &self.id, "*");
+            }
+        }
+        dry
+    }
+}
 
-        runtime.spawn(async move {
-            sleep(Duration::from_millis(delay)).await;
-            let hub_instance = Hub::get_instance();
-            let mut hub = hub_instance.lock().unwrap();
-            hub.process(0, &bytes, &id, "*", "*", "*");
-        });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils;
 
-        vec![]
+    #[test]
+    fn destroy_cancels () {
+        let mut delay = Delay::new("delay-test");
+        delay.set_data("delay".to_string(), json!(60_000)).unwrap();
+        assert!(delay.set_data("delay".to_string(), json!("soon")).is_err());
+        let res = delay.process(utils::now_micros(), &MidiMessage::Clock, "*", "*", "*", "*");
+        assert_eq!(res, vec![(PORT_DRY.to_string(), vec![0xF8])]);
+        delay.process(utils::now_micros(), &MidiMessage::Start, "*", "*", "*", "*");
+        assert_eq!(scheduler::pending("delay-test"), 2);
+        delay.destroy();
+        assert_eq!(scheduler::pending("delay-test"), 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn note_values () {
+        let mut delay = Delay::new("");
+        assert_eq!(delay.interval(), 1_000_000);
+        delay.set_data("sync".to_string(), json!(true)).unwrap();
+        assert_eq!(delay.interval(), 500_000); // quarter at 120 bpm
+        delay.set_data("division".to_string(), json!(8)).unwrap();
+        delay.set_data("feel".to_string(), json!("dotted")).unwrap();
+        assert_eq!(delay.interval(), 375_000);
+        delay.set_data("feel".to_string(), json!("triplet")).unwrap();
+        assert_eq!(delay.interval(), 166_667);
+        assert!(delay.set_data("division".to_string(), json!(3)).is_err());
+        assert!(delay.set_data("feel".to_string(), json!("swing")).is_err());
+
+        delay.set_data("feel".to_string(), json!("straight")).unwrap();
+        delay.set_data("follow_clock".to_string(), json!(true)).unwrap();
+        for tick in 0..48 {
+            // 24 ticks every 400ms is 150 bpm
+            let res = delay.process(tick * 400_000 / 24, &MidiMessage::Clock, "*", "*", "*", "*");
+            assert_eq!(res.len(), 1);
+        }
+        assert!((delay.tempo() - 150.0).abs() < 0.1);
+        assert_eq!(delay.interval(), 200_000);
+        delay.process(10_000_000, &MidiMessage::Clock, "*", "*", "*", "*"); // clock stopped
+        assert_eq!(delay.tempo(), 120.0);
+    }
+
+    #[test]
+    fn repeats () {
+        let mut delay = Delay::new("");
+        delay.set_data("repeats".to_string(), json!(3)).unwrap();
+        delay.set_data("decay".to_string(), json!(0.5)).unwrap();
+        delay.set_data("transpose".to_string(), json!(12)).unwrap();
+        let note = |note, velocity| MidiMessage::NoteOn { channel: 0, note, velocity };
+        assert_eq!(delay.echo(&note(60, 100), 1), Some(note(72, 50)));
+        assert_eq!(delay.echo(&note(60, 100), 2), Some(note(84, 25)));
+        assert_eq!(delay.echo(&note(60, 100), 3), Some(note(96, 13)));
+        assert_eq!(delay.echo(&note(60, 0), 3), Some(note(96, 0)));
+        assert_eq!(delay.echo(&note(120, 100), 1), None);
+        assert_eq!(delay.echo(&MidiMessage::NoteOff { channel: 0, note: 60, velocity: 0 }, 2),
+            Some(MidiMessage::NoteOff { channel: 0, note: 84, velocity: 0 }));
+        assert_eq!(delay.echo(&MidiMessage::Clock, 2), Some(MidiMessage::Clock));
+        delay.set_data("decay".to_string(), json!(1)).unwrap();
+        assert_eq!(delay.echo(&note(60, 100), 1), Some(note(72, 1)));
+        assert!(delay.set_data("repeats".to_string(), json!(0)).is_err());
+        assert!(delay.set_data("decay".to_string(), json!(1.5)).is_err());
+        assert!(delay.set_data("transpose".to_string(), json!(-25)).is_err());
+    }
+}
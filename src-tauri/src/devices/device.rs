@@ -1,5 +1,11 @@
 use serde_json::{Value, Error};
-use std::error::Error as StdErr;
+use std::{any::Any, error::Error as StdErr};
+use crate::utils::{MidiMessage, MidiPorts};
+
+/**
+ * Slow part of getting or setting device data, runs outside the hub dispatch thread
+ */
+pub type Job = Box<dyn FnOnce() -> Result<Box<dyn Any + Send>, String> + Send>;
 
 pub trait Device: Send + Sync {
     fn get_id(&self) -> &str;
@@ -10,9 +16,35 @@ pub trait Device: Send + Sync {
     fn get_data(&mut self, key: String) -> Result<Option<Value>, String>;
     fn set_data(&mut self, key: String, data:Value) -> Result<(), String>;
     fn delete_data(&mut self, key: String) -> Result<(), String>;
+    /**
+     * Job for data keys too slow for the hub dispatch thread, like reading files or
+     * running scripts, replaces get_data and set_data. Its result is passed to
+     * finish_data back on the dispatch thread
+     */
+    fn start_data(&mut self, _key: &str, _data: &Value) -> Option<Job> { None }
+    fn finish_data(&mut self, _key: &str, _result: Box<dyn Any + Send>) -> Result<Option<Value>, String> { Ok(None) }
+    /**
+     * Connection state of devices bound to midi ports, None for other devices
+     */
+    fn online(&self) -> Option<bool> { None }
+    /**
+     * Whether the midi port of the device is in the list of available ports
+     */
+    fn port_available(&self, _ports: &MidiPorts) -> bool { false }
+    /**
+     * Whether the connected port was replaced since the device was initialized,
+     * like a device unplugged and plugged in again between two polls
+     */
+    fn port_changed(&self, _ports: &MidiPorts) -> bool { false }
+    /**
+     * Whether messages leaving a port are scheduled for later instead of processed right away,
+     * loops through such ports are allowed
+     */
+    fn defers_output(&self, _port: &str) -> bool { false }
     fn process(
         &mut self,
-        bytes: &Vec<u8>,
+        ts: u64,
+        msg: &MidiMessage,
         from: &str,
         to: &str,
         from_port: &str,
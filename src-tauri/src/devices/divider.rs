@@ -0,0 +1,230 @@
+use serde::Serialize;
+use serde_json::{json, Value, Error};
+use std::error::Error as StdErr;
+use crate::{devices::{clock::CLOCK_PPQN, device::Device}, scheduler, utils::MidiMessage};
+
+/*
+ * Follows incoming midi clock to detect tempo and re-emits it multiplied or divided,
+ * transport messages are forwarded unchanged
+ */
+
+const MAX_TICK_INTERVAL: u64 = 1_000_000; // micros, longer gaps restart tempo detection
+
+#[derive(Serialize)]
+pub struct Divider {
+    pub id: String,
+    pub class: String,
+    pub multiply: u64,
+    pub divide: u64,
+    pub phase: u64, // offset in input ticks
+    pub smoothing: f64, // 0 follows tempo changes instantly, close to 1 averages over many ticks
+    #[serde(skip_serializing)]
+    ticks: u64, // input ticks since start
+    #[serde(skip_serializing)]
+    last_tick: Option<u64>,
+    #[serde(skip_serializing)]
+    period: Option<f64>, // smoothed micros between input ticks
+}
+
+impl Divider {
+    pub fn new(id: &str) -> Self {
+        Divider {
+            id: String::from(id),
+            class: String::from("divider"),
+            multiply: 1,
+            divide: 2,
+            phase: 0,
+            smoothing: 0.9,
+            ticks: 0,
+            last_tick: None,
+            period: None,
+        }
+    }
+
+    pub fn bpm(&self) -> Option<f64> {
+        self.period.map(|p| 60_000_000.0 / (p * CLOCK_PPQN as f64))
+    }
+
+    /**
+     * Restarts counting, ticks scheduled for the previous transport state are dropped
+     */
+    fn reset(&mut self) {
+        self.ticks = 0;
+        scheduler::cancel(&self.id);
+    }
+
+    /**
+     * Hub timestamps of the output ticks between this input tick and the next,
+     * ticks after this one are predicted from the detected tempo
+     */
+    fn tick(&mut self, ts: u64) -> Vec<u64> {
+        if let Some(last) = self.last_tick {
+            let dt = ts.saturating_sub(last);
+            if dt < MAX_TICK_INTERVAL {
+                let dt = dt as f64;
+                self.period = Some(match self.period {
+                    Some(period) => period * self.smoothing + dt * (1.0 - self.smoothing),
+                    None => dt,
+                });
+            } else {
+                self.period = None;
+            }
+        }
+        self.last_tick = Some(ts);
+
+        // output tick j happens at input position j * divide / multiply
+        let position = self.ticks + self.phase;
+        self.ticks += 1;
+        let first = (position * self.multiply).div_ceil(self.divide);
+        let last = ((position + 1) * self.multiply).div_ceil(self.divide);
+        let mut res = vec![];
+        for j in first..last {
+            let offset = (j * self.divide) as f64 / self.multiply as f64 - position as f64;
+            if offset <= 0.0 {
+                res.push(ts);
+            } else if let Some(period) = self.period {
+                res.push(ts + (offset * period).round() as u64);
+            }
+        }
+        res
+    }
+}
+
+impl Device for Divider {
+    fn get_id(&self) -> &str { &self.id }
+    fn get_class(&self) -> &str { &self.class }
+    fn destroy(&mut self) {
+        self.reset();
+    }
+    fn get_data(&mut self, key: String) -> Result<Option<Value>, String> {
+        match key.as_str() {
+            "bpm" => Ok(Some(json!(self.bpm()))),
+            _ => Ok(None)
+        }
+    }
+    fn set_data(&mut self, key: String, data: Value) -> Result<(), String> {
+        match key.as_str() {
+            "multiply" => {
+                self.multiply = data.as_u64().filter(|m| *m >= 1 && *m <= 24).ok_or("Invalid multiplier, expected 1 to 24")?;
+            },
+            "divide" => {
+                self.divide = data.as_u64().filter(|d| *d >= 1 && *d <= 96).ok_or("Invalid divider, expected 1 to 96")?;
+            },
+            "phase" => {
+                self.phase = data.as_u64().filter(|p| *p < 96).ok_or("Invalid phase")?;
+            },
+            "smoothing" => {
+                self.smoothing = data.as_f64().filter(|s| (0.0..1.0).contains(s)).ok_or("Invalid smoothing")?;
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+    fn delete_data(&mut self, _key: String) -> Result<(), String> { Ok(()) }
+    fn init(&mut self) -> Result<(), Box<dyn StdErr>> { Ok(()) }
+
+    fn serialize(&self) -> Result<Value, Error> {
+        serde_json::to_value(self)
+    }
+
+    fn process(
+        &mut self,
+        ts: u64,
+        msg: &MidiMessage,
+        _from: &str,
+        _to: &str,
+        _from_port: &str,
+        _to_port: &str
+    ) -> Vec<(String, Vec<u8>)> {
+        match msg {
+            MidiMessage::Clock => {
+                let clock = MidiMessage::Clock.to_bytes();
+                let mut res = vec![];
+                for at in self.tick(ts) {
+                    if at <= ts {
+                        res.push(("*".to_string(), clock.clone()));
+                    } else {
+                        scheduler::schedule(at, clock.clone(), &self.id, "*");
+                    }
+                }
+                res
+            },
+            MidiMessage::Start | MidiMessage::Stop => {
+                self.reset();
+                vec![("*".to_string(), msg.to_bytes())]
+            },
+            MidiMessage::Continue | MidiMessage::SongPosition(_) => {
+                vec![("*".to_string(), msg.to_bytes())]
+            },
+            _ => vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(divider: &mut Divider, ticks: u64) -> Vec<Vec<u64>> {
+        (0..ticks).map(|i| divider.tick(i * 20_000)).collect()
+    }
+
+    #[test]
+    fn divide () {
+        let mut divider = Divider::new("");
+        assert_eq!(run(&mut divider, 6), vec![vec![0], vec![], vec![40_000], vec![], vec![80_000], vec![]]);
+        divider.ticks = 0;
+        divider.last_tick = None;
+        divider.set_data("divide".to_string(), json!(3)).unwrap();
+        divider.set_data("phase".to_string(), json!(1)).unwrap();
+        assert_eq!(run(&mut divider, 6), vec![vec![], vec![], vec![40_000], vec![], vec![], vec![100_000]]);
+    }
+
+    #[test]
+    fn multiply () {
+        let mut divider = Divider::new("");
+        divider.set_data("divide".to_string(), json!(1)).unwrap();
+        divider.set_data("multiply".to_string(), json!(2)).unwrap();
+        // the second tick of each pair is predicted once the tempo is known
+        assert_eq!(run(&mut divider, 3), vec![vec![0], vec![20_000, 30_000], vec![40_000, 50_000]]);
+        divider.set_data("divide".to_string(), json!(2)).unwrap();
+        divider.set_data("multiply".to_string(), json!(3)).unwrap();
+        divider.ticks = 0;
+        divider.last_tick = None;
+        assert_eq!(run(&mut divider, 2), vec![vec![0, 13_333], vec![20_000 + 6_667]]);
+    }
+
+    #[test]
+    fn detect_bpm () {
+        let mut divider = Divider::new("");
+        assert_eq!(divider.get_data("bpm".to_string()).unwrap(), Some(json!(null)));
+        divider.set_data("smoothing".to_string(), json!(0.5)).unwrap();
+        for i in 0..48 {
+            divider.tick(i * 20_833);
+        }
+        assert_eq!(divider.bpm().unwrap().round(), 120.0);
+        for i in 48..96 {
+            divider.tick(48 * 20_833 + (i - 48) * 10_417);
+        }
+        assert_eq!(divider.bpm().unwrap().round(), 240.0);
+        divider.tick(10_000_000);
+        assert_eq!(divider.bpm(), None);
+    }
+
+    #[test]
+    fn transport () {
+        let mut divider = Divider::new("divider-test-transport");
+        divider.set_data("divide".to_string(), json!(1)).unwrap();
+        divider.set_data("multiply".to_string(), json!(2)).unwrap();
+        let clock = |divider: &mut Divider, ts| divider.process(ts, &MidiMessage::Clock, "*", "*", "*", "*");
+        assert_eq!(clock(&mut divider, 1_000), vec![("*".to_string(), vec![0xF8])]);
+        assert_eq!(clock(&mut divider, 61_000_000), vec![("*".to_string(), vec![0xF8])]); // tempo lost after a gap
+        assert_eq!(clock(&mut divider, 61_500_000).len(), 1);
+        assert_eq!(scheduler::pending("divider-test-transport"), 1); // predicted half tick
+        let res = divider.process(0, &MidiMessage::Start, "*", "*", "*", "*");
+        assert_eq!(res, vec![("*".to_string(), vec![0xFA])]);
+        assert_eq!(divider.ticks, 0);
+        assert_eq!(scheduler::pending("divider-test-transport"), 0);
+        assert_eq!(divider.process(0, &MidiMessage::NoteOn { channel: 0, note: 1, velocity: 1 }, "*", "*", "*", "*"), vec![]);
+    }
+}
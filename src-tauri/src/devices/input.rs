@@ -6,6 +6,7 @@ use std::sync::Mutex;
 
 use crate::hub::Hub;
 use crate::devices::device::Device;
+use crate::utils::{self, MidiMessage, MidiPorts, PortMatch};
 
 use crate::globals::PREFIX_INPUT;
 
@@ -17,6 +18,13 @@ use crate::globals::PREFIX_INPUT;
 pub struct Input {
     pub id: String,
     pub class: String,
+    pub label: String,
+    pub port: String, // bound midi port, the id stays the same when rebinding
+    pub online: bool, // connected to its midi port
+    pub port_match: PortMatch,
+    pub port_name: String, // connected port, empty when offline
+    #[serde(skip_serializing)]
+    port_identity: String,
     #[serde(skip_serializing)]
     conn: Option<Mutex<MidiInputConnection<()>>>,
 }
@@ -24,12 +32,30 @@ pub struct Input {
 impl Input {
     pub fn new(id: &str) -> Self {
         let preid = if id.starts_with(PREFIX_INPUT) { id } else { &format!("{}{}", PREFIX_INPUT, id) };
+        let port = preid.strip_prefix(PREFIX_INPUT).unwrap_or(preid).to_string();
         Input {
             id: String::from(preid),
             class: String::from("input"),
+            label: port.clone(),
+            port,
+            online: false,
+            port_match: PortMatch::default(),
+            port_name: String::new(),
+            port_identity: String::new(),
             conn: None,
         }
     }
+
+    /**
+     * New devices get an id independent of their port, so the port can be added
+     * again after the device is bound to another one
+     */
+    pub fn with_port(id: &str, port: &str) -> Self {
+        let mut input = Input::new(id);
+        input.port = port.to_string();
+        input.label = port.to_string();
+        input
+    }
 }
 
 impl Device for Input {
@@ -40,44 +66,78 @@ impl Device for Input {
         return &self.class;
     }
     fn destroy(&mut self) {
+        self.online = false;
+        self.port_name.clear();
+        self.port_identity.clear();
         if let Some(mutex) = self.conn.take() {
             let conn = mutex.into_inner().unwrap();
             conn.close();
         }
     }
+    fn online(&self) -> Option<bool> {
+        Some(self.online)
+    }
+    fn port_available(&self, ports: &MidiPorts) -> bool {
+        if self.online {
+            ports.inputs.contains(&self.port_name)
+        } else {
+            self.port_match.find(&ports.inputs, &self.port).is_some()
+        }
+    }
+    fn port_changed(&self, ports: &MidiPorts) -> bool {
+        self.online && !ports.input_ids.contains(&self.port_identity)
+    }
     fn get_data(&mut self, _key: String) -> Result<Option<Value>, String> { Ok(None) }
-    fn set_data(&mut self, _key: String, _data:Value) -> Result<(), String> { Ok(()) }
+    fn set_data(&mut self, key: String, data:Value) -> Result<(), String> {
+        match key.as_str() {
+            "label" => {
+                self.label = data.as_str().ok_or("Invalid label")?.to_string();
+            },
+            "port" => {
+                let port = data.as_str().filter(|p| !p.is_empty()).ok_or("Invalid port")?;
+                if port != self.port {
+                    self.port = port.to_string();
+                    self.destroy();
+                    let _ = self.init(); // offline until the port is available
+                }
+            },
+            "port_match" => {
+                let port_match: PortMatch = serde_json::from_value(data).map_err(|_| "Invalid port match")?;
+                port_match.validate()?;
+                if port_match != self.port_match {
+                    self.port_match = port_match;
+                    self.destroy();
+                    let _ = self.init(); // offline until a matching port is available
+                }
+            },
+            _ => {}
+        }
+        Ok(())
+    }
     fn delete_data(&mut self, _key: String) -> Result<(), String> { Ok(()) }
 
     fn init(&mut self) -> Result<(), Box<dyn StdErr>> {
         let mut input = MidiInput::new(&self.id)?;
         input.ignore(Ignore::None);
         let ports = &input.ports();
-        let mut found = false;
-        let mut idx = 0;
-        for (i, p) in ports.iter().enumerate() {
-            if &input.port_name(p)? == &self.id.strip_prefix(PREFIX_INPUT).unwrap() {
-                found = true;
-                idx = i;
-                break;
-            }
-        }
-        if !found {
+        let names = ports.iter().map(|p| input.port_name(p)).collect::<Result<Vec<_>, _>>()?;
+        let Some(idx) = self.port_match.find(&names, &self.port) else {
             let str = String::from("Device port not found ") + &self.id;
-            Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, str)))?;
-        }
+            Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, str)))?
+        };
         let port = &ports[idx];
         let id = self.id.clone();
         self.conn = Some(Mutex::new(input.connect(
             &port,
             &id.clone(),
-            move |ts, bytes, _| {
-                let hub_instance = Hub::get_instance();
-                let mut hub = hub_instance.lock().unwrap();
-                hub.process(ts, &bytes.to_vec(), &id, "*", "*", "*");
+            move |_, bytes, _| {
+                Hub::send(utils::now_micros(), bytes.to_vec(), &id, "*", "*", "*");
             },
             ()
         )?));
+        self.online = true;
+        self.port_name = names[idx].clone();
+        self.port_identity = utils::port_identity(idx, &ports[idx].id());
 
         Ok(())
     }
@@ -86,7 +146,8 @@ impl Device for Input {
     }
     fn process(
         &mut self,
-        _bytes: &Vec<u8>,
+        _ts: u64,
+        _msg: &MidiMessage,
         _from: &str,
         _to: &str,
         _from_port: &str,
@@ -109,6 +170,15 @@ mod tests {
         input.destroy();
     }
 
+    #[test]
+    #[serial]
+    fn with_port() {
+        let input = Input::with_port("Input 1", "Port A");
+        assert_eq!(input.id, format!("{}Input 1", PREFIX_INPUT));
+        assert_eq!(input.port, "Port A");
+        assert_eq!(input.label, "Port A");
+    }
+
     #[test]
     #[serial]
     fn init() {
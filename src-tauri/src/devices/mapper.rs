@@ -1,7 +1,43 @@
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, Error};
+use std::collections::HashMap;
 use std::error::Error as StdErr;
 use crate::devices::device::Device;
+use crate::globals::PORT_THRU;
+use crate::utils::{MidiMessage, ParamDecoder, ParamEvent, ParamKind};
+
+/**
+ * Response curve applied to the value byte, data2 for two byte messages and data1 otherwise
+ */
+#[derive(Default, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Curve {
+    #[default]
+    Linear,
+    Exp,
+    Log,
+    S,
+    Table, // lookup table of output values, interpolated when it has other than 128 entries
+}
+
+#[derive(Default, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    #[default]
+    First,
+    All,
+}
+
+/**
+ * Converts parameters between plain control changes, 14 bit controllers, nrpn and rpn
+ */
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Conversion {
+    pub from: ParamKind,
+    pub to: ParamKind,
+    pub in_param: i32, // controller or parameter number, -1 matches any
+    pub out_param: i32, // -1 keeps the input number
+}
 
 #[derive(Default, Serialize, Deserialize, Clone)]
 pub struct Rule {
@@ -19,13 +55,201 @@ pub struct Rule {
     pub out_data2_max: i32,
     pub pull_data1: bool,
     pub pull_data2: bool,
+    #[serde(default)]
+    pub curve: Curve,
+    #[serde(default)]
+    pub table: Vec<i32>,
+    #[serde(default)]
+    pub invert: bool,
+    #[serde(default)]
+    pub clamp: bool, // clamp data outside the input range instead of dropping the message
+    #[serde(default)]
+    pub convert: Option<Conversion>,
+}
+
+impl Rule {
+    pub fn new() -> Self {
+        Rule {
+            in_channel: -1,
+            in_type: -1,
+            in_data1_min: -1,
+            in_data1_max: -1,
+            in_data2_min: -1,
+            in_data2_max: -1,
+            out_channel: -1,
+            out_type: -1,
+            out_data1_min: -1,
+            out_data1_max: -1,
+            out_data2_min: -1,
+            out_data2_max: -1,
+            pull_data1: false, // use data2 value for data1 mapping
+            pull_data2: false, // use data1 value for data2 mapping
+            curve: Curve::Linear,
+            table: vec![],
+            invert: false,
+            clamp: false,
+            convert: None,
+        }
+    }
+
+    /**
+     * Applies the response curve and inversion to a value in the 0..1 range
+     */
+    fn shape(&self, x: f64) -> f64 {
+        const K: f64 = 4.0; // steepness of exp and log curves
+        let y = match self.curve {
+            Curve::Linear => x,
+            Curve::Exp => ((K * x).exp() - 1.0) / (K.exp() - 1.0),
+            Curve::Log => (1.0 + x * (K.exp() - 1.0)).ln() / K,
+            Curve::S => x * x * (3.0 - 2.0 * x),
+            Curve::Table => {
+                if self.table.is_empty() {
+                    x
+                } else {
+                    let pos = x * (self.table.len() - 1) as f64;
+                    let i = pos.floor() as usize;
+                    let a = self.table[i] as f64;
+                    let b = self.table[(i + 1).min(self.table.len() - 1)] as f64;
+                    (a + (b - a) * pos.fract()) / 127.0
+                }
+            }
+        };
+        let y = y.clamp(0.0, 1.0);
+        if self.invert { 1.0 - y } else { y }
+    }
+
+    /**
+     * Returns the mapped message bytes or None if the message is outside this rule boundaries
+     */
+    fn apply(&self, msg: &MidiMessage) -> Option<Vec<u8>> {
+        let (mut evt_type, mut channel, mut data1, data2) = match *msg {
+            MidiMessage::NoteOff { channel, note, velocity } => (0x08, channel, note as i32, Some(velocity as i32)),
+            MidiMessage::NoteOn { channel, note, velocity } => (0x09, channel, note as i32, Some(velocity as i32)),
+            MidiMessage::Aftertouch { channel, note, pressure } => (0x0A, channel, note as i32, Some(pressure as i32)),
+            MidiMessage::ControlChange { channel, controller, value } => (0x0B, channel, controller as i32, Some(value as i32)),
+            MidiMessage::ProgramChange { channel, program } => (0x0C, channel, program as i32, None),
+            MidiMessage::ChannelAftertouch { channel, pressure } => (0x0D, channel, pressure as i32, None),
+            MidiMessage::PitchBend { channel, value } => (0x0E, channel, (value & 0x7F) as i32, Some((value >> 7) as i32)),
+            _ => {
+                // system and unknown messages have nothing to map, forward them unless the rule filters by type or channel
+                if self.in_type > -1 || self.in_channel > -1 {
+                    return None;
+                }
+                return Some(msg.to_bytes());
+            }
+        };
+        let has_data2 = data2.is_some();
+        let mut data2 = data2.unwrap_or(0);
+
+        if (self.in_type > -1 && self.in_type != evt_type as i32) ||
+            (self.in_channel > -1 && self.in_channel != channel as i32)
+        {
+            return None;
+        }
+        data1 = self.fit(data1, self.in_data1_min, self.in_data1_max)?;
+        if has_data2 {
+            data2 = self.fit(data2, self.in_data2_min, self.in_data2_max)?;
+        }
+
+        if self.out_type != -1 {
+            evt_type = self.out_type as u8
+        }
+        if self.out_channel != -1 {
+            channel = self.out_channel as u8
+        }
+
+        let shaped = self.curve != Curve::Linear || self.invert;
+        let dt1 = data1;
+        if self.out_data1_min != -1 || self.out_data1_max != -1 || (shaped && !has_data2) {
+            let value = if self.pull_data1 { data2 } else { data1 };
+            data1 = self.map(value, self.out_data1_min, self.out_data1_max, !has_data2);
+        } else if self.pull_data1 {
+            data1 = data2;
+        }
+        if self.out_data2_min != -1 || self.out_data2_max != -1 || (shaped && has_data2) {
+            let value = if self.pull_data2 { dt1 } else { data2 };
+            data2 = self.map(value, self.out_data2_min, self.out_data2_max, has_data2);
+        } else if self.pull_data2 {
+            data2 = dt1;
+        }
+
+        let status_byte = (evt_type << 4) | (channel & 0x0F);
+
+        if evt_type == 0x0C || evt_type == 0x0D { // single data byte messages for program and channelAT
+            Some(vec![status_byte, data1 as u8])
+        } else { // two data byte messages for other events
+            Some(vec![status_byte, data1 as u8, data2 as u8])
+        }
+    }
+
+    /**
+     * Returns the converted parameter messages, 14 bit inputs are matched against
+     * the event assembled by the mapper decoder and the curve applies to the full value
+     */
+    fn convert(&self, conversion: &Conversion, msg: &MidiMessage, param: Option<&ParamEvent>) -> Option<Vec<Vec<u8>>> {
+        let (channel, number, value) = match (conversion.from, msg, param) {
+            (ParamKind::Cc7, &MidiMessage::ControlChange { channel, controller, value }, _) => {
+                (channel, controller as u16, (value as u16) << 7 | value as u16)
+            },
+            (kind, _, Some(event)) if kind != ParamKind::Cc7 && event.kind == kind => (event.channel, event.param, event.value),
+            _ => return None,
+        };
+        if (conversion.in_param > -1 && conversion.in_param != number as i32) ||
+            (self.in_channel > -1 && self.in_channel != channel as i32)
+        {
+            return None;
+        }
+        let x = value as f64 / 16383.0;
+        let y = if self.curve != Curve::Linear || self.invert { self.shape(x) } else { x };
+        let event = ParamEvent {
+            kind: conversion.to,
+            channel: if self.out_channel != -1 { self.out_channel as u8 } else { channel },
+            param: if conversion.out_param > -1 { conversion.out_param as u16 } else { number },
+            value: (y * 16383.0).round() as u16,
+        };
+        Some(event.to_messages())
+    }
+
+    /**
+     * Checks a data value against the input boundaries, clamping it if the rule allows
+     */
+    fn fit(&self, value: i32, min: i32, max: i32) -> Option<i32> {
+        let below = min > -1 && value < min;
+        let above = max > -1 && value > max;
+        match (below, above, self.clamp) {
+            (false, false, _) => Some(value),
+            (true, _, true) => Some(min),
+            (_, true, true) => Some(max),
+            _ => None,
+        }
+    }
+
+    /**
+     * Maps a 0..127 value into the output range, unset boundaries default to the full range
+     */
+    fn map(&self, value: i32, min: i32, max: i32, shaped: bool) -> i32 {
+        let min = if min == -1 { 0 } else { min };
+        let max = if max == -1 { 127 } else { max };
+        let x = value.clamp(0, 127) as f64 / 127.0;
+        let y = if shaped { self.shape(x) } else { x };
+        map_linear(y, min, max)
+    }
+}
+
+fn map_linear(value: f64, min: i32, max: i32) -> i32 {
+    let mapped = min as f64 + value * (max - min) as f64;
+    (mapped.round() as i32).clamp(0, 127)
 }
 
 #[derive(Serialize)]
 pub struct Mapper {
     pub id: String,
     pub class: String,
-    pub rule: Rule,
+    pub rules: Vec<Rule>,
+    pub mode: MatchMode,
+    pub pass_through: bool,
+    #[serde(skip_serializing)]
+    params: HashMap<(String, String), ParamDecoder>, // decoder state per source device and port
 }
 
 impl Mapper {
@@ -33,41 +257,41 @@ impl Mapper {
         Mapper {
             id: String::from(id),
             class: String::from("map"),
-            rule: Rule {
-                in_channel: -1,
-                in_type: -1,
-                in_data1_min: -1,
-                in_data1_max: -1,
-                in_data2_min: -1,
-                in_data2_max: -1,
-                out_channel: -1,
-                out_type: -1,
-                out_data1_min: -1,
-                out_data1_max: -1,
-                out_data2_min: -1,
-                out_data2_max: -1,
-                pull_data1: false, // use data2 value for data1 mapping
-                pull_data2: false // use data1 value for data2 mapping
-            }
+            rules: vec![Rule::new()],
+            mode: MatchMode::First,
+            pass_through: false,
+            params: HashMap::new(),
         }
     }
 }
 
-fn map_linear(value: i32, min: i32, max: i32) -> i32 {
-    let range = max - min;
-    let mapped = min + (value * range / 127);
-    mapped
-}
-
 impl Device for Mapper {
     fn get_id(&self) -> &str { &self.id }
     fn get_class(&self) -> &str { &self.class }
     fn destroy(&mut self) {}
     fn get_data(&mut self, _key: String) -> Result<Option<Value>, String> { Ok(None) }
 
-    fn set_data(&mut self, _key: String, data:Value) -> Result<(), String> {
-        let rule:Rule = serde_json::from_value(data).map_err(|err| format!("Failed to deserialize rule {}", err))?;
-        self.rule = rule;
+    fn set_data(&mut self, key: String, data:Value) -> Result<(), String> {
+        match key.as_str() {
+            "rule" => { // replaces the first rule, used by single rule editors and older projects
+                let rule:Rule = serde_json::from_value(data).map_err(|err| format!("Failed to deserialize rule {}", err))?;
+                if self.rules.is_empty() {
+                    self.rules.push(rule);
+                } else {
+                    self.rules[0] = rule;
+                }
+            },
+            "rules" => {
+                self.rules = serde_json::from_value(data).map_err(|err| format!("Failed to deserialize rules {}", err))?;
+            },
+            "mode" => {
+                self.mode = serde_json::from_value(data).map_err(|_| "Invalid mode, expected first or all")?;
+            },
+            "pass_through" => {
+                self.pass_through = data.as_bool().ok_or("Invalid pass through")?;
+            },
+            _ => {}
+        }
         Ok(())
     }
 
@@ -80,62 +304,167 @@ impl Device for Mapper {
 
     fn process(
         &mut self,
-        bytes: &Vec<u8>,
-        _from: &str,
+        _ts: u64,
+        msg: &MidiMessage,
+        from: &str,
         _to: &str,
-        _from_port: &str,
+        from_port: &str,
         _to_port: &str
     ) -> Vec<(String, Vec<u8>)> {
-        if bytes.len() < 1 || bytes.len() > 3 {
-            return vec![]
-        }
-
-        let mut evt_type = (bytes[0] & 0xF0) >> 4;
-        let mut channel = bytes[0] & 0x0F;
-        let mut data1 = if bytes.len() > 1 { bytes[1] as i32 } else { 0 };
-        let mut data2 = if bytes.len() > 2 { bytes[2] as i32 } else { 0 };
-        let rule = self.rule.clone();
-
-        // if the event is outside the map boundaries ignore it
-        if (rule.in_type > -1 && rule.in_type != evt_type as i32) ||
-            (rule.in_channel > -1 && rule.in_channel != channel as i32) ||
-            (bytes.len() > 1 && rule.in_data1_min > -1 && rule.in_data1_min > data1) ||
-            (bytes.len() > 1 && rule.in_data1_max > -1 && rule.in_data1_max < data1) ||
-            (bytes.len() > 2 && rule.in_data2_min > -1 && rule.in_data2_min > data2) ||
-            (bytes.len() > 2 && rule.in_data2_max > -1 && rule.in_data2_max < data2)
-        {
-            return vec![];
-        }
-
-        if rule.out_type != -1 {
-            evt_type = rule.out_type as u8
+        let param = self.params.entry((from.to_string(), from_port.to_string())).or_default().decode(msg);
+        let mut results = vec![];
+        for rule in &self.rules {
+            let matched = match &rule.convert {
+                Some(conversion) => rule.convert(conversion, msg, param.as_ref()),
+                None => rule.apply(msg).map(|bytes| vec![bytes]),
+            };
+            if let Some(messages) = matched {
+                results.extend(messages.into_iter().map(|bytes| ("*".to_string(), bytes)));
+                if self.mode == MatchMode::First {
+                    break;
+                }
+            }
         }
-        if rule.out_channel != -1 {
-            channel = rule.out_channel as u8
+        if results.is_empty() && self.pass_through {
+            results.push((PORT_THRU.to_string(), msg.to_bytes()));
         }
+        results
+    }
+}
 
-        let dt1 = data1.clone();
-        if rule.out_data1_min != -1 || rule.out_data1_max != -1 {
-            let min = rule.out_data1_min.max(0);
-            let max = rule.out_data1_max.max(0).max(min);
-            data1 = map_linear(if rule.pull_data1 { data2 } else { data1 }, min, max);
-        } else if rule.pull_data1 {
-            data1 = data2;
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn process(mapper: &mut Mapper, bytes: &[u8]) -> Vec<(String, Vec<u8>)> {
+        mapper.process(0, &MidiMessage::from_bytes(bytes), "*", "*", "*", "*")
+    }
+
+    fn cc_rule(controller: i32) -> Rule {
+        Rule { in_type: 0x0B, in_data1_min: controller, in_data1_max: controller, ..Rule::new() }
+    }
+
+    #[test]
+    fn linear_range () {
+        let mut mapper = Mapper::new("");
+        mapper.rules[0].out_data2_min = 10;
+        mapper.rules[0].out_data2_max = 0;
+        assert_eq!(process(&mut mapper, &[0xB0, 1, 127])[0].1, vec![0xB0, 1, 0]);
+        assert_eq!(process(&mut mapper, &[0xB0, 1, 0])[0].1, vec![0xB0, 1, 10]);
+        mapper.rules[0].out_data2_min = 20;
+        mapper.rules[0].out_data2_max = 100;
+        assert_eq!(process(&mut mapper, &[0xB0, 1, 127])[0].1, vec![0xB0, 1, 100]);
+        assert_eq!(process(&mut mapper, &[0xF8]), vec![("*".to_string(), vec![0xF8])]);
+    }
+
+    #[test]
+    fn curves () {
+        let mut rule = Rule::new();
+        for curve in [Curve::Exp, Curve::Log, Curve::S] {
+            rule.curve = curve;
+            assert_eq!(rule.map(0, -1, -1, true), 0);
+            assert_eq!(rule.map(127, -1, -1, true), 127);
         }
-        if rule.out_data2_min != -1 || rule.out_data2_max != -1 {
-            let min = rule.out_data2_min.max(0);
-            let max = rule.out_data2_max.max(0).max(min);
-            data2 = map_linear(if rule.pull_data2 { dt1 } else { data2 }, min, max);
-        } else if rule.pull_data2 {
-            data2 = dt1;
+        rule.curve = Curve::Exp;
+        assert!(rule.map(64, -1, -1, true) < 20);
+        rule.curve = Curve::Log;
+        assert!(rule.map(64, -1, -1, true) > 100);
+        rule.curve = Curve::S;
+        assert!(rule.map(32, -1, -1, true) < 32);
+        rule.curve = Curve::Table;
+        rule.table = vec![0, 100, 127];
+        assert_eq!(rule.map(127, -1, -1, true), 127);
+        assert_eq!(rule.map(0, -1, -1, true), 0);
+        assert!((99..=101).contains(&rule.map(64, -1, -1, true)));
+        rule.curve = Curve::Linear;
+        rule.invert = true;
+        assert_eq!(rule.map(127, 10, 20, true), 10);
+    }
+
+    #[test]
+    fn curve_value_byte () {
+        let mut mapper = Mapper::new("");
+        mapper.rules[0].invert = true;
+        assert_eq!(process(&mut mapper, &[0x90, 60, 127])[0].1, vec![0x90, 60, 0]); // note stays, velocity inverts
+        assert_eq!(process(&mut mapper, &[0xC0, 0])[0].1, vec![0xC0, 127]);
+    }
+
+    #[test]
+    fn clamp_or_drop () {
+        let mut mapper = Mapper::new("");
+        mapper.rules[0].in_data2_min = 10;
+        mapper.rules[0].in_data2_max = 20;
+        assert_eq!(process(&mut mapper, &[0x90, 60, 30]), vec![]);
+        mapper.rules[0].clamp = true;
+        assert_eq!(process(&mut mapper, &[0x90, 60, 30])[0].1, vec![0x90, 60, 20]);
+        assert_eq!(process(&mut mapper, &[0x90, 60, 1])[0].1, vec![0x90, 60, 10]);
+    }
+
+    #[test]
+    fn rules_and_modes () {
+        let mut mapper = Mapper::new("");
+        let mut a = cc_rule(1);
+        a.out_channel = 1;
+        let mut b = Rule::new();
+        b.out_channel = 2;
+        mapper.set_data("rules".to_string(), serde_json::to_value(vec![a, b]).unwrap()).unwrap();
+        assert_eq!(process(&mut mapper, &[0xB0, 1, 5]), vec![("*".to_string(), vec![0xB1, 1, 5])]);
+        mapper.set_data("mode".to_string(), json!("all")).unwrap();
+        assert_eq!(process(&mut mapper, &[0xB0, 1, 5]).len(), 2);
+        assert_eq!(process(&mut mapper, &[0xB0, 2, 5]), vec![("*".to_string(), vec![0xB2, 2, 5])]);
+        assert!(mapper.set_data("mode".to_string(), json!("any")).is_err());
+
+        mapper.rules.pop();
+        assert_eq!(process(&mut mapper, &[0xB0, 2, 5]), vec![]);
+        mapper.set_data("pass_through".to_string(), json!(true)).unwrap();
+        assert_eq!(process(&mut mapper, &[0xB0, 2, 5]), vec![(PORT_THRU.to_string(), vec![0xB0, 2, 5])]);
+    }
+
+    #[test]
+    fn convert_params () {
+        let mut mapper = Mapper::new("");
+        mapper.rules[0].convert = Some(Conversion { from: ParamKind::Cc7, to: ParamKind::Nrpn, in_param: 74, out_param: 0x0105 });
+        let res = process(&mut mapper, &[0xB1, 74, 127]);
+        let bytes: Vec<Vec<u8>> = res.into_iter().map(|r| r.1).collect();
+        assert_eq!(bytes, vec![vec![0xB1, 99, 2], vec![0xB1, 98, 5], vec![0xB1, 6, 127], vec![0xB1, 38, 127]]);
+        assert_eq!(process(&mut mapper, &[0xB1, 75, 127]), vec![]);
+
+        mapper.rules[0].convert = Some(Conversion { from: ParamKind::Nrpn, to: ParamKind::Cc14, in_param: -1, out_param: 1 });
+        let mut res = vec![];
+        for packet in bytes {
+            res.extend(process(&mut mapper, &packet));
         }
+        assert_eq!(res.len(), 4); // the first data msb comes before lsbs are known
+        assert_eq!(res[2..].to_vec(), vec![("*".to_string(), vec![0xB1, 1, 127]), ("*".to_string(), vec![0xB1, 33, 127])]);
 
-        let status_byte = (evt_type << 4) | (channel & 0x0F);
+        mapper.rules[0].convert = Some(Conversion { from: ParamKind::Cc14, to: ParamKind::Cc7, in_param: 1, out_param: 7 });
+        process(&mut mapper, &[0xB0, 1, 64]);
+        assert_eq!(process(&mut mapper, &[0xB0, 33, 127]), vec![("*".to_string(), vec![0xB0, 7, 64])]);
+    }
 
-        if evt_type == 0x0C || evt_type == 0x0D { // single data byte messages for program and channelAT
-            vec![("*".to_string(), vec![status_byte, data1 as u8])]
-        } else { // two data byte messages for other events
-            vec![("*".to_string(), vec![status_byte, data1 as u8, data2 as u8])]
+    #[test]
+    fn params_per_source () {
+        let mut mapper = Mapper::new("");
+        mapper.rules[0].convert = Some(Conversion { from: ParamKind::Nrpn, to: ParamKind::Cc7, in_param: -1, out_param: 7 });
+        for bytes in [[0xB0, 99, 0], [0xB0, 98, 1]] {
+            mapper.process(0, &MidiMessage::from_bytes(&bytes), "a1", "*", "2", "*");
         }
+        // same concatenated key, but a different source
+        assert_eq!(mapper.process(0, &MidiMessage::from_bytes(&[0xB0, 6, 64]), "a", "*", "12", "*"), vec![]);
+        let res = mapper.process(0, &MidiMessage::from_bytes(&[0xB0, 6, 64]), "a1", "*", "2", "*");
+        assert_eq!(res, vec![("*".to_string(), vec![0xB0, 7, 64])]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn legacy_rule () {
+        let mut mapper = Mapper::new("");
+        let mut rule = serde_json::to_value(Rule::new()).unwrap();
+        rule.as_object_mut().unwrap().remove("curve");
+        rule["out_channel"] = json!(3);
+        mapper.set_data("rule".to_string(), rule).unwrap();
+        assert_eq!(mapper.rules.len(), 1);
+        assert_eq!(mapper.rules[0].curve, Curve::Linear);
+        assert_eq!(process(&mut mapper, &[0x90, 1, 1])[0].1, vec![0x93, 1, 1]);
+    }
+}
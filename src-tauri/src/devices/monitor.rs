@@ -2,6 +2,7 @@ use serde::Serialize;
 use serde_json::{Value, Error};
 use std::error::Error as StdErr;
 use crate::devices::device::Device;
+use crate::utils::MidiMessage;
 
 /*
  * Monitors midi inputs and outputs to display on the viewport
@@ -42,14 +43,15 @@ impl Device for Monitor {
     }
     fn process(
         &mut self,
-        bytes: &Vec<u8>,
+        _ts: u64,
+        msg: &MidiMessage,
         _from: &str,
         _to: &str,
         _from_port: &str,
         _to_port: &str
     ) -> Vec<(String, Vec<u8>)> {
         let mut res = vec![];
-        res.push(("*".to_string(), bytes.clone()));
+        res.push(("*".to_string(), msg.to_bytes()));
         res
     }
 }
\ No newline at end of file
@@ -1,11 +1,13 @@
 use midir::{MidiOutput, MidiOutputConnection};
 use serde::Serialize;
-use serde_json::{Value, Error};
+use serde_json::{json, Value, Error};
 use std::error::Error as StdErr;
 use std::sync::Mutex;
 
 use crate::devices::device::Device;
-use crate::globals::PREFIX_OUTPUT;
+use crate::app;
+use crate::globals::{EVT_DEVICE_STATUS, PREFIX_OUTPUT};
+use crate::utils::{self, MidiMessage, MidiPorts, PortMatch};
 
 /*
  * Output for midi hardware
@@ -15,6 +17,13 @@ use crate::globals::PREFIX_OUTPUT;
 pub struct Output {
     pub id: String,
     pub class: String,
+    pub label: String,
+    pub port: String, // bound midi port, the id stays the same when rebinding
+    pub online: bool, // connected to its midi port
+    pub port_match: PortMatch,
+    pub port_name: String, // connected port, empty when offline
+    #[serde(skip_serializing)]
+    port_identity: String,
     #[serde(skip_serializing)]
     conn: Option<Mutex<MidiOutputConnection>>,
 }
@@ -22,34 +31,46 @@ pub struct Output {
 impl Output {
     pub fn new(id: &str) -> Self {
         let preid = if id.starts_with(PREFIX_OUTPUT) { id } else { &format!("{}{}", PREFIX_OUTPUT, id) };
+        let port = preid.strip_prefix(PREFIX_OUTPUT).unwrap_or(preid).to_string();
         Output {
             id: String::from(preid),
             class: String::from("output"),
+            label: port.clone(),
+            port,
+            online: false,
+            port_match: PortMatch::default(),
+            port_name: String::new(),
+            port_identity: String::new(),
             conn: None,
         }
     }
+
+    /**
+     * See Input::with_port
+     */
+    pub fn with_port(id: &str, port: &str) -> Self {
+        let mut output = Output::new(id);
+        output.port = port.to_string();
+        output.label = port.to_string();
+        output
+    }
 }
 
 impl Device for Output {
     fn init(&mut self) -> Result<(), Box<dyn StdErr>> {
         let output = MidiOutput::new(&self.id)?;
         let ports = &output.ports();
-        let mut found = false;
-        let mut idx = 0;
-        for (i, p) in ports.iter().enumerate() {
-            if &output.port_name(p)? == &self.id.strip_prefix(PREFIX_OUTPUT).unwrap() {
-                found = true;
-                idx = i;
-                break;
-            }
-        }
-        if !found {
+        let names = ports.iter().map(|p| output.port_name(p)).collect::<Result<Vec<_>, _>>()?;
+        let Some(idx) = self.port_match.find(&names, &self.port) else {
             let str = String::from("Device port not found ") + &self.id;
             return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, str)));
-        }
+        };
         let port = &ports[idx];
         let id = self.id.clone();
         self.conn = Some(Mutex::new(output.connect(port, &id)?));
+        self.online = true;
+        self.port_name = names[idx].clone();
+        self.port_identity = utils::port_identity(idx, &ports[idx].id());
         Ok(())
     }
     fn get_id(&self) -> &str {
@@ -59,31 +80,77 @@ impl Device for Output {
         return &self.class;
     }
     fn get_data(&mut self, _key: String) -> Result<Option<Value>, String> { Ok(None) }
-    fn set_data(&mut self, _key: String, _data:Value) -> Result<(), String> { Ok(()) }
+    fn set_data(&mut self, key: String, data:Value) -> Result<(), String> {
+        match key.as_str() {
+            "label" => {
+                self.label = data.as_str().ok_or("Invalid label")?.to_string();
+            },
+            "port" => {
+                let port = data.as_str().filter(|p| !p.is_empty()).ok_or("Invalid port")?;
+                if port != self.port {
+                    self.port = port.to_string();
+                    self.destroy();
+                    let _ = self.init(); // offline until the port is available
+                }
+            },
+            "port_match" => {
+                let port_match: PortMatch = serde_json::from_value(data).map_err(|_| "Invalid port match")?;
+                port_match.validate()?;
+                if port_match != self.port_match {
+                    self.port_match = port_match;
+                    self.destroy();
+                    let _ = self.init(); // offline until a matching port is available
+                }
+            },
+            _ => {}
+        }
+        Ok(())
+    }
     fn delete_data(&mut self, _key: String) -> Result<(), String> { Ok(()) }
     fn destroy(&mut self) {
+        self.online = false;
+        self.port_name.clear();
+        self.port_identity.clear();
         if let Some(mutex) = self.conn.take() {
             let conn = mutex.into_inner().unwrap();
             conn.close();
         }
     }
+    fn online(&self) -> Option<bool> {
+        Some(self.online)
+    }
+    fn port_available(&self, ports: &MidiPorts) -> bool {
+        if self.online {
+            ports.outputs.contains(&self.port_name)
+        } else {
+            self.port_match.find(&ports.outputs, &self.port).is_some()
+        }
+    }
+    fn port_changed(&self, ports: &MidiPorts) -> bool {
+        self.online && !ports.output_ids.contains(&self.port_identity)
+    }
 
     fn serialize(&self) -> Result<Value, Error> {
         serde_json::to_value(self)
     }
     fn process(
         &mut self,
-        bytes: &Vec<u8>,
+        _ts: u64,
+        msg: &MidiMessage,
         _from: &str,
         _to: &str,
         _from_port: &str,
         _to_port: &str
     ) -> Vec<(String, Vec<u8>)> {
-        if let Some(ref mutex) = self.conn {
-            let mut conn = mutex.lock().unwrap();
-            if let Err(e) = conn.send(bytes) { // send message to midi channel this output is connected to
-                eprintln!("Error sending bytes from output {} {}", self.id, e);
-            }
+        let sent = match self.conn {
+            Some(ref mutex) => mutex.lock().unwrap().send(&msg.to_bytes()), // send message to midi channel this output is connected to
+            None => Ok(())
+        };
+        if let Err(e) = sent {
+            eprintln!("Error sending bytes from output {} {}", self.id, e);
+            // the port is gone, connected again by the port watcher once available
+            self.destroy();
+            app::emit(EVT_DEVICE_STATUS, json!({ "id": self.id, "online": false }));
         }
         vec![] // ignore forward processing
     }
@@ -102,6 +169,15 @@ mod tests {
         input.destroy();
     }
 
+    #[test]
+    #[serial]
+    fn with_port() {
+        let output = Output::with_port("Output 1", "Port A");
+        assert_eq!(output.id, format!("{}Output 1", PREFIX_OUTPUT));
+        assert_eq!(output.port, "Port A");
+        assert_eq!(output.label, "Port A");
+    }
+
     #[test]
     #[serial]
     fn init() {
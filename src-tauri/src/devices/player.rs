@@ -0,0 +1,392 @@
+use serde::Serialize;
+use serde_json::{json, Value, Error};
+use tokio::{sync::oneshot, time::{sleep_until, Instant}};
+use std::{any::Any, collections::HashSet, error::Error as StdErr, fs, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::Duration};
+use crate::{app::TOKIO_RUNTIME, devices::device::{Device, Job}, hub::Hub, smf::{self, TimedEvent}, utils::{self, MidiMessage}};
+
+/*
+ * Plays Standard MIDI Files into the graph, events are sent on the * port
+ * and on a port numbered after their track or channel, tracks without
+ * events like the tempo track of format 1 files are not numbered
+ */
+
+pub const PORT_MODE_TRACK: &str = "track";
+pub const PORT_MODE_CHANNEL: &str = "channel";
+
+type Notes = HashSet<(Option<String>, u8, u8)>; // port, channel, note
+
+struct LoadedFile {
+    ppq: u16,
+    events: Vec<TimedEvent>,
+    tracks: usize,
+}
+
+/**
+ * Reads a file into its timeline, events are numbered after the tracks with events
+ */
+fn read_file(path: &str) -> Result<LoadedFile, String> {
+    let data = fs::read(path).map_err(|e| format!("Failed to open file {}: {}", path, e))?;
+    let file = smf::read(&data).map_err(|e| format!("Failed to read midi file {}: {}", path, e))?;
+    let ppq = if file.division & 0x8000 == 0 { file.division.max(1) } else { smf::DEFAULT_PPQ };
+    let numbers: Vec<usize> = file.tracks.iter()
+        .scan(0, |n, t| { *n += !t.events.is_empty() as usize; Some(*n) })
+        .collect();
+    let mut events = file.timeline();
+    for event in events.iter_mut() {
+        event.track = numbers[event.track] - 1; // counted from its own track so at least 1
+    }
+    Ok(LoadedFile { ppq, events, tracks: numbers.last().copied().unwrap_or_default() })
+}
+
+#[derive(Serialize)]
+pub struct Player {
+    pub id: String,
+    pub class: String,
+    pub path: String,
+    pub port_mode: String,
+    #[serde(rename = "loop")]
+    pub looping: bool,
+    pub tempo_scale: f64,
+    pub follow_clock: bool, // play from incoming clock, start, stop and continue messages
+    pub tracks: usize, // tracks with events, numbered from port 1 in track mode
+    #[serde(skip_serializing)]
+    events: Arc<Vec<TimedEvent>>,
+    #[serde(skip_serializing)]
+    ppq: u16,
+    #[serde(skip_serializing)]
+    playing: Arc<AtomicBool>,
+    #[serde(skip_serializing)]
+    stop_tx: Option<oneshot::Sender<()>>,
+    #[serde(skip_serializing)]
+    position: usize, // next event when following clock
+    #[serde(skip_serializing)]
+    clock_ticks: u64,
+    #[serde(skip_serializing)]
+    notes: Notes,
+}
+
+impl Player {
+    pub fn new(id: &str) -> Self {
+        Player {
+            id: String::from(id),
+            class: String::from("player"),
+            path: "".to_string(),
+            port_mode: PORT_MODE_TRACK.to_string(),
+            looping: false,
+            tempo_scale: 1.0,
+            follow_clock: false,
+            tracks: 0,
+            events: Arc::new(vec![]),
+            ppq: smf::DEFAULT_PPQ,
+            playing: Arc::new(AtomicBool::new(false)),
+            stop_tx: None,
+            position: 0,
+            clock_ticks: 0,
+            notes: HashSet::new(),
+        }
+    }
+
+    fn load(&mut self, path: &str) -> Result<(), String> {
+        self.set_file(path, read_file(path))
+    }
+
+    fn set_file(&mut self, path: &str, file: Result<LoadedFile, String>) -> Result<(), String> {
+        self.stop();
+        self.path = path.to_string();
+        self.events = Arc::new(vec![]);
+        self.tracks = 0;
+        let file = file?;
+        self.ppq = file.ppq;
+        self.tracks = file.tracks;
+        self.events = Arc::new(file.events);
+        Ok(())
+    }
+
+    fn play(&mut self) {
+        self.stop();
+        if self.events.is_empty() {
+            return;
+        }
+        let (stop_tx, mut stop_rx) = oneshot::channel::<()>();
+        self.stop_tx = Some(stop_tx);
+        self.playing.store(true, Ordering::Relaxed);
+
+        let events = Arc::clone(&self.events);
+        let playing = Arc::clone(&self.playing);
+        let id = self.id.clone();
+        let port_mode = self.port_mode.clone();
+        // files with all events at time zero would loop without waiting
+        let looping = self.looping && events.last().is_some_and(|e| e.micros > 0);
+        let scale = self.tempo_scale;
+        let runtime = TOKIO_RUNTIME.lock().unwrap();
+
+        runtime.spawn(async move {
+            let mut notes = Notes::new();
+            'playback: loop {
+                let start = Instant::now();
+                for event in events.iter() {
+                    let at = start + Duration::from_micros((event.micros as f64 / scale) as u64);
+                    tokio::select! {
+                        _ = sleep_until(at) => {},
+                        _ = &mut stop_rx => break 'playback,
+                    }
+                    for (port, bytes) in play_event(&port_mode, event, &mut notes) {
+                        Hub::send(utils::now_micros(), bytes, &id, "*", &port, "*");
+                    }
+                }
+                if !looping {
+                    break;
+                }
+            }
+            for (port, bytes) in notes_off(&mut notes) {
+                Hub::send(utils::now_micros(), bytes, &id, "*", &port, "*");
+            }
+            playing.store(false, Ordering::Relaxed);
+        });
+    }
+
+    fn stop(&mut self) {
+        if let Some(stop_tx) = self.stop_tx.take() {
+            let _ = stop_tx.send(());
+        }
+    }
+
+    /**
+     * Advances playback on each clock message, 24 clocks per quarter note
+     */
+    fn follow(&mut self, msg: &MidiMessage) -> Vec<(String, Vec<u8>)> {
+        let mut res = vec![];
+        match msg {
+            MidiMessage::Start => {
+                self.position = 0;
+                self.clock_ticks = 0;
+                self.playing.store(true, Ordering::Relaxed);
+            },
+            MidiMessage::Continue => {
+                self.playing.store(true, Ordering::Relaxed);
+            },
+            MidiMessage::Stop => {
+                self.playing.store(false, Ordering::Relaxed);
+                res.extend(notes_off(&mut self.notes));
+            },
+            MidiMessage::SongPosition(beats) => { // 16th notes
+                self.clock_ticks = *beats as u64 * 6;
+                let tick = self.clock_ticks * self.ppq as u64 / 24;
+                self.position = self.events.iter().position(|e| e.tick >= tick).unwrap_or(self.events.len());
+            },
+            MidiMessage::Clock if self.playing.load(Ordering::Relaxed) => {
+                if self.position >= self.events.len() && self.looping {
+                    self.position = 0;
+                    self.clock_ticks = 0;
+                }
+                let tick = self.clock_ticks * self.ppq as u64 / 24;
+                while let Some(event) = self.events.get(self.position) {
+                    if event.tick > tick {
+                        break;
+                    }
+                    let outputs = play_event(&self.port_mode, event, &mut self.notes);
+                    res.extend(outputs);
+                    self.position += 1;
+                }
+                self.clock_ticks += 1;
+            },
+            _ => {}
+        }
+        res
+    }
+}
+
+/**
+ * Ports and bytes to send for an event, keeps track of notes that are still playing
+ */
+fn play_event(port_mode: &str, event: &TimedEvent, notes: &mut Notes) -> Vec<(String, Vec<u8>)> {
+    let msg = MidiMessage::from_bytes(&event.bytes);
+    let port = match port_mode {
+        PORT_MODE_CHANNEL => msg.channel().map(|c| (c + 1).to_string()),
+        _ => Some((event.track + 1).to_string()),
+    };
+    match msg {
+        MidiMessage::NoteOn { channel, note, velocity } if velocity > 0 => {
+            notes.insert((port.clone(), channel, note));
+        },
+        MidiMessage::NoteOn { channel, note, .. } | MidiMessage::NoteOff { channel, note, .. } => {
+            notes.remove(&(port.clone(), channel, note));
+        },
+        _ => {}
+    }
+    let mut res = vec![("*".to_string(), event.bytes.clone())];
+    if let Some(port) = port {
+        res.push((port, event.bytes.clone()));
+    }
+    res
+}
+
+fn notes_off(notes: &mut Notes) -> Vec<(String, Vec<u8>)> {
+    let mut res = vec![];
+    for (port, channel, note) in notes.drain() {
+        let bytes = MidiMessage::NoteOff { channel, note, velocity: 0 }.to_bytes();
+        res.push(("*".to_string(), bytes.clone()));
+        if let Some(port) = port {
+            res.push((port, bytes));
+        }
+    }
+    res
+}
+
+impl Device for Player {
+    fn get_id(&self) -> &str { &self.id }
+    fn get_class(&self) -> &str { &self.class }
+    fn destroy(&mut self) {
+        self.stop();
+    }
+    fn get_data(&mut self, key: String) -> Result<Option<Value>, String> {
+        match key.as_str() {
+            "status" => Ok(Some(json!({
+                "playing": self.playing.load(Ordering::Relaxed),
+                "events": self.events.len(),
+                "duration": self.events.last().map(|e| e.micros / 1000).unwrap_or_default(),
+            }))),
+            _ => Ok(None)
+        }
+    }
+    /**
+     * Files are read outside the hub dispatch thread
+     */
+    fn start_data(&mut self, key: &str, data: &Value) -> Option<Job> {
+        let path = data.as_str().filter(|_| key == "path")?.to_string();
+        Some(Box::new(move || {
+            let file = read_file(&path);
+            Ok(Box::new((path, file)) as Box<dyn Any + Send>)
+        }))
+    }
+    fn finish_data(&mut self, key: &str, result: Box<dyn Any + Send>) -> Result<Option<Value>, String> {
+        let (path, file) = *result.downcast::<(String, Result<LoadedFile, String>)>()
+            .map_err(|_| format!("Invalid result of {}", key))?;
+        self.set_file(&path, file)?;
+        Ok(None)
+    }
+    fn set_data(&mut self, key: String, data: Value) -> Result<(), String> {
+        match key.as_str() {
+            "path" => {
+                let path = data.as_str().ok_or("Invalid file path")?;
+                self.load(path)?;
+            },
+            "port_mode" => {
+                let mode = data.as_str().filter(|m| [PORT_MODE_TRACK, PORT_MODE_CHANNEL].contains(m)).ok_or("Invalid port mode")?;
+                self.port_mode = mode.to_string();
+            },
+            "loop" => {
+                self.looping = data.as_bool().ok_or("Invalid loop value")?;
+            },
+            "tempo_scale" => {
+                self.tempo_scale = data.as_f64().filter(|s| *s > 0.0).ok_or("Invalid tempo scale")?;
+            },
+            "follow_clock" => {
+                self.follow_clock = data.as_bool().ok_or("Invalid follow clock value")?;
+            },
+            "play" => self.play(),
+            "stop" => self.stop(),
+            _ => {}
+        }
+        Ok(())
+    }
+    fn delete_data(&mut self, _key: String) -> Result<(), String> { Ok(()) }
+    fn init(&mut self) -> Result<(), Box<dyn StdErr>> { Ok(()) }
+
+    fn serialize(&self) -> Result<Value, Error> {
+        serde_json::to_value(self)
+    }
+
+    fn process(
+        &mut self,
+        _ts: u64,
+        msg: &MidiMessage,
+        _from: &str,
+        _to: &str,
+        _from_port: &str,
+        _to_port: &str
+    ) -> Vec<(String, Vec<u8>)> {
+        if self.follow_clock {
+            self.follow(msg)
+        } else {
+            vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smf::{SmfEvent, SmfTrack};
+
+    fn load(player: &mut Player, name: &str) {
+        let tracks = vec![
+            SmfTrack { name: "a".to_string(), events: vec![
+                SmfEvent { tick: 0, bytes: vec![0x90, 60, 100] },
+                SmfEvent { tick: 96, bytes: vec![0x80, 60, 0] },
+            ] },
+            SmfTrack { name: "b".to_string(), events: vec![
+                SmfEvent { tick: 48, bytes: vec![0x93, 64, 100] },
+            ] },
+        ];
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, smf::write(1, tracks, 96, 500_000)).unwrap();
+        player.set_data("path".to_string(), json!(path.to_str().unwrap())).unwrap();
+        let _ = fs::remove_file(path);
+    }
+
+    fn clock(player: &mut Player, msg: MidiMessage) -> Vec<(String, Vec<u8>)> {
+        player.process(0, &msg, "*", "*", "*", "*")
+    }
+
+    #[test]
+    fn load_file () {
+        let mut player = Player::new("");
+        load(&mut player, "mididash_player_load.mid");
+        let status = player.get_data("status".to_string()).unwrap().unwrap();
+        assert_eq!(status["events"], 3);
+        assert_eq!(status["duration"], 500);
+        assert_eq!(player.tracks, 2); // the tempo track is not counted
+        assert!(player.set_data("path".to_string(), json!("/invalid/file.mid")).is_err());
+
+        let job = player.start_data("path", &json!("/invalid/other.mid")).unwrap();
+        assert!(player.finish_data("path", job().unwrap()).is_err());
+        assert_eq!(player.path, "/invalid/other.mid");
+        assert_eq!(player.tracks, 0);
+        assert!(player.start_data("loop", &json!(true)).is_none());
+    }
+
+    #[test]
+    fn follow_clock () {
+        let mut player = Player::new("");
+        load(&mut player, "mididash_player_clock.mid");
+        assert_eq!(clock(&mut player, MidiMessage::Clock), vec![]);
+        player.set_data("follow_clock".to_string(), json!(true)).unwrap();
+        assert_eq!(clock(&mut player, MidiMessage::Clock), vec![]); // not started
+        clock(&mut player, MidiMessage::Start);
+        let res = clock(&mut player, MidiMessage::Clock);
+        assert_eq!(res, vec![("*".to_string(), vec![0x90, 60, 100]), ("1".to_string(), vec![0x90, 60, 100])]);
+        for _ in 0..11 {
+            assert_eq!(clock(&mut player, MidiMessage::Clock), vec![]);
+        }
+        let res = clock(&mut player, MidiMessage::Clock); // 8th note
+        assert_eq!(res, vec![("*".to_string(), vec![0x93, 64, 100]), ("2".to_string(), vec![0x93, 64, 100])]);
+        let res = clock(&mut player, MidiMessage::Stop);
+        assert_eq!(res.len(), 4); // both notes released on * and track ports
+        assert!(res.contains(&("1".to_string(), vec![0x80, 60, 0])));
+        assert!(res.contains(&("2".to_string(), vec![0x83, 64, 0])));
+    }
+
+    #[test]
+    fn channel_ports () {
+        let mut player = Player::new("");
+        load(&mut player, "mididash_player_channel.mid");
+        player.set_data("follow_clock".to_string(), json!(true)).unwrap();
+        player.set_data("port_mode".to_string(), json!("channel")).unwrap();
+        clock(&mut player, MidiMessage::SongPosition(2)); // 8th note
+        clock(&mut player, MidiMessage::Continue);
+        let res = clock(&mut player, MidiMessage::Clock);
+        assert_eq!(res, vec![("*".to_string(), vec![0x93, 64, 100]), ("4".to_string(), vec![0x93, 64, 100])]);
+        assert!(player.set_data("port_mode".to_string(), json!("other")).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use serde::Serialize;
+use serde_json::{json, Value, Error};
+use std::{any::Any, error::Error as StdErr, fs};
+use crate::{devices::device::{Device, Job}, smf::{self, SmfEvent, SmfTrack}, utils::{self, MidiMessage}};
+
+/*
+ * Records incoming midi into Standard MIDI Files, messages are passed through unchanged
+ */
+
+#[derive(Serialize)]
+pub struct Recorder {
+    pub id: String,
+    pub class: String,
+    pub format: u16, // 0 single track, 1 one track per source device
+    pub ppq: u16,
+    pub bpm: f64,
+    pub recording: bool,
+    #[serde(skip_serializing)]
+    start: Option<u64>, // hub timestamp of the recording start
+    #[serde(skip_serializing)]
+    events: Vec<(u64, String, Vec<u8>)>, // micros since start, source device, bytes
+}
+
+impl Recorder {
+    pub fn new(id: &str) -> Self {
+        Recorder {
+            id: String::from(id),
+            class: String::from("recorder"),
+            format: 1,
+            ppq: smf::DEFAULT_PPQ,
+            bpm: smf::DEFAULT_BPM,
+            recording: false,
+            start: None,
+            events: vec![],
+        }
+    }
+
+    fn duration(&self) -> u64 {
+        self.events.last().map(|e| e.0).unwrap_or_default()
+    }
+
+    /**
+     * Encodes the recorded events, on format 1 each source device gets its own track
+     */
+    pub fn to_smf(&self) -> Vec<u8> {
+        let tempo = smf::bpm_to_tempo(self.bpm);
+        let mut tracks: Vec<SmfTrack> = vec![];
+        for (micros, source, bytes) in &self.events {
+            let event = SmfEvent { tick: smf::micros_to_ticks(*micros, self.ppq, tempo), bytes: bytes.clone() };
+            if let Some(track) = tracks.iter_mut().find(|t| &t.name == source) {
+                track.events.push(event);
+            } else {
+                tracks.push(SmfTrack { name: source.clone(), events: vec![event] });
+            }
+        }
+        if tracks.is_empty() {
+            tracks.push(SmfTrack::default());
+        }
+        smf::write(self.format, tracks, self.ppq, tempo)
+    }
+}
+
+impl Device for Recorder {
+    fn get_id(&self) -> &str { &self.id }
+    fn get_class(&self) -> &str { &self.class }
+    fn destroy(&mut self) {
+        self.recording = false;
+    }
+    fn get_data(&mut self, key: String) -> Result<Option<Value>, String> {
+        match key.as_str() {
+            "status" => Ok(Some(json!({
+                "recording": self.recording,
+                "events": self.events.len(),
+                "duration": self.duration() / 1000,
+            }))),
+            _ => Ok(None)
+        }
+    }
+    fn start_data(&mut self, key: &str, data: &Value) -> Option<Job> {
+        let path = data.as_str().filter(|p| key == "save" && !p.is_empty())?.to_string();
+        let bytes = self.to_smf(); // encoded here, only the write waits on the disk
+        Some(Box::new(move || {
+            write_file(&path, &bytes)?;
+            Ok(Box::new(()) as Box<dyn Any + Send>)
+        }))
+    }
+    fn set_data(&mut self, key: String, data: Value) -> Result<(), String> {
+        match key.as_str() {
+            "format" => {
+                let format = data.as_u64().filter(|f| *f <= 1).ok_or("Invalid format, expected 0 or 1")?;
+                self.format = format as u16;
+            },
+            "ppq" => {
+                let ppq = data.as_u64().filter(|p| *p > 0 && *p < 0x8000).ok_or("Invalid ppq")?;
+                self.ppq = ppq as u16;
+            },
+            "bpm" => {
+                self.bpm = data.as_f64().filter(|b| *b > 0.0).ok_or("Invalid bpm")?;
+            },
+            "start" => {
+                self.events.clear();
+                self.start = Some(utils::now_micros());
+                self.recording = true;
+            },
+            "stop" => {
+                self.recording = false;
+            },
+            "clear" => {
+                self.events.clear();
+            },
+            "save" => {
+                let path = data.as_str().filter(|p| !p.is_empty()).ok_or("Invalid file path")?;
+                write_file(path, &self.to_smf())?;
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+    fn delete_data(&mut self, _key: String) -> Result<(), String> { Ok(()) }
+    fn init(&mut self) -> Result<(), Box<dyn StdErr>> { Ok(()) }
+
+    fn serialize(&self) -> Result<Value, Error> {
+        serde_json::to_value(self)
+    }
+
+    fn process(
+        &mut self,
+        ts: u64,
+        msg: &MidiMessage,
+        from: &str,
+        _to: &str,
+        _from_port: &str,
+        _to_port: &str
+    ) -> Vec<(String, Vec<u8>)> {
+        let bytes = msg.to_bytes();
+        if self.recording {
+            if let Some(start) = self.start {
+                self.events.push((ts.saturating_sub(start), from.to_string(), bytes.clone()));
+            }
+        }
+        vec![("*".to_string(), bytes)]
+    }
+}
+
+fn write_file(path: &str, bytes: &[u8]) -> Result<(), String> {
+    fs::write(path, bytes).map_err(|e| format!("Failed to save file {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(recorder: &mut Recorder, from: &str, bytes: &[u8]) -> Vec<(String, Vec<u8>)> {
+        recorder.process(utils::now_micros(), &MidiMessage::from_bytes(bytes), from, "*", "*", "*")
+    }
+
+    #[test]
+    fn pass_through () {
+        let mut recorder = Recorder::new("");
+        let res = process(&mut recorder, "a", &[0x90, 60, 100]);
+        assert_eq!(res, vec![("*".to_string(), vec![0x90, 60, 100])]);
+        assert_eq!(recorder.events.len(), 0);
+    }
+
+    #[test]
+    fn record_tracks () {
+        let mut recorder = Recorder::new("");
+        recorder.set_data("start".to_string(), json!(null)).unwrap();
+        process(&mut recorder, "a", &[0x90, 60, 100]);
+        process(&mut recorder, "b", &[0x91, 62, 100]);
+        process(&mut recorder, "a", &[0x80, 60, 0]);
+        let start = recorder.start.unwrap();
+        recorder.process(start + 250_000, &MidiMessage::from_bytes(&[0x80, 62, 0]), "b", "*", "*", "*");
+        recorder.set_data("stop".to_string(), json!(null)).unwrap();
+        process(&mut recorder, "a", &[0x90, 60, 100]);
+        let status = recorder.get_data("status".to_string()).unwrap().unwrap();
+        assert_eq!(status["events"], 4);
+        assert_eq!(status["duration"], 250); // millis from the message timestamps
+        assert_eq!(status["recording"], false);
+
+        let file = recorder.to_smf();
+        assert_eq!(file[8..12], [0, 1, 0, 3]); // conductor track + 2 sources
+        recorder.set_data("format".to_string(), json!(0)).unwrap();
+        let file = recorder.to_smf();
+        assert_eq!(file[8..12], [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn save () {
+        let mut recorder = Recorder::new("");
+        recorder.set_data("start".to_string(), json!(null)).unwrap();
+        process(&mut recorder, "a", &[0xB0, 1, 2]);
+        let path = std::env::temp_dir().join("mididash_recorder_test.mid");
+        recorder.set_data("save".to_string(), json!(path.to_str().unwrap())).unwrap();
+        let file = fs::read(&path).unwrap();
+        assert_eq!(file, recorder.to_smf());
+        let _ = fs::remove_file(&path);
+
+        // written outside the dispatch thread
+        let job = recorder.start_data("save", &json!(path.to_str().unwrap())).unwrap();
+        process(&mut recorder, "a", &[0xB0, 1, 3]); // recorded after the file was encoded
+        let result = job().unwrap();
+        assert_eq!(recorder.finish_data("save", result), Ok(None));
+        assert_eq!(fs::read(&path).unwrap(), file);
+        let _ = fs::remove_file(&path);
+        assert!(recorder.start_data("save", &json!("")).is_none());
+        assert!(recorder.start_data("format", &json!(0)).is_none());
+        assert!(recorder.set_data("format".to_string(), json!(2)).is_err());
+    }
+}
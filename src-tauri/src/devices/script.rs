@@ -1,32 +1,489 @@
-use mlua::{Lua, Table, Value};
+use mlua::{Function, HookTriggers, Lua, Table, Value, VmState};
+use once_cell::sync::Lazy;
+use regex::Regex;
 use serde::Serialize;
-use serde_json::{json, Error, Value as JsonValue};
-use std::{collections::HashMap, error::Error as StdErr, sync::Mutex};
-use crate::{app, devices::device::Device, globals::{EVT_SCRIPT_ERROR, EVT_SCRIPT_LOG }};
+use serde_json::{json, Error, Map, Value as JsonValue};
+use tokio::time::{sleep_until, Instant};
+use std::{any::Any, collections::HashMap, error::Error as StdErr, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, Weak}, time::Duration};
+use crate::{app::{self, TOKIO_RUNTIME}, devices::device::{Device, Job}, globals::{EVT_SCRIPT_ERROR, EVT_SCRIPT_LOG }, hub::{self, Hub}, lua_library, lua_midi, shared, utils::{self, MidiMessage}};
+
+const TIMERS: &str = "timers"; // registry table of pending timer callbacks by timer id
+const CHUNK: &str = "chunk"; // registry key of the compiled script
+const HOOK_STEP: u32 = 1000; // instructions between budget checks
+const MAX_DEPTH: u32 = 32; // nested tables converted to json
+const CALLBACKS: [&str; 4] = ["on_midi", "on_start", "on_stop", "on_shared"];
+
+// definitions of callbacks, matched on the source without comments and strings
+static CALLBACK_DEF: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\bfunction\s+(_G\.)?on_(midi|start|stop|shared)\b|\bon_(midi|start|stop|shared)\s*=[^=]").unwrap()
+});
+// callbacks set through a string key of the globals table
+static CALLBACK_KEY: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"\b_G\s*\[\s*["']on_(midi|start|stop|shared)["']\s*\]\s*=[^=]"#).unwrap()
+});
+
+pub const SANDBOX_SAFE: &str = "safe"; // no filesystem, process or module loading access
+pub const SANDBOX_FULL: &str = "full"; // lua standard libraries
+
+/**
+ * Execution budget of a single run, zero disables a limit
+ */
+#[derive(Clone, Copy, Default)]
+struct Limits {
+    time: u64, // ms
+    instructions: u64,
+    memory: usize, // bytes
+}
 
 #[derive(Serialize)]
 pub struct Script {
     pub id: String,
     pub class: String,
     pub script: String,
+    pub sandbox: String,
+    pub time_limit: u64,
+    pub instruction_limit: u64,
+    pub memory_limit: usize,
+    #[serde(skip_serializing)]
+    lua: Arc<Mutex<Lua>>,
     #[serde(skip_serializing)]
-    lua: Mutex<Lua>,
+    structured: bool, // script defines on_midi, on_start, on_stop or on_shared
+    #[serde(skip_serializing)]
+    generation: Arc<AtomicU64>, // bumped to cancel the timers of the current lua state
     #[serde(skip_serializing)]
     test_bytes: Vec<u8> // used for testing the script from the frontend
 }
 
 impl Script {
     pub fn new(id: &str) -> Self {
-        Script {
+        let mut script = Script {
             id: String::from(id),
             class: String::from("script"),
             script: "".to_string(),
-            lua: Mutex::new(Lua::new()),
+            sandbox: SANDBOX_SAFE.to_string(),
+            time_limit: 100,
+            instruction_limit: 10_000_000,
+            memory_limit: 32 * 1024 * 1024,
+            lua: Arc::new(Mutex::new(Lua::new())),
+            structured: false,
+            generation: Arc::new(AtomicU64::new(0)),
             test_bytes: vec![]
+        };
+        if let Ok(lua) = script.create_lua() {
+            script.lua = Arc::new(Mutex::new(lua));
+        }
+        script
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            time: self.time_limit,
+            instructions: self.instruction_limit,
+            memory: self.memory_limit,
+        }
+    }
+
+    /**
+     * Creates a lua state for the sandbox profile with the memory cap applied
+     */
+    fn create_lua(&self) -> mlua::Result<Lua> {
+        let lua = Lua::new();
+        if self.sandbox == SANDBOX_SAFE {
+            let globals = lua.globals();
+            for name in ["io", "require", "package", "dofile", "loadfile"] {
+                globals.set(name, Value::Nil)?;
+            }
+            let os: Table = globals.get("os")?;
+            let safe_os = lua.create_table()?;
+            for name in ["time", "clock", "date", "difftime"] {
+                safe_os.set(name, os.get::<Value>(name)?)?;
+            }
+            globals.set("os", safe_os)?;
         }
+        lua.set_memory_limit(self.memory_limit)?;
+        lua.set_app_data(self.limits());
+        Ok(lua)
+    }
+
+    fn emit_error(&self, err: &str) {
+        app::emit(EVT_SCRIPT_ERROR, json!({
+            "id": self.id,
+            "error": err
+        }));
+    }
+
+    /**
+     * Compiles the script into the current state, scripts that seem to define callbacks
+     * are run once and get started if they did, the others only run per message
+     * with the bytes and res globals
+     */
+    fn load_script(&mut self) -> Result<(), String> {
+        let lua = self.lua.lock().unwrap();
+        let globals = lua.globals();
+        let _ = lua.unset_named_registry_value(CHUNK);
+        for name in CALLBACKS {
+            let _ = globals.set(name, Value::Nil);
+        }
+        self.structured = false;
+        if self.script.trim().is_empty() {
+            return Ok(());
+        }
+        let defines_callbacks = CALLBACK_KEY.is_match(&self.script)
+            || CALLBACK_DEF.is_match(&strip_literals(&self.script));
+        let result = lua.load(&self.script)
+            .set_name(format!("={}", self.id))
+            .into_function()
+            .and_then(|chunk| {
+                lua.set_named_registry_value(CHUNK, &chunk)?;
+                if !defines_callbacks {
+                    return Ok(());
+                }
+                globals.set("bytes", lua.create_table()?)?;
+                globals.set("res", lua.create_table()?)?;
+                let run = with_budget(&lua, || chunk.call::<()>(()));
+                self.structured = CALLBACKS.iter()
+                    .any(|name| matches!(globals.raw_get::<Value>(*name), Ok(Value::Function(_))));
+                if self.structured {
+                    run?;
+                    call_callback(&lua, "on_start", ())?;
+                }
+                Ok(()) // callbacks defined conditionally, errors are reported per message
+            });
+        result.map_err(|err| {
+            let err = err.to_string();
+            self.emit_error(&err);
+            err
+        })
+    }
+
+    /**
+     * Calls on_stop of structured scripts before their state is replaced or destroyed
+     */
+    fn stop_script(&mut self) {
+        if !self.structured {
+            return;
+        }
+        let result = {
+            let lua = self.lua.lock().unwrap();
+            call_callback(&lua, "on_stop", ())
+        };
+        if let Err(err) = result {
+            self.emit_error(&err.to_string());
+        }
+    }
+
+    fn update_limits(&self) -> Result<(), String> {
+        let lua = self.lua.lock().unwrap();
+        lua.set_memory_limit(self.memory_limit).map_err(|e| e.to_string())?;
+        lua.set_app_data(self.limits());
+        Ok(())
+    }
+
+    fn cancel_timers(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /**
+     * Persistent store table of the script as json
+     */
+    fn store_json(&self) -> JsonValue {
+        let lua = self.lua.lock().unwrap();
+        match lua.globals().get::<Value>("store") {
+            Ok(store @ Value::Table(_)) => lua_to_json(&store, 0),
+            _ => json!({})
+        }
+    }
+
+    fn set_store(&self, store: &JsonValue) -> mlua::Result<()> {
+        let lua = self.lua.lock().unwrap();
+        lua.globals().set("store", json_to_lua(&lua, store)?)
+    }
+
+    /**
+     * Creates the shared lua module, values are converted to json so they can be saved with the project
+     */
+    fn shared_module(&self, lua: &Lua) -> mlua::Result<Table> {
+        let module = lua.create_table()?;
+        module.set("get", lua.create_function(|lua, key: String| {
+            shared::get(&key).map_or(Ok(Value::Nil), |value| json_to_lua(lua, &value))
+        })?)?;
+        let id = self.id.clone();
+        module.set("set", lua.create_function(move |_, (key, value): (String, Value)| {
+            shared::set(&key, lua_to_json(&value, 0), &id);
+            Ok(())
+        })?)?;
+        module.set("keys", lua.create_function(|lua, ()| lua.create_sequence_from(shared::keys()))?)?;
+        Ok(module)
+    }
+
+    /**
+     * Calls on_shared when other devices change a shared value, like timers
+     * the callback is discarded if the script state was reset in the meantime
+     */
+    fn shared_watcher(&self) -> shared::Watcher {
+        let state = Arc::downgrade(&self.lua);
+        let generation = Arc::clone(&self.generation);
+        let current = generation.load(Ordering::Relaxed);
+        let id = self.id.clone();
+        Box::new(move |key, value| {
+            let state = state.clone();
+            let generation = Arc::clone(&generation);
+            let id = id.clone();
+            let key = key.to_string();
+            let value = value.clone();
+            let runtime = TOKIO_RUNTIME.lock().unwrap();
+            runtime.spawn(async move {
+                let Some(state) = state.upgrade() else {
+                    return;
+                };
+                let lua = state.lock().unwrap();
+                if generation.load(Ordering::Relaxed) != current {
+                    return;
+                }
+                let result = json_to_lua(&lua, &value)
+                    .and_then(|value| call_callback(&lua, "on_shared", (key, value)));
+                if let Err(err) = result {
+                    app::emit(EVT_SCRIPT_ERROR, json!({
+                        "id": id,
+                        "error": format!("{}", err)
+                    }));
+                }
+            });
+        })
+    }
+
+    /**
+     * Creates the after or every lua function, callbacks run on the tokio runtime
+     * and are discarded if the script state was reset in the meantime
+     */
+    fn timer_fn(&self, lua: &Lua, repeat: bool, next_timer: Arc<AtomicU64>) -> mlua::Result<Function> {
+        let state = Arc::downgrade(&self.lua);
+        let generation = Arc::clone(&self.generation);
+        let id = self.id.clone();
+        lua.create_function(move |lua, (ms, callback): (f64, Function)| {
+            if ms.is_nan() || ms <= 0.0 {
+                return Err(mlua::Error::RuntimeError("Timer interval must be greater than zero".to_string()));
+            }
+            let timer = next_timer.fetch_add(1, Ordering::Relaxed);
+            let timers: Table = lua.named_registry_value(TIMERS)?;
+            timers.set(timer, callback)?;
+            let current = generation.load(Ordering::Relaxed);
+            let hops = hub::current_hops(); // timers started by a message keep counting its hops
+            let state = state.clone();
+            let generation = Arc::clone(&generation);
+            let id = id.clone();
+            let period = Duration::from_secs_f64(ms / 1000.0);
+            let runtime = TOKIO_RUNTIME.lock().unwrap();
+            runtime.spawn(async move {
+                let mut deadline = Instant::now() + period;
+                loop {
+                    sleep_until(deadline).await;
+                    if !hub::with_hops(hops, || run_timer(&state, &generation, current, &id, timer, repeat)) {
+                        break;
+                    }
+                    deadline += period;
+                }
+            });
+            Ok(timer)
+        })
+    }
+}
+
+/**
+ * Source with comments and string literals blanked, so callback names inside them are ignored
+ */
+fn strip_literals(src: &str) -> String {
+    let mut out = String::with_capacity(src.len());
+    let mut rest = src;
+    while let Some(c) = rest.chars().next() {
+        if let Some(comment) = rest.strip_prefix("--") {
+            rest = match long_bracket(comment) {
+                Some(level) => skip_long(comment, level),
+                None => comment.find('\n').map_or("", |i| &comment[i..]),
+            };
+            out.push(' ');
+        } else if let Some(level) = long_bracket(rest) {
+            rest = skip_long(rest, level);
+            out.push(' ');
+        } else if c == '"' || c == '\'' {
+            let mut end = rest.len();
+            let mut escaped = false;
+            for (i, ch) in rest.char_indices().skip(1) {
+                if escaped {
+                    escaped = false;
+                } else if ch == '\\' {
+                    escaped = true;
+                } else if ch == c || ch == '\n' {
+                    end = i + ch.len_utf8();
+                    break;
+                }
+            }
+            rest = &rest[end..];
+            out.push(' ');
+        } else {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+/**
+ * Level of the long bracket like [[ or [==[ starting the text
+ */
+fn long_bracket(s: &str) -> Option<usize> {
+    let level = s.strip_prefix('[')?.chars().take_while(|c| *c == '=').count();
+    s[1 + level..].starts_with('[').then_some(level)
+}
+
+/**
+ * Text after the long bracket closing the one starting it
+ */
+fn skip_long(s: &str, level: usize) -> &str {
+    let close = format!("]{}]", "=".repeat(level));
+    let start = level + 2;
+    s[start..].find(&close).map_or("", |i| &s[start + i + close.len()..])
+}
+
+/**
+ * Calls a timer callback, returns whether a repeating timer should keep running
+ */
+fn run_timer(state: &Weak<Mutex<Lua>>, generation: &AtomicU64, current: u64, id: &str, timer: u64, repeat: bool) -> bool {
+    let Some(state) = state.upgrade() else {
+        return false;
+    };
+    let lua = state.lock().unwrap();
+    if generation.load(Ordering::Relaxed) != current { // checked with the lock held so a reset state is never used
+        return false;
+    }
+    let Ok(timers) = lua.named_registry_value::<Table>(TIMERS) else {
+        return false;
+    };
+    let Ok(callback) = timers.get::<Function>(timer) else {
+        return false; // cancelled
+    };
+    if !repeat {
+        let _ = timers.set(timer, Value::Nil);
+    }
+    if let Err(err) = with_budget(&lua, || callback.call::<()>(())) {
+        let _ = timers.set(timer, Value::Nil);
+        app::emit(EVT_SCRIPT_ERROR, json!({
+            "id": id,
+            "error": format!("{}", err)
+        }));
+        return false;
+    }
+    repeat
+}
+
+/**
+ * Runs lua code under the state limits, the hook aborts runs that go over budget
+ */
+fn with_budget<R>(lua: &Lua, f: impl FnOnce() -> mlua::Result<R>) -> mlua::Result<R> {
+    let limits = lua.app_data_ref::<Limits>().map(|l| *l).unwrap_or_default();
+    let start = Instant::now();
+    let count = AtomicU64::new(0);
+    lua.set_hook(HookTriggers::new().every_nth_instruction(HOOK_STEP), move |_, _| {
+        let instructions = count.fetch_add(HOOK_STEP as u64, Ordering::Relaxed) + HOOK_STEP as u64;
+        if limits.instructions > 0 && instructions > limits.instructions {
+            Err(mlua::Error::RuntimeError(format!("Script exceeded the limit of {} instructions", limits.instructions)))
+        } else if limits.time > 0 && start.elapsed() > Duration::from_millis(limits.time) {
+            Err(mlua::Error::RuntimeError(format!("Script exceeded the time limit of {}ms", limits.time)))
+        } else {
+            Ok(VmState::Continue)
+        }
+    });
+    let res = f();
+    lua.remove_hook();
+    res
+}
+
+/**
+ * Calls a global callback if the script defined it
+ */
+fn call_callback(lua: &Lua, name: &str, args: impl mlua::IntoLuaMulti) -> mlua::Result<Value> {
+    match lua.globals().get::<Option<Function>>(name)? {
+        Some(callback) => with_budget(lua, || callback.call::<Value>(args)),
+        None => Ok(Value::Nil)
+    }
+}
+
+/**
+ * Reads an array of { port, bytes } entries returned by a script
+ */
+fn collect_messages(lua: &Lua, res: Table) -> Vec<(String, Vec<u8>)> {
+    let mut ret = vec![];
+    for entry in res.sequence_values::<Table>() {
+        let entry = entry.unwrap_or(lua.create_table().unwrap());
+        let port: String = entry.get("port").unwrap_or("unknown".to_string());
+        let values: Vec<u8> = entry
+            .get("bytes").unwrap_or(lua.create_table().unwrap())
+            .sequence_values()
+            .collect::<Result<Vec<u8>, _>>().unwrap_or(vec![]);
+        ret.push((port, values));
+    }
+    ret
+}
+
+/**
+ * Converts a lua value to json, sequences become arrays and other tables objects,
+ * functions and userdata are left out
+ */
+fn lua_to_json(value: &Value, depth: u32) -> JsonValue {
+    match value {
+        Value::Boolean(b) => JsonValue::Bool(*b),
+        Value::Integer(i) => json!(i),
+        Value::Number(n) => serde_json::Number::from_f64(*n).map_or(JsonValue::Null, JsonValue::Number),
+        Value::String(s) => JsonValue::String(s.to_string_lossy()),
+        Value::Table(t) if depth < MAX_DEPTH => {
+            let len = t.raw_len();
+            if len > 0 && t.pairs::<Value, Value>().count() == len {
+                return JsonValue::Array(t.sequence_values::<Value>()
+                    .filter_map(Result::ok)
+                    .map(|v| lua_to_json(&v, depth + 1))
+                    .collect());
+            }
+            let mut map = Map::new();
+            for (key, value) in t.pairs::<Value, Value>().filter_map(Result::ok) {
+                let key = match key {
+                    Value::String(s) => s.to_string_lossy(),
+                    Value::Integer(i) => i.to_string(),
+                    _ => continue
+                };
+                let value = lua_to_json(&value, depth + 1);
+                if !value.is_null() {
+                    map.insert(key, value);
+                }
+            }
+            JsonValue::Object(map)
+        }
+        _ => JsonValue::Null
     }
 }
 
+fn json_to_lua(lua: &Lua, value: &JsonValue) -> mlua::Result<Value> {
+    Ok(match value {
+        JsonValue::Null => Value::Nil,
+        JsonValue::Bool(b) => Value::Boolean(*b),
+        JsonValue::Number(n) => n.as_i64().map_or(Value::Number(n.as_f64().unwrap_or_default()), Value::Integer),
+        JsonValue::String(s) => Value::String(lua.create_string(s)?),
+        JsonValue::Array(arr) => {
+            let table = lua.create_table()?;
+            for (i, value) in arr.iter().enumerate() {
+                table.raw_set(i + 1, json_to_lua(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+        JsonValue::Object(map) => {
+            let table = lua.create_table()?;
+            for (key, value) in map {
+                table.raw_set(key.as_str(), json_to_lua(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
 // Function to stringify an unknown mlua Value
 fn stringify_value(value: &Value, depth: u32) -> String {
     match value {
@@ -66,7 +523,11 @@ impl Device for Script {
     fn get_class(&self) -> &str {
         return &self.class;
     }
-    fn destroy(&mut self) {}
+    fn destroy(&mut self) {
+        self.stop_script();
+        self.cancel_timers();
+        shared::unwatch(&self.id);
+    }
     fn get_data(&mut self, key: String) -> Result<Option<JsonValue>, String> {
         match key.as_str() {
             "globals" => {
@@ -89,18 +550,42 @@ impl Device for Script {
                 Ok(Some(serde_json::to_value(result).expect("Failed to serialize hashmap")))
             }
             "test_result" => {
-                let test_bytes = self.test_bytes.clone();
-                let res = self.process(&test_bytes, "*", "*", "*", "*");
+                let msg = MidiMessage::from_bytes(&self.test_bytes);
+                let res = self.process(utils::now_micros(), &msg, "*", "*", "*", "*");
                 Ok(Some(serde_json::to_value(res).expect("Failed to process bytes")))
             }
             _ => Ok(None)
         }
     }
+    /**
+     * Test messages run the script outside the hub dispatch thread
+     */
+    fn start_data(&mut self, key: &str, _data: &JsonValue) -> Option<Job> {
+        if key != "test_result" {
+            return None;
+        }
+        let (lua, structured, id) = (Arc::clone(&self.lua), self.structured, self.id.clone());
+        let msg = MidiMessage::from_bytes(&self.test_bytes);
+        Some(Box::new(move || {
+            let lua = lua.lock().unwrap();
+            let res = run_message(&lua, structured, &msg, "*", "*", "*", "*").unwrap_or_else(|err| {
+                app::emit(EVT_SCRIPT_ERROR, json!({ "id": id, "error": err.to_string() }));
+                vec![]
+            });
+            Ok(Box::new(res) as Box<dyn Any + Send>)
+        }))
+    }
+    fn finish_data(&mut self, key: &str, result: Box<dyn Any + Send>) -> Result<Option<JsonValue>, String> {
+        let res = result.downcast::<Vec<(String, Vec<u8>)>>().map_err(|_| format!("Invalid result of {}", key))?;
+        Ok(Some(serde_json::to_value(*res).map_err(|e| e.to_string())?))
+    }
     fn set_data(&mut self, key: String, data:JsonValue) -> Result<(), String> {
         match key.as_str() {
             "script" => {
                 if let Some(script) = data.as_str() {
+                    self.stop_script();
                     self.script = script.to_string();
+                    self.load_script()?;
                 } else {
                     Err("Failed to set script")?
                 }
@@ -112,6 +597,31 @@ impl Device for Script {
                         .collect();
                 }
             },
+            "sandbox" => {
+                let sandbox = data.as_str().filter(|s| *s == SANDBOX_SAFE || *s == SANDBOX_FULL).ok_or("Invalid sandbox profile")?;
+                if self.sandbox != sandbox {
+                    self.sandbox = sandbox.to_string();
+                    self.init().map_err(|e| e.to_string())?; // libraries can only be restored on a new state
+                }
+            },
+            "time_limit" => {
+                self.time_limit = data.as_u64().ok_or("Invalid time limit")?;
+                self.update_limits()?;
+            },
+            "instruction_limit" => {
+                self.instruction_limit = data.as_u64().ok_or("Invalid instruction limit")?;
+                self.update_limits()?;
+            },
+            "memory_limit" => {
+                self.memory_limit = data.as_u64().ok_or("Invalid memory limit")? as usize;
+                self.update_limits()?;
+            },
+            "store" => {
+                if !data.is_object() {
+                    Err("Invalid store")?
+                }
+                self.set_store(&data).map_err(|e| e.to_string())?;
+            },
             "reset-state" => {
                 let _ = self.init();
                 app::emit(EVT_SCRIPT_LOG, json!({ "id": self.id, "message": "Script state reset" }));
@@ -121,10 +631,18 @@ impl Device for Script {
 
         Ok(())
     }
-    fn delete_data(&mut self, _key: String) -> Result<(), String> { Ok(()) }
+    fn delete_data(&mut self, key: String) -> Result<(), String> {
+        if key == "store" {
+            self.set_store(&json!({})).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
 
     fn init(&mut self) -> Result<(), Box<dyn StdErr>> {
-        let lua = Lua::new();
+        self.stop_script();
+        self.cancel_timers();
+        let store = self.store_json(); // kept across state resets
+        let lua = self.create_lua()?;
         let id = self.id.clone();
         let globals = lua.globals();
         let fn_log = Some(lua.create_function(move |_, value: Value| {
@@ -133,67 +651,102 @@ impl Device for Script {
         }).unwrap());
         let _ = globals.set("log", fn_log);
 
-        let mut lock = self.lua.lock().unwrap();
-        *lock = lua;
+        let id = self.id.clone();
+        let fn_send = lua.create_function(move |_, (port, bytes): (String, Vec<u8>)| {
+            Hub::send(utils::now_micros(), bytes, &id, "*", &port, "*");
+            Ok(())
+        })?;
+        globals.set("send", fn_send)?;
+
+        lua.set_named_registry_value(TIMERS, lua.create_table()?)?;
+        let next_timer = Arc::new(AtomicU64::new(1));
+        globals.set("after", self.timer_fn(&lua, false, Arc::clone(&next_timer))?)?;
+        globals.set("every", self.timer_fn(&lua, true, next_timer)?)?;
+        let fn_cancel = lua.create_function(|lua, timer: u64| {
+            let timers: Table = lua.named_registry_value(TIMERS)?;
+            timers.set(timer, Value::Nil)
+        })?;
+        globals.set("cancel", fn_cancel)?;
+        globals.set("midi", lua_midi::create_module(&lua)?)?;
+        globals.set("shared", self.shared_module(&lua)?)?;
+        globals.set("require", lua_library::create_require(&lua)?)?;
+        globals.set("store", json_to_lua(&lua, &store)?)?;
+
+        *self.lua.lock().unwrap() = lua;
+        shared::watch(&self.id, self.shared_watcher());
+        let _ = self.load_script(); // errors are reported as script errors
         Ok(())
     }
 
     fn serialize(&self) -> Result<JsonValue, Error> {
-        serde_json::to_value(self)
+        let mut value = serde_json::to_value(self)?;
+        value["store"] = self.store_json();
+        Ok(value)
     }
 
     fn process(
         &mut self,
-        bytes: &Vec<u8>,
+        _ts: u64,
+        msg: &MidiMessage,
         from: &str,
         to: &str,
         from_port: &str,
         to_port: &str
     ) -> Vec<(String, Vec<u8>)> {
         let lua = self.lua.lock().unwrap();
-        let globals = lua.globals();
-        let _ = globals.set("from", from);
-        let _ = globals.set("to", to);
-        let _ = globals.set("from_port", from_port);
-        let _ = globals.set("to_port", to_port);
-        let _ = globals.set("res", lua.create_table().unwrap());
-        let bytes_table = lua.create_table().unwrap();
-        for byte in bytes.iter() {
-            let _ = bytes_table.push(*byte);
-        }
-        let _ = globals.set("bytes", bytes_table);
-
-        let result = lua.load(&self.script)
-            .exec()
-            .map_err(|err| {
-                app::emit(EVT_SCRIPT_ERROR, json!({
-                    "id": self.id,
-                    "error": format!("{}", err)
-                }));
-                format!("{}", err)
-            });
-
-        if result.is_ok() {
-            let mut ret = vec![];
-            let res:Table = globals.get("res").unwrap_or(lua.create_table().unwrap());
-            for entry in res.sequence_values::<Table>() {
-                let entry = entry.unwrap_or(lua.create_table().unwrap());
-                let port: String = entry.get("port").unwrap_or("unknown".to_string());
-                let values: Vec<u8> = entry
-                    .get("bytes").unwrap_or(lua.create_table().unwrap())
-                    .sequence_values()
-                    .collect::<Result<Vec<u8>, _>>().unwrap_or(vec![]);
-                ret.push((port, values));
-            }
-            ret
-        } else {
+        run_message(&lua, self.structured, msg, from, to, from_port, to_port).unwrap_or_else(|err| {
+            self.emit_error(&err.to_string());
             vec![]
+        })
+    }
+}
+
+/**
+ * Runs a message through a script state, calling on_midi of structured scripts
+ * or the whole chunk with the bytes and res globals
+ */
+fn run_message(
+    lua: &Lua,
+    structured: bool,
+    msg: &MidiMessage,
+    from: &str,
+    to: &str,
+    from_port: &str,
+    to_port: &str
+) -> mlua::Result<Vec<(String, Vec<u8>)>> {
+    if structured {
+        let msg_table = lua_midi::message_table(lua, msg)?;
+        msg_table.set("from", from)?;
+        msg_table.set("to", to)?;
+        msg_table.set("from_port", from_port)?;
+        msg_table.set("to_port", to_port)?;
+        call_callback(lua, "on_midi", msg_table).map(|res| match res {
+            Value::Table(res) => collect_messages(lua, res),
+            _ => vec![]
+        })
+    } else {
+        let globals = lua.globals();
+        globals.set("from", from)?;
+        globals.set("to", to)?;
+        globals.set("from_port", from_port)?;
+        globals.set("to_port", to_port)?;
+        globals.set("res", lua.create_table()?)?;
+        globals.set("bytes", lua.create_sequence_from(msg.to_bytes())?)?;
+        match lua.named_registry_value::<Option<Function>>(CHUNK) {
+            Ok(Some(chunk)) => with_budget(lua, || chunk.call::<()>(())).map(|_| {
+                match globals.get::<Table>("res") {
+                    Ok(res) => collect_messages(lua, res),
+                    Err(_) => vec![]
+                }
+            }),
+            _ => Ok(vec![])
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use serial_test::serial;
     use super::*;
 
     #[test]
@@ -222,7 +775,7 @@ mod tests {
             x = 1
         "#;
         let _ = script.set_data("script".to_string(), serde_json::to_value(json!(code)).expect("fail"));
-        let res = script.process(&vec![], "*", "*", "*", "*");
+        let res = script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
         assert_eq!(res, []);
         let globals = script.get_data("globals".to_string()).unwrap().unwrap();
         let prop = globals.get("x").unwrap().as_u64().unwrap();
@@ -239,7 +792,7 @@ mod tests {
             }
         "#;
         let _ = script.set_data("script".to_string(), serde_json::to_value(json!(code)).expect("fail"));
-        let res = script.process(&vec![], "*", "*", "*", "*");
+        let res = script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
         assert_eq!(res.len(), 2);
         assert_eq!(res[0], ("1".to_string(), vec![1, 2, 3 ]));
         assert_eq!(res[1], ("2".to_string(), vec![4, 5, 6 ]));
@@ -252,10 +805,10 @@ mod tests {
             table.insert(res, { port = "1", bytes = {1, 2, 3} })
         "#;
         let _ = script.set_data("script".to_string(), serde_json::to_value(json!(code)).expect("fail"));
-        let res = script.process(&vec![], "*", "*", "*", "*");
+        let res = script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
         assert_eq!(res.len(), 1);
         assert_eq!(res[0], ("1".to_string(), vec![1, 2, 3 ]));
-        let res = script.process(&vec![], "*", "*", "*", "*");
+        let res = script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
         assert_eq!(res.len(), 1);
         assert_eq!(res[0], ("1".to_string(), vec![1, 2, 3 ]));
     }
@@ -267,19 +820,19 @@ mod tests {
             table.insert(res, {})
         "#;
         let _ = script.set_data("script".to_string(), serde_json::to_value(json!(code)).expect("fail"));
-        let res = script.process(&vec![], "*", "*", "*", "*");
+        let res = script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
         assert_eq!(res, vec![("unknown".to_string(), vec![])]);
 
         let code = r#"
             res = 1
         "#;
         let _ = script.set_data("script".to_string(), serde_json::to_value(json!(code)).expect("fail"));
-        let res = script.process(&vec![], "*", "*", "*", "*");
+        let res = script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
         assert_eq!(res, vec![]);
 
         let code = r#""#;
         let _ = script.set_data("script".to_string(), serde_json::to_value(json!(code)).expect("fail"));
-        let res = script.process(&vec![], "*", "*", "*", "*");
+        let res = script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
         assert_eq!(res, vec![]);
     }
 
@@ -290,8 +843,387 @@ mod tests {
             sdfsf =sf=()))((=sd=f=s !!~df= s=dfs
         "#;
         let _ = script.set_data("script".to_string(), serde_json::to_value(json!(code)).expect("fail"));
-        let res = script.process(&vec![], "*", "*", "*", "*");
+        let res = script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        assert_eq!(res, vec![]);
+    }
+
+    fn global(script: &Script, name: &str) -> i64 {
+        script.lua.lock().unwrap().globals().get(name).unwrap_or(-1)
+    }
+
+    /**
+     * Polls until the condition holds, callbacks run on the shared tokio runtime
+     */
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    // held by the timer functions, the shared watcher and each running timer task
+    fn tasks(script: &Script) -> usize {
+        Arc::strong_count(&script.generation)
+    }
+
+    #[test]
+    fn timers () {
+        let mut script = Script::new("script-timers");
+        script.init().expect("");
+        let code = r#"
+            if not started then
+                started = true
+                n = 0
+                after(10, function() done = 1 end)
+                every(5, function() n = n + 1 end)
+                cancel(after(10, function() cancelled = 1 end))
+            end
+        "#;
+        let _ = script.set_data("script".to_string(), json!(code));
+        let idle = tasks(&script);
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        // the cancelled timer was due before the fifth repeat
+        assert!(wait_for(|| global(&script, "done") == 1 && global(&script, "n") >= 5));
+        assert_eq!(global(&script, "cancelled"), -1);
+
+        script.destroy();
+        let n = global(&script, "n"); // callbacks check the state generation with the lock held
+        assert!(wait_for(|| tasks(&script) == idle - 1)); // the shared watcher is removed too
+        assert_eq!(global(&script, "n"), n);
+    }
+
+    #[test]
+    fn timers_reset_state () {
+        let mut script = Script::new("script-timers-reset");
+        script.init().expect("");
+        let idle = tasks(&script);
+        let code = r#"
+            if bytes[1] then
+                after(20, function() fired = 1 end)
+            end
+        "#;
+        let _ = script.set_data("script".to_string(), json!(code));
+        script.process(0, &MidiMessage::from_bytes(&[0xF8]), "*", "*", "*", "*");
+        let _ = script.set_data("reset-state".to_string(), json!(null));
+        let _ = script.set_data("script".to_string(), json!("every(0, function() end)"));
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*"); // invalid interval only reports an error
+        assert!(wait_for(|| tasks(&script) == idle)); // the timer of the previous state ended
+        assert_eq!(global(&script, "fired"), -1);
+    }
+
+    #[test]
+    fn budget () {
+        let mut script = Script::new("");
+        script.init().expect("");
+        let _ = script.set_data("time_limit".to_string(), json!(20));
+        let _ = script.set_data("script".to_string(), json!("x = 1 while true do end"));
+        let start = Instant::now();
+        let res = script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
         assert_eq!(res, vec![]);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(global(&script, "x"), 1);
+
+        let _ = script.set_data("instruction_limit".to_string(), json!(5000));
+        let _ = script.set_data("script".to_string(), json!("n = 0 for i = 1, 100000 do n = i end"));
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        let n = global(&script, "n");
+        assert!(n > 0 && n < 100000, "n {}", n);
+
+        let _ = script.set_data("instruction_limit".to_string(), json!(0));
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        assert_eq!(global(&script, "n"), 100000);
+    }
+
+    #[test]
+    fn memory_limit () {
+        let mut script = Script::new("");
+        let _ = script.set_data("memory_limit".to_string(), json!(1024 * 1024));
+        let _ = script.set_data("script".to_string(), json!("s = string.rep('x', 4 * 1024 * 1024) ok = 1"));
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        assert_eq!(global(&script, "ok"), -1);
+    }
+
+    #[test]
+    fn sandbox () {
+        let mut script = Script::new("");
+        script.init().expect("");
+        let code = r#"
+            has_io = io ~= nil and 1 or 0
+            has_package = package ~= nil and 1 or 0
+            has_execute = os.execute ~= nil and 1 or 0
+            time = os.time()
+        "#;
+        let _ = script.set_data("script".to_string(), json!(code));
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        assert_eq!(global(&script, "has_io"), 0);
+        assert_eq!(global(&script, "has_package"), 0);
+        assert_eq!(global(&script, "has_execute"), 0);
+        assert!(global(&script, "time") > 0);
+
+        assert!(script.set_data("sandbox".to_string(), json!("none")).is_err());
+        script.set_data("sandbox".to_string(), json!(SANDBOX_FULL)).unwrap();
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        assert_eq!(global(&script, "has_io"), 1);
+        assert_eq!(global(&script, "has_execute"), 1);
+    }
+
+    #[test]
+    fn test_result_job () {
+        let mut script = Script::new("");
+        script.init().expect("");
+        script.set_data("script".to_string(), json!("res[1] = { port = 'out', bytes = bytes }")).unwrap();
+        script.set_data("test_bytes".to_string(), json!([0x90, 60, 1])).unwrap();
+        let job = script.start_data("test_result", &JsonValue::Null).unwrap();
+        let res = script.finish_data("test_result", job().unwrap()).unwrap();
+        assert_eq!(res, Some(json!([["out", [0x90, 60, 1]]])));
+        assert_eq!(res, script.get_data("test_result".to_string()).unwrap());
+        assert!(script.start_data("store", &JsonValue::Null).is_none());
+    }
+
+    #[test]
+    fn syntax_error () {
+        let mut script = Script::new("");
+        script.init().expect("");
+        let res = script.set_data("script".to_string(), json!("x = = 1"));
+        assert!(res.is_err());
+        assert_eq!(script.script, "x = = 1");
+        script.set_data("script".to_string(), json!("x = (x or 0) + 1")).unwrap();
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        assert_eq!(global(&script, "x"), 2); // only per message
+    }
+
+    #[test]
+    fn structured () {
+        let mut script = Script::new("");
+        script.init().expect("");
+        let code = r#"
+            count = 0
+            function on_start()
+                started = 1
+            end
+            function on_stop()
+                stopped = (stopped or 0) + 1
+            end
+            function on_midi(msg)
+                count = count + 1
+                return { { port = msg.from_port, bytes = msg.bytes } }
+            end
+        "#;
+        script.set_data("script".to_string(), json!(code)).unwrap();
+        assert_eq!(global(&script, "started"), 1);
+        let res = script.process(0, &MidiMessage::from_bytes(&[0x90, 1, 2]), "a", "*", "out", "*");
+        assert_eq!(res, vec![("out".to_string(), vec![0x90, 1, 2])]);
+        script.process(0, &MidiMessage::from_bytes(&[0x90, 1, 2]), "a", "*", "out", "*");
+        assert_eq!(global(&script, "count"), 2); // the chunk runs once, not per message
+
+        script.set_data("script".to_string(), json!("on_midi = function() end")).unwrap();
+        assert_eq!(global(&script, "stopped"), 1);
+        assert_eq!(script.process(0, &MidiMessage::from_bytes(&[0xF8]), "*", "*", "*", "*"), vec![]);
+        script.destroy();
+        assert_eq!(global(&script, "stopped"), 1); // on_stop of the previous script was cleared
+    }
+
+    #[test]
+    fn structured_detection () {
+        let mut script = Script::new("");
+        script.init().expect("");
+        // callbacks only mentioned in comments or strings, not run when saved
+        let code = "loads = (loads or 0) + 1\n-- function on_midi(msg) end\n--[==[ on_stop = nil ]==]\nlocal s = 'on_start = 1'\nres[1] = { port = '*', bytes = bytes }";
+        script.set_data("script".to_string(), json!(code)).unwrap();
+        assert!(!script.structured);
+        assert_eq!(global(&script, "loads"), -1);
+        assert_eq!(script.process(0, &MidiMessage::from_bytes(&[0xF8]), "*", "*", "*", "*"), vec![("*".to_string(), vec![0xF8])]);
+        assert_eq!(global(&script, "loads"), 1);
+
+        // callbacks assigned through the globals table
+        let code = "_G['on_midi'] = function(msg) return { { port = 'x', bytes = msg.bytes } } end";
+        script.set_data("script".to_string(), json!(code)).unwrap();
+        assert!(script.structured);
+        assert_eq!(script.process(0, &MidiMessage::from_bytes(&[0xF8]), "*", "*", "*", "*"), vec![("x".to_string(), vec![0xF8])]);
+
+        // defined only under a condition, run once when saved but not started
+        let code = "if bytes[1] then function on_midi(msg) return {} end end res[1] = { port = 'y', bytes = bytes }";
+        script.set_data("script".to_string(), json!(code)).unwrap();
+        assert!(!script.structured);
+        assert_eq!(script.process(0, &MidiMessage::from_bytes(&[0xF8]), "*", "*", "*", "*"), vec![("y".to_string(), vec![0xF8])]);
+    }
+
+    #[test]
+    fn midi_module () {
+        let mut script = Script::new("");
+        script.init().expect("");
+        let code = r#"
+            function on_midi(msg)
+                if msg.type == midi.NOTE_ON then
+                    local res = {}
+                    for _, note in ipairs(midi.chord(msg.note, "minor")) do
+                        table.insert(res, { port = "out", bytes = midi.note_on(msg.channel, note, msg.velocity) })
+                    end
+                    return res
+                elseif msg.type == midi.CC then
+                    return { { port = "cc", bytes = midi.cc(16, msg.controller, 127 - msg.value) } }
+                end
+                return { { port = msg.type, bytes = midi.pitch_bend(1, 8192) } }
+            end
+        "#;
+        script.set_data("script".to_string(), json!(code)).unwrap();
+        let res = script.process(0, &MidiMessage::from_bytes(&[0x91, 60, 100]), "*", "*", "*", "*");
+        assert_eq!(res, vec![
+            ("out".to_string(), vec![0x91, 60, 100]),
+            ("out".to_string(), vec![0x91, 63, 100]),
+            ("out".to_string(), vec![0x91, 67, 100]),
+        ]);
+        let res = script.process(0, &MidiMessage::from_bytes(&[0xB0, 7, 100]), "*", "*", "*", "*");
+        assert_eq!(res, vec![("cc".to_string(), vec![0xBF, 7, 27])]);
+        let res = script.process(0, &MidiMessage::from_bytes(&[0xF8]), "*", "*", "*", "*");
+        assert_eq!(res, vec![("Clock".to_string(), vec![0xE0, 0x00, 0x40])]);
+    }
+
+    #[test]
+    fn midi_module_helpers () {
+        let mut script = Script::new("");
+        script.init().expect("");
+        let code = r#"
+            msg = midi.parse({ 0xE3, 0x00, 0x40 })
+            bend = msg.value
+            channel = msg.channel
+            clock_channel = midi.parse(midi.clock()).channel or -1
+            note = midi.note_number("A4")
+            name_ok = midi.note_name(61) == "C#4" and 1 or 0
+            scale_len = #midi.scales.pentatonic_minor
+            quantized = midi.quantize(61, 60, "major")
+            quantized_up = midi.quantize(66, 62, "minor")
+            ok, err = pcall(midi.note_on, 17, 60, 100)
+            bad_channel = ok and 1 or 0
+            ok = pcall(midi.chord, 60, "nope")
+            bad_chord = ok and 1 or 0
+        "#;
+        script.set_data("script".to_string(), json!(code)).unwrap();
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        assert_eq!(global(&script, "bend"), 8192);
+        assert_eq!(global(&script, "channel"), 4);
+        assert_eq!(global(&script, "clock_channel"), -1);
+        assert_eq!(global(&script, "note"), 69);
+        assert_eq!(global(&script, "name_ok"), 1);
+        assert_eq!(global(&script, "scale_len"), 5);
+        assert_eq!(global(&script, "quantized"), 60);
+        assert_eq!(global(&script, "quantized_up"), 65);
+        assert_eq!(global(&script, "bad_channel"), 0);
+        assert_eq!(global(&script, "bad_chord"), 0);
+    }
+
+    #[test]
+    fn store () {
+        let mut script = Script::new("");
+        script.init().expect("");
+        let code = r#"
+            store.count = (store.count or 0) + 1
+            store.notes = { 60, 64, 67 }
+            store.scene = { name = "intro", ratio = 0.5 }
+            store.fn = function() end
+        "#;
+        script.set_data("script".to_string(), json!(code)).unwrap();
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        script.set_data("reset-state".to_string(), json!(null)).unwrap();
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        let value = Device::serialize(&script).unwrap();
+        assert_eq!(value["store"], json!({
+            "count": 2, // only per message, not when saved or reset
+            "notes": [60, 64, 67],
+            "scene": { "name": "intro", "ratio": 0.5 }
+        }));
+
+        let mut restored = Script::new("");
+        restored.init().expect("");
+        restored.set_data("store".to_string(), value["store"].clone()).unwrap();
+        restored.set_data("script".to_string(), json!("third = store.notes[3]")).unwrap();
+        restored.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        assert_eq!(global(&restored, "third"), 67);
+        assert!(restored.set_data("store".to_string(), json!([1])).is_err());
+        restored.delete_data("store".to_string()).unwrap();
+        assert_eq!(Device::serialize(&restored).unwrap()["store"], json!({}));
+    }
+
+    #[test]
+    fn shared_store () {
+        let mut a = Script::new("script-shared-a");
+        let mut b = Script::new("script-shared-b");
+        a.init().expect("");
+        b.init().expect("");
+        let code = r#"
+            function on_shared(key, value)
+                if key == "script-scene" then
+                    scene = value.index
+                end
+            end
+        "#;
+        b.set_data("script".to_string(), json!(code)).unwrap();
+        a.set_data("script".to_string(), json!(r#"shared.set("script-scene", { index = 3 })"#)).unwrap();
+        a.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        assert!(wait_for(|| global(&b, "scene") == 3));
+        assert_eq!(shared::get("script-scene"), Some(json!({ "index": 3 })));
+
+        b.destroy();
+        a.set_data("script".to_string(), json!(r#"shared.set("script-scene", { index = 4 }) x = shared.get("script-scene").index"#)).unwrap();
+        a.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        assert_eq!(global(&a, "x"), 4);
+        assert_eq!(global(&b, "scene"), 3); // watchers are removed right away, nothing is pending
+        shared::set("script-scene", JsonValue::Null, "");
+    }
+
+    #[test]
+    #[serial]
+    fn require_modules () {
+        let dir = std::env::temp_dir().join("mididash_library_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("chords")).unwrap();
+        std::fs::write(dir.join("utils.lua"), "loads = (loads or 0) + 1 return { double = function(x) return x * 2 end }").unwrap();
+        std::fs::write(dir.join("chords").join("jazz.lua"), "return { ninth = 14 }").unwrap();
+        std::fs::write(dir.join("cycle.lua"), "return require('cycle')").unwrap();
+        lua_library::set_dirs(vec![dir.clone()]);
+        lua_library::set_embedded(&serde_json::from_value(json!({ "embedded": "return 5", "utils": "return {}" })).unwrap());
+
+        let mut script = Script::new("");
+        script.init().expect("");
+        let code = r#"
+            local utils = require("utils")
+            doubled = utils.double(21)
+            ninth = require("chords.jazz").ninth
+            embedded = require("embedded")
+            cycle = pcall(require, "cycle") and 1 or 0
+            missing = pcall(require, "missing") and 1 or 0
+            escaped = pcall(require, "../utils") and 1 or 0
+        "#;
+        script.set_data("script".to_string(), json!(code)).unwrap();
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        assert_eq!(global(&script, "doubled"), 42); // folders come before embedded modules
+        assert_eq!(global(&script, "ninth"), 14);
+        assert_eq!(global(&script, "embedded"), 5);
+        assert_eq!(global(&script, "cycle"), 0);
+        assert_eq!(global(&script, "missing"), 0);
+        assert_eq!(global(&script, "escaped"), 0);
+        assert_eq!(global(&script, "loads"), 1); // cached between runs
+
+        std::fs::write(dir.join("utils.lua"), "loads = loads + 1 return { double = function(x) return x * 3 end }").unwrap();
+        std::thread::sleep(lua_library::STAMP_TTL);
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
+        assert_eq!(global(&script, "doubled"), 63);
+        assert_eq!(global(&script, "loads"), 2);
+
+        let sources = lua_library::sources();
+        assert_eq!(sources.get("chords.jazz"), Some(&json!("return { ninth = 14 }")));
+        assert_eq!(sources.get("embedded"), Some(&json!("return 5")));
+        assert!(sources.get("utils").unwrap().as_str().unwrap().contains("x * 3"));
+
+        lua_library::set_dirs(vec![]);
+        lua_library::set_embedded(&serde_json::Map::new());
+        let _ = std::fs::remove_dir_all(&dir);
     }
 
     #[test]
@@ -302,6 +1234,6 @@ mod tests {
             log("12345")
         "#;
         let _ = script.set_data("script".to_string(), serde_json::to_value(json!(code)).expect("fail"));
-        script.process(&vec![], "*", "*", "*", "*");
+        script.process(0, &MidiMessage::from_bytes(&[]), "*", "*", "*", "*");
     }
 }
\ No newline at end of file
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use serde::Serialize;
 use serde_json::{Value, Error};
 use std::error::Error as StdErr;
-use crate::{devices::device::Device, globals::{PORT_AFTERTOUCH, PORT_CC, PORT_CHANNEL_AT, PORT_COMMON, PORT_CONTINUE, PORT_NOTE_OFF, PORT_NOTE_ON, PORT_PITCH, PORT_PROGRAM, PORT_REALTIME, PORT_START, PORT_STOP, PORT_SYSEX, PORT_UNKNOWN}, utils::{parse_midi, MIDI_AFTERTOUCH, MIDI_CC, MIDI_CHANNEL_AT,
+use crate::{devices::device::Device, globals::{PORT_AFTERTOUCH, PORT_CC, PORT_CHANNEL_AT, PORT_COMMON, PORT_CONTINUE, PORT_NOTE_OFF, PORT_NOTE_ON, PORT_PARAM, PORT_PITCH, PORT_PROGRAM, PORT_REALTIME, PORT_START, PORT_STOP, PORT_SYSEX, PORT_UNKNOWN}, utils::{parse_midi, MidiMessage, ParamDecoder, MIDI_AFTERTOUCH, MIDI_CC, MIDI_CHANNEL_AT,
     MIDI_EXT_ACTIVE_SNS, MIDI_EXT_CLOCK, MIDI_EXT_CONTINUE, MIDI_EXT_MTC, MIDI_EXT_POSITION,
     MIDI_EXT_RESET, MIDI_EXT_SELECT, MIDI_EXT_START, MIDI_EXT_STOP, MIDI_EXT_SYSEX,
     MIDI_EXT_TUNE, MIDI_NOTE_OFF, MIDI_NOTE_ON, MIDI_PITCH, MIDI_PROG_CHNG
@@ -16,7 +16,9 @@ pub struct Splitter {
     pub id: String,
     pub class: String,
     #[serde(skip_serializing)]
-    pub sysex: HashMap<String, Vec<u8>>
+    pub sysex: HashMap<(String, String), Vec<u8>>,
+    #[serde(skip_serializing)]
+    pub params: HashMap<(String, String), ParamDecoder>,
 }
 
 impl Splitter {
@@ -24,7 +26,8 @@ impl Splitter {
         Splitter {
             id: String::from(id),
             class: String::from("split"),
-            sysex: HashMap::new()
+            sysex: HashMap::new(),
+            params: HashMap::new(),
         }
     }
 }
@@ -43,19 +46,17 @@ impl Device for Splitter {
     }
     fn process(
         &mut self,
-        bytes: &Vec<u8>,
+        _ts: u64,
+        msg: &MidiMessage,
         from: &str,
         _to: &str,
         from_port: &str,
         _to_port: &str
     ) -> Vec<(String, Vec<u8>)> {
-        let src = from.to_string() + from_port;
-        if !self.sysex.contains_key(&src) {
-            self.sysex.insert(src.clone(), vec![]);
-        }
+        let src = (from.to_string(), from_port.to_string());
         let mut results = vec![];
-        let sysex = self.sysex.get_mut(&src);
-        let events = parse_midi(bytes.clone(), sysex.unwrap());
+        let sysex = self.sysex.entry(src.clone()).or_default();
+        let events = parse_midi(msg.to_bytes(), sysex);
         for event in events {
             let name = event.0;
             let channel = event.1;
@@ -77,13 +78,13 @@ impl Device for Splitter {
             results.push(("*".to_string(), bytes.clone()));
             results.push((port.to_string(), bytes.clone()));
 
-            if port == MIDI_EXT_START {
+            if name == MIDI_EXT_START {
                 results.push((PORT_START.to_string(), bytes.clone()));
             }
-            else if port == MIDI_EXT_CONTINUE {
+            else if name == MIDI_EXT_CONTINUE {
                 results.push((PORT_CONTINUE.to_string(), bytes.clone()));
             }
-            else if port == MIDI_EXT_STOP {
+            else if name == MIDI_EXT_STOP {
                 results.push((PORT_STOP.to_string(), bytes.clone()));
             }
 
@@ -92,7 +93,32 @@ impl Device for Splitter {
             }
         }
 
+        // each completed 14 bit parameter once, as its control change sequence
+        if let Some(event) = self.params.entry(src).or_default().decode(msg) {
+            for bytes in event.to_messages() {
+                results.push((PORT_PARAM.to_string(), bytes));
+            }
+        }
 
         results
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(splitter: &mut Splitter, bytes: &[u8]) -> Vec<Vec<u8>> {
+        splitter.process(0, &MidiMessage::from_bytes(bytes), "a", "*", "*", "*").into_iter()
+            .filter(|r| r.0 == PORT_PARAM)
+            .map(|r| r.1)
+            .collect()
+    }
+
+    #[test]
+    fn param_port () {
+        let mut splitter = Splitter::new("");
+        assert!(params(&mut splitter, &[0xB0, 7, 100]).is_empty()); // 7 bit volume
+        assert!(params(&mut splitter, &[0xB0, 1, 64]).is_empty());
+        assert_eq!(params(&mut splitter, &[0xB0, 33, 2]), vec![vec![0xB0, 1, 64], vec![0xB0, 33, 2]]);
+    }
+}
@@ -1,16 +1,87 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{Value, Error};
 use std::error::Error as StdErr;
 use super::device::Device;
+use crate::{hub::Hub, scheduler, utils::{self, MidiMessage}};
 
 /*
- * Delays midi inputs and outputs to display on the viewport
+ * Sends configured messages when fired from the frontend or by an incoming message
  */
 
+const MIN_INTERVAL: u64 = 10; // millis between repeats
+
+/**
+ * Message sent when firing, notes are released after their length
+ * or on the next fire in toggle and repeat modes
+ */
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TriggerMessage {
+    Note {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+        #[serde(default = "default_length")]
+        length: u64, // millis
+    },
+    Cc { channel: u8, controller: u8, value: u8 },
+    Program { channel: u8, program: u8 },
+    Sysex { bytes: Vec<u8> },
+}
+
+fn default_length() -> u64 { 250 }
+
+impl TriggerMessage {
+    pub fn validate(&self) -> Result<(), String> {
+        let valid = match self {
+            TriggerMessage::Note { channel, note, velocity, .. } => *channel < 16 && *note < 128 && *velocity < 128,
+            TriggerMessage::Cc { channel, controller, value } => *channel < 16 && *controller < 128 && *value < 128,
+            TriggerMessage::Program { channel, program } => *channel < 16 && *program < 128,
+            TriggerMessage::Sysex { bytes } => bytes.len() >= 2 && bytes[0] == 0xF0 && bytes[bytes.len() - 1] == 0xF7 &&
+                bytes[1..bytes.len() - 1].iter().all(|b| *b < 0x80),
+        };
+        if valid { Ok(()) } else { Err(format!("Invalid trigger message {:?}", self)) }
+    }
+
+    fn on(&self) -> Vec<u8> {
+        match *self {
+            TriggerMessage::Note { channel, note, velocity, .. } => MidiMessage::NoteOn { channel, note, velocity }.to_bytes(),
+            TriggerMessage::Cc { channel, controller, value } => MidiMessage::ControlChange { channel, controller, value }.to_bytes(),
+            TriggerMessage::Program { channel, program } => MidiMessage::ProgramChange { channel, program }.to_bytes(),
+            TriggerMessage::Sysex { ref bytes } => bytes.clone(),
+        }
+    }
+
+    /**
+     * Message releasing the on state, controllers go back to zero
+     */
+    fn off(&self) -> Option<Vec<u8>> {
+        match *self {
+            TriggerMessage::Note { channel, note, .. } => Some(MidiMessage::NoteOff { channel, note, velocity: 0 }.to_bytes()),
+            TriggerMessage::Cc { channel, controller, .. } => Some(MidiMessage::ControlChange { channel, controller, value: 0 }.to_bytes()),
+            _ => None
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerMode {
+    #[default]
+    Oneshot,
+    Toggle, // fires the messages and releases them on the next fire
+    Repeat, // fires the messages every interval until fired again
+}
+
 #[derive(Serialize)]
 pub struct Trigger {
     pub id: String,
     pub class: String,
+    pub messages: Vec<TriggerMessage>,
+    pub mode: TriggerMode,
+    pub interval: u64, // millis between repeats
+    pub input: Vec<u8>, // leading bytes of the incoming messages that fire, empty fires on any message
+    pub active: bool, // on state of toggle and repeat modes
 }
 
 impl Trigger {
@@ -18,6 +89,80 @@ impl Trigger {
         Trigger {
             id: String::from(id),
             class: String::from("trigger"),
+            messages: vec![],
+            mode: TriggerMode::Oneshot,
+            interval: 500,
+            input: vec![],
+            active: false,
+        }
+    }
+
+    /**
+     * Incoming messages matching the input bytes fire, note offs never do
+     */
+    fn fires_on(&self, msg: &MidiMessage) -> bool {
+        let note_off = matches!(msg, MidiMessage::NoteOff { .. } | MidiMessage::NoteOn { velocity: 0, .. });
+        !note_off && msg.to_bytes().starts_with(&self.input)
+    }
+
+    /**
+     * Returns the messages to send right away, later ones are scheduled
+     */
+    pub fn fire(&mut self, ts: u64) -> Vec<Vec<u8>> {
+        match self.mode {
+            TriggerMode::Oneshot => {
+                for message in &self.messages {
+                    if let (&TriggerMessage::Note { length, .. }, Some(off)) = (message, message.off()) {
+                        scheduler::schedule(ts + length.max(1) * 1000, off, &self.id, "*");
+                    }
+                }
+                self.messages.iter().map(TriggerMessage::on).collect()
+            },
+            TriggerMode::Toggle => {
+                if self.active {
+                    return self.release();
+                }
+                self.active = true;
+                self.messages.iter().map(TriggerMessage::on).collect()
+            },
+            TriggerMode::Repeat => {
+                if self.active {
+                    return self.release();
+                }
+                self.active = true;
+                let every = self.interval * 1000;
+                for message in &self.messages {
+                    scheduler::schedule_every(ts + every, every, message.on(), &self.id, "*");
+                    if let (&TriggerMessage::Note { length, .. }, Some(off)) = (message, message.off()) {
+                        // released before the next repeat
+                        let length = (length * 1000).min(every.saturating_sub(1000));
+                        scheduler::schedule_every(ts + length, every, off, &self.id, "*");
+                    }
+                }
+                self.messages.iter().map(TriggerMessage::on).collect()
+            }
+        }
+    }
+
+    /**
+     * Cancels scheduled messages and returns the ones releasing held notes,
+     * toggled controllers are also set back to zero
+     */
+    fn release(&mut self) -> Vec<Vec<u8>> {
+        let pending = scheduler::pending(&self.id) > 0;
+        scheduler::cancel(&self.id);
+        let active = std::mem::take(&mut self.active);
+        let toggled = active && self.mode == TriggerMode::Toggle;
+        self.messages.iter()
+            .filter(|m| if matches!(m, TriggerMessage::Note { .. }) { active || pending } else { toggled })
+            .filter_map(TriggerMessage::off)
+            .collect()
+    }
+
+    fn send(&self, messages: Vec<Vec<u8>>) {
+        let ts = utils::now_micros();
+        for bytes in messages {
+            Hub::send(ts, bytes, &self.id, "*", "*", "*");
         }
     }
 }
@@ -29,9 +174,43 @@ impl Device for Trigger {
     fn get_class(&self) -> &str {
         return &self.class;
     }
-    fn destroy(&mut self) {}
+    fn destroy(&mut self) {
+        let held = self.release();
+        self.send(held);
+    }
     fn get_data(&mut self, _key: String) -> Result<Option<Value>, String> { Ok(None) }
-    fn set_data(&mut self, _key: String, _data:Value) -> Result<(), String> { Ok(()) }
+    fn set_data(&mut self, key: String, data:Value) -> Result<(), String> {
+        match key.as_str() {
+            "messages" => {
+                let messages: Vec<TriggerMessage> = serde_json::from_value(data).map_err(|e| format!("Invalid trigger messages {}", e))?;
+                for message in &messages {
+                    message.validate()?;
+                }
+                let held = self.release();
+                self.send(held);
+                self.messages = messages;
+            },
+            "mode" => {
+                let mode = serde_json::from_value(data).map_err(|_| "Invalid mode, expected oneshot, toggle or repeat")?;
+                let held = self.release();
+                self.send(held);
+                self.mode = mode;
+            },
+            "interval" => {
+                self.interval = data.as_u64().filter(|i| *i >= MIN_INTERVAL).ok_or("Invalid interval")?;
+            },
+            "input" => {
+                let input: Vec<u8> = serde_json::from_value(data).map_err(|_| "Invalid input bytes")?;
+                self.input = input;
+            },
+            "fire" => {
+                let messages = self.fire(utils::now_micros());
+                self.send(messages);
+            },
+            _ => {}
+        }
+        Ok(())
+    }
     fn delete_data(&mut self, _key: String) -> Result<(), String> { Ok(()) }
 
     fn init(&mut self) -> Result<(), Box<dyn StdErr>> {
@@ -42,12 +221,93 @@ impl Device for Trigger {
     }
     fn process(
         &mut self,
-        _bytes: &Vec<u8>,
+        ts: u64,
+        msg: &MidiMessage,
         _from: &str,
         _to: &str,
         _from_port: &str,
         _to_port: &str
     ) -> Vec<(String, Vec<u8>)> {
-        vec![]
+        if !self.fires_on(msg) {
+            return vec![];
+        }
+        self.fire(ts).into_iter().map(|bytes| ("*".to_string(), bytes)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn trigger(id: &str, mode: &str) -> Trigger {
+        let mut trigger = Trigger::new(id);
+        trigger.set_data("messages".to_string(), json!([
+            { "type": "note", "channel": 0, "note": 60, "velocity": 100, "length": 60_000 },
+            { "type": "cc", "channel": 1, "controller": 7, "value": 90 },
+            { "type": "sysex", "bytes": [0xF0, 0x7E, 0xF7] },
+        ])).unwrap();
+        trigger.set_data("mode".to_string(), json!(mode)).unwrap();
+        trigger
+    }
+
+    #[test]
+    fn messages () {
+        let mut trigger = trigger("trigger-test-messages", "oneshot");
+        assert_eq!(trigger.messages.len(), 3);
+        assert!(trigger.set_data("messages".to_string(), json!([{ "type": "note", "channel": 16, "note": 60, "velocity": 1 }])).is_err());
+        assert!(trigger.set_data("messages".to_string(), json!([{ "type": "sysex", "bytes": [0xF0, 0x90, 0xF7] }])).is_err());
+        assert!(trigger.set_data("messages".to_string(), json!([{ "type": "pitch" }])).is_err());
+        assert!(trigger.set_data("mode".to_string(), json!("hold")).is_err());
+        assert!(trigger.set_data("interval".to_string(), json!(1)).is_err());
+        assert_eq!(trigger.messages.len(), 3);
+        let value = Device::serialize(&trigger).unwrap();
+        assert_eq!(value["messages"][0]["type"], json!("note"));
+        assert_eq!(value["mode"], json!("oneshot"));
+    }
+
+    #[test]
+    fn oneshot () {
+        let mut trigger = trigger("trigger-test-oneshot", "oneshot");
+        let res = trigger.process(utils::now_micros(), &MidiMessage::Start, "*", "*", "*", "*");
+        assert_eq!(res, vec![
+            ("*".to_string(), vec![0x90, 60, 100]),
+            ("*".to_string(), vec![0xB1, 7, 90]),
+            ("*".to_string(), vec![0xF0, 0x7E, 0xF7]),
+        ]);
+        assert_eq!(scheduler::pending("trigger-test-oneshot"), 1); // note off
+        assert!(!trigger.active);
+        trigger.destroy();
+        assert_eq!(scheduler::pending("trigger-test-oneshot"), 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn toggle () {
+        let mut trigger = trigger("trigger-test-toggle", "toggle");
+        trigger.set_data("input".to_string(), json!([0x90, 36])).unwrap();
+        let note = |velocity| MidiMessage::NoteOn { channel: 0, note: 36, velocity };
+        assert_eq!(trigger.process(0, &note(0), "*", "*", "*", "*"), vec![]);
+        assert_eq!(trigger.process(0, &MidiMessage::Start, "*", "*", "*", "*"), vec![]);
+        assert_eq!(trigger.process(0, &note(100), "*", "*", "*", "*").len(), 3);
+        assert!(trigger.active);
+        assert_eq!(trigger.process(0, &note(100), "*", "*", "*", "*"), vec![
+            ("*".to_string(), vec![0x80, 60, 0]),
+            ("*".to_string(), vec![0xB1, 7, 0]),
+        ]);
+        assert!(!trigger.active);
+        assert_eq!(scheduler::pending("trigger-test-toggle"), 0);
+    }
+
+    #[test]
+    fn repeat () {
+        let mut trigger = trigger("trigger-test-repeat", "repeat");
+        trigger.set_data("interval".to_string(), json!(60_000)).unwrap();
+        let res = trigger.fire(utils::now_micros());
+        assert_eq!(res.len(), 3);
+        assert!(trigger.active);
+        assert_eq!(scheduler::pending("trigger-test-repeat"), 4); // 3 messages and the note off
+        assert_eq!(trigger.fire(utils::now_micros()), vec![vec![0x80, 60, 0]]);
+        assert!(!trigger.active);
+        assert_eq!(scheduler::pending("trigger-test-repeat"), 0);
+    }
+}
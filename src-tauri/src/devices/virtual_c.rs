@@ -1,9 +1,10 @@
-use std::sync::{Arc, Mutex};
+use std::{collections::VecDeque, sync::{Arc, Mutex}};
 
 use midir::{os::unix::{VirtualInput, VirtualOutput}, Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
 use serde::Serialize;
 use serde_json::{Error, Value};
-use crate::hub::Hub;
+use crate::hub::{self, Hub};
+use crate::utils::{self, MidiMessage};
 use regex::Regex;
 
 use super::device::Device;
@@ -31,6 +32,40 @@ pub struct VirtualC {
     oconn: Option<Mutex<MidiOutputConnection>>,
     #[serde(skip_serializing)]
     voconn: Option<Arc<Mutex<MidiOutputConnection>>>,
+    #[serde(skip_serializing)]
+    sent: Arc<Mutex<SentQueue>>,
+}
+
+const MAX_SENT: usize = 1024; // messages waiting to come back through the cable
+
+/**
+ * Messages sent through the cable with their hops, so the ones coming back keep counting them
+ * while messages from other apps on the virtual input start from zero
+ */
+#[derive(Default)]
+struct SentQueue {
+    queue: VecDeque<(Vec<u8>, usize)>,
+}
+
+impl SentQueue {
+    fn sent(&mut self, bytes: Vec<u8>, hops: usize) {
+        if self.queue.len() >= MAX_SENT {
+            self.queue.pop_front();
+        }
+        self.queue.push_back((bytes, hops));
+    }
+
+    /**
+     * Hops of received bytes, older messages that never came back are dropped
+     */
+    fn received(&mut self, bytes: &[u8]) -> usize {
+        let Some(index) = self.queue.iter().position(|(sent, _)| sent == bytes) else {
+            return 0;
+        };
+        let hops = self.queue[index].1;
+        self.queue.drain(..=index);
+        hops
+    }
 }
 
 impl VirtualC {
@@ -44,6 +79,7 @@ impl VirtualC {
             viconn: None,
             oconn: None,
             voconn: None,
+            sent: Arc::new(Mutex::new(SentQueue::default())),
         }
     }
 }
@@ -110,13 +146,13 @@ impl Device for VirtualC {
 
         // Virtual Out -> Input -> Hub
         let id = self.id.clone();
+        let sent = Arc::clone(&self.sent);
         self.iconn = Some(Mutex::new(input.connect(
             &in_port.unwrap(),
             &self.id,
-            move |ts, bytes, _| {
-                let hub_instance = Hub::get_instance();
-                let mut hub = hub_instance.lock().unwrap();
-                hub.process(ts, &bytes.to_vec(), &id, "*", "*", "*");
+            move |_, bytes, _| {
+                let hops = sent.lock().unwrap().received(bytes);
+                Hub::send_hops(utils::now_micros(), bytes.to_vec(), &id, "*", "*", "*", hops);
             },
             ()
         ).unwrap()));
@@ -164,7 +200,8 @@ impl Device for VirtualC {
     fn delete_data(&mut self, _key: String) -> Result<(), String> { Ok(()) }
     fn process(
             &mut self,
-            bytes: &Vec<u8>,
+            _ts: u64,
+            msg: &MidiMessage,
             _from: &str,
             _to: &str,
             _from_port: &str,
@@ -172,8 +209,10 @@ impl Device for VirtualC {
         ) -> Vec<(String, Vec<u8>)>
     {
         if let Some(ref mutex) = self.oconn {
+            let bytes = msg.to_bytes();
+            self.sent.lock().unwrap().sent(bytes.clone(), hub::current_hops());
             let mut conn = mutex.lock().unwrap();
-            if let Err(e) = conn.send(bytes) { // send message to virtual midi channel
+            if let Err(e) = conn.send(&bytes) { // send message to virtual midi channel
                 eprintln!("Error sending bytes from virtual output {} {}", self.out_id, e);
             }
         }
@@ -210,10 +249,10 @@ mod tests {
         fn get_class(&self) -> &str {
             return &self.class;
         }
-        fn process(&mut self, bytes: &Vec<u8>, _from: &str, _to: &str, _from_port: &str, _to_port: &str) -> Vec<(String, Vec<u8>)> {
+        fn process(&mut self, _ts: u64, msg: &MidiMessage, _from: &str, _to: &str, _from_port: &str, _to_port: &str) -> Vec<(String, Vec<u8>)> {
             let mut result = vec![];
-            let mut b = bytes.clone();
-            b[0] = bytes[0] + 1;
+            let mut b = msg.to_bytes();
+            b[0] += 1;
             result.push(("*".to_string(), b.clone()));
             self.bytes = b;
             result
@@ -254,14 +293,14 @@ mod tests {
         let handle = thread::spawn(move || {
             let mut c = VirtualC::new("Test virtual channel");
             c.init().expect("Failed to initialize virtual");
-            let hub_instance = Hub::get_instance();
-            let mut hub = hub_instance.lock().unwrap_or_else(|e| e.into_inner());
-            hub.destroy();
-            // create mock device to listen to hub events
-            let d1: Box<dyn Device> = Box::new(MockDevice::new("1"));
-            hub.add_device(d1);
+            Hub::call(|hub| {
+                hub.destroy();
+                // create mock device to listen to hub events
+                let d1: Box<dyn Device> = Box::new(MockDevice::new("1"));
+                hub.add_device(d1);
+                hub.connect("Test virtual channel", "1", "*", "*");
+            }).expect("");
             let bytes = vec![0x80, 0, 0];
-            hub.connect("Test virtual channel", "1", "*", "*");
 
             if let Some(mutex) = c.oconn {
                 let mut conn = mutex.lock().unwrap();
@@ -270,10 +309,25 @@ mod tests {
             std::thread::sleep(std::time::Duration::from_millis(50));
         });
         handle.join().unwrap();
-        let hub_instance = Hub::get_instance();
-        let hub = hub_instance.lock().unwrap_or_else(|e| e.into_inner());
         let get_device = |value: Option<Value>| serde_json::from_value(value.unwrap()).expect("Invalid JSON");
-        let b1: MockDevice = get_device(hub.serialize_device("1"));
+        let b1: MockDevice = get_device(Hub::call(|hub| hub.serialize_device("1")).expect(""));
         assert_eq!(b1.bytes[0], 0x81);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn sent_queue () {
+        let mut sent = SentQueue::default();
+        sent.sent(vec![0x90, 60, 100], 64);
+        sent.sent(vec![0x80, 60, 0], 12);
+        assert_eq!(sent.received(&[0xB0, 1, 2]), 0); // from another app
+        assert_eq!(sent.received(&[0x90, 60, 100]), 64); // looped back
+        assert_eq!(sent.received(&[0x90, 60, 100]), 0); // same bytes from another app after the loop
+        assert_eq!(sent.received(&[0x80, 60, 0]), 12);
+        assert_eq!(sent.received(&[0x80, 60, 0]), 0);
+        for i in 0..MAX_SENT + 1 {
+            sent.sent(vec![0xF8], i);
+        }
+        assert_eq!(sent.queue.len(), MAX_SENT);
+        assert_eq!(sent.received(&[0xF8]), 1);
+    }
+}
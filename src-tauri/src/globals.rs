@@ -8,6 +8,8 @@ pub const EVT_FILE_SAVE_AS: &str = "save-file-as";
 pub const EVT_FILE_SAVE: &str = "save-file";
 pub const EVT_SCRIPT_ERROR: &str = "script-error";
 pub const EVT_SCRIPT_LOG: &str = "script-log";
+pub const EVT_SHARED_CHANGE: &str = "shared-change";
+pub const EVT_DEVICE_STATUS: &str = "device-status";
 pub const EVT_SHOW_ABOUT: &str = "show-about";
 
 pub const PORT_NOTE_ON: &str = "noteon";
@@ -24,6 +26,9 @@ pub const PORT_STOP: &str = "stop";
 pub const PORT_COMMON: &str = "CM";
 pub const PORT_SYSEX: &str = "sysex";
 pub const PORT_UNKNOWN: &str = "unknown";
+pub const PORT_PARAM: &str = "param"; // 14 bit cc, nrpn and rpn
+pub const PORT_THRU: &str = "thru"; // mapper messages that matched no rule
+pub const PORT_DRY: &str = "dry"; // delay input passed through without delay
 
 pub const PREFIX_INPUT: &str = "Mdash In - ";
 pub const PREFIX_OUTPUT: &str = "Mdash Out - ";
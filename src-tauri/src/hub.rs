@@ -1,5 +1,6 @@
-use crate::{app, devices::device::Device, globals::EVT_MIDI};
-use std::{io, sync::{Arc, Mutex}};
+use crate::{app, devices::device::{Device, Job}, globals::EVT_MIDI, scheduler, utils::{MidiMessage, MidiPorts}};
+use std::collections::{HashMap, HashSet};
+use std::{any::Any, cell::Cell, io, panic::{self, AssertUnwindSafe}, sync::mpsc::{self, Sender}, thread, time::{Duration, Instant}};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -20,7 +21,9 @@ pub struct Connector {
     pub from: String,
     pub to: String,
     pub from_port: String,
-    pub to_port: String
+    pub to_port: String,
+    #[serde(flatten)]
+    pub settings: ConnectorSettings,
 }
 
 impl Connector {
@@ -31,7 +34,100 @@ impl Connector {
             from: String::from(from),
             to: String::from(to),
             from_port: String::from(from_port),
-            to_port: String::from(to_port)
+            to_port: String::from(to_port),
+            settings: ConnectorSettings::default(),
+        }
+    }
+}
+
+pub const ALL_CHANNELS: u16 = 0xFFFF;
+pub const ALL_TYPES: u16 = 0x1FF;
+pub const MAX_VELOCITY_SCALE: f64 = 4.0;
+
+/**
+ * Filters applied to messages travelling through a connector before they reach
+ * the destination device, the defaults let every message through unchanged
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ConnectorSettings {
+    pub muted: bool,
+    pub channels: u16, // bit per channel, bit 0 is channel 1
+    pub types: u16, // bit per message type, see type_bit()
+    pub note_min: u8, // note range of note on, note off and aftertouch
+    pub note_max: u8,
+    pub velocity_scale: f64, // applied to note on velocities
+}
+
+impl Default for ConnectorSettings {
+    fn default() -> Self {
+        ConnectorSettings {
+            muted: false,
+            channels: ALL_CHANNELS,
+            types: ALL_TYPES,
+            note_min: 0,
+            note_max: 127,
+            velocity_scale: 1.0,
+        }
+    }
+}
+
+impl ConnectorSettings {
+    /**
+     * Bit of a message in the types mask, 0 to 6 are channel messages from note off
+     * to pitch bend, 7 is system common and sysex, 8 is realtime
+     */
+    pub fn type_bit(msg: &MidiMessage) -> Option<u16> {
+        match msg {
+            MidiMessage::NoteOff { .. } => Some(0),
+            MidiMessage::NoteOn { .. } => Some(1),
+            MidiMessage::Aftertouch { .. } => Some(2),
+            MidiMessage::ControlChange { .. } => Some(3),
+            MidiMessage::ProgramChange { .. } => Some(4),
+            MidiMessage::ChannelAftertouch { .. } => Some(5),
+            MidiMessage::PitchBend { .. } => Some(6),
+            MidiMessage::SysEx(_) | MidiMessage::TimeCode(_) | MidiMessage::SongPosition(_) |
+            MidiMessage::SongSelect(_) | MidiMessage::TuneRequest => Some(7),
+            MidiMessage::Clock | MidiMessage::Start | MidiMessage::Continue | MidiMessage::Stop |
+            MidiMessage::ActiveSensing | MidiMessage::Reset => Some(8),
+            MidiMessage::Unknown(_) => None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.note_min > self.note_max || self.note_max > 127 {
+            Err("Invalid note range")?
+        }
+        if !(0.0..=MAX_VELOCITY_SCALE).contains(&self.velocity_scale) {
+            Err(format!("Velocity scale must be between 0 and {}", MAX_VELOCITY_SCALE))?
+        }
+        Ok(())
+    }
+
+    /**
+     * Returns the message to deliver or None if it is filtered out,
+     * unknown messages are only dropped by muting
+     */
+    pub fn apply(&self, msg: &MidiMessage) -> Option<MidiMessage> {
+        if self.muted {
+            return None;
+        }
+        if msg.channel().is_some_and(|channel| self.channels & (1 << channel) == 0) {
+            return None;
+        }
+        if Self::type_bit(msg).is_some_and(|bit| self.types & (1 << bit) == 0) {
+            return None;
+        }
+        match *msg {
+            MidiMessage::NoteOff { note, .. } |
+            MidiMessage::NoteOn { note, .. } |
+            MidiMessage::Aftertouch { note, .. } if note < self.note_min || note > self.note_max => None,
+            // zero velocity is a note off and must stay one
+            MidiMessage::NoteOn { channel, note, velocity } if velocity > 0 && self.velocity_scale != 1.0 => {
+                let velocity = (velocity as f64 * self.velocity_scale).round().clamp(1.0, 127.0) as u8;
+                Some(MidiMessage::NoteOn { channel, note, velocity })
+            },
+            _ => Some(msg.clone()),
         }
     }
 }
@@ -40,10 +136,88 @@ pub struct Hub {
     devices: Vec<Box<dyn Device>>,
     connectors: Vec<Connector>,
     paused: bool,
+    max_hops: usize,
+    dropped: HashMap<(String, String), Instant>, // last report of messages dropped between two devices
+}
+
+/**
+ * Default number of devices a message can travel through before being dropped
+ */
+pub const DEFAULT_MAX_HOPS: usize = 64;
+
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+thread_local! {
+    // hops of the messages sent from this thread, set while a device processes a message
+    static HOPS: Cell<usize> = const { Cell::new(0) };
 }
 
-// Static singleton instance of Hub using Lazy for thread-safe initialization
-static INSTANCE: Lazy<Arc<Mutex<Hub>>> = Lazy::new(|| Arc::new(Mutex::new(Hub::new())));
+/**
+ * Hops of the messages sent from the current thread, 0 outside of device processing
+ */
+pub fn current_hops() -> usize {
+    HOPS.with(Cell::get)
+}
+
+/**
+ * Runs a closure with the messages it sends, directly or through the scheduler,
+ * counted as hops away from their source so feedback loops stay limited
+ */
+pub fn with_hops<R>(hops: usize, f: impl FnOnce() -> R) -> R {
+    struct Restore(usize);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            HOPS.with(|h| h.set(self.0));
+        }
+    }
+    let _restore = Restore(HOPS.with(|h| h.replace(hops)));
+    f()
+}
+
+/**
+ * Messages drained by the hub dispatch thread, midi is queued by input callbacks
+ * and devices, control closures are sent by commands that need access to the hub
+ */
+enum Message {
+    Midi {
+        ts: u64,
+        bytes: Vec<u8>,
+        from: String,
+        to: String,
+        from_port: String,
+        to_port: String,
+        hops: usize,
+    },
+    Control(Box<dyn FnOnce(&mut Hub) + Send>),
+}
+
+// Queue into the dispatch thread, the thread is spawned on first use and owns the hub instance
+static DISPATCH: Lazy<Sender<Message>> = Lazy::new(|| {
+    let (tx, rx) = mpsc::channel::<Message>();
+    thread::Builder::new()
+        .name("hub-dispatch".into())
+        .spawn(move || {
+            let mut hub = Hub::new();
+            for message in rx {
+                // a panicking device must not take down the routing thread
+                let result = panic::catch_unwind(AssertUnwindSafe(|| match message {
+                    Message::Midi { ts, bytes, from, to, from_port, to_port, hops } => {
+                        hub.process_hops(ts, &bytes, &from, &to, &from_port, &to_port, hops);
+                    },
+                    Message::Control(f) => f(&mut hub),
+                }));
+                if result.is_err() {
+                    eprintln!("hub::dispatch() message handler panicked");
+                }
+            }
+        })
+        .expect("Failed to spawn hub dispatch thread");
+    tx
+});
+
+fn dispatch(message: Message) -> Result<(), String> {
+    DISPATCH.send(message).map_err(|_| "Hub dispatch thread is not running".to_string())
+}
 
 impl Hub {
     pub fn new() -> Self {
@@ -51,17 +225,59 @@ impl Hub {
             devices: Vec::new(),
             connectors: Vec::new(),
             paused: false,
+            max_hops: DEFAULT_MAX_HOPS,
+            dropped: HashMap::new(),
+        }
+    }
+
+    /**
+     * Queues a midi message to be processed by the dispatch thread,
+     * never blocks so it is safe to call from midi callbacks.
+     * Messages sent by a device while it processes another one keep counting its hops
+     */
+    pub fn send(ts: u64, bytes: Vec<u8>, from: &str, to: &str, from_port: &str, to_port: &str) {
+        Hub::send_hops(ts, bytes, from, to, from_port, to_port, current_hops());
+    }
+
+    /**
+     * Queues a midi message that already travelled through a number of devices
+     */
+    pub fn send_hops(ts: u64, bytes: Vec<u8>, from: &str, to: &str, from_port: &str, to_port: &str, hops: usize) {
+        let message = Message::Midi {
+            ts,
+            bytes,
+            from: from.into(),
+            to: to.into(),
+            from_port: from_port.into(),
+            to_port: to_port.into(),
+            hops,
+        };
+        if let Err(e) = dispatch(message) {
+            eprintln!("hub::send() {}", e);
         }
     }
 
-    // Singleton access method using `Lazy`
-    pub fn get_instance() -> Arc<Mutex<Hub>> {
-        INSTANCE.clone() // Return the singleton instance wrapped in an Arc<Mutex<Hub>>
+    /**
+     * Runs a closure with the hub on the dispatch thread and waits for its result,
+     * messages queued before the call are processed first.
+     * Must not be called from inside a device running on the dispatch thread
+     */
+    pub fn call<R, F>(f: F) -> Result<R, String>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Hub) -> R + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(1);
+        dispatch(Message::Control(Box::new(move |hub| {
+            let _ = tx.send(f(hub));
+        })))?;
+        rx.recv().map_err(|_| "Hub dispatch thread dropped the request".to_string())
     }
 
     pub fn destroy(&mut self) {
         for device in &mut self.devices {
             device.destroy();
+            scheduler::cancel(device.get_id());
         }
         self.devices.clear();
         self.connectors.clear();
@@ -80,6 +296,10 @@ impl Hub {
         self.paused = paused;
     }
 
+    pub fn set_max_hops(&mut self, max_hops: usize) {
+        self.max_hops = max_hops;
+    }
+
     /**
      * Used to reconnect inputs and outputs to their respective midi ports
      */
@@ -93,9 +313,50 @@ impl Hub {
         Ok(())
     }
 
+    /**
+     * Closes devices whose midi port disappeared and initializes again the ones
+     * whose port is back or was replaced, returns the devices that went online or offline
+     */
+    pub fn sync_ports(&mut self, ports: &MidiPorts) -> Vec<(String, bool)> {
+        let mut changes = vec![];
+        for device in self.devices.iter_mut() {
+            let Some(online) = device.online() else {
+                continue;
+            };
+            let available = device.port_available(ports);
+            if online && !available {
+                device.destroy();
+            } else if available && (!online || device.port_changed(ports)) {
+                device.destroy();
+                if let Err(err) = device.init() {
+                    eprintln!("Failed to reconnect device {} {}", device.get_id(), err);
+                }
+            }
+            if let Some(state) = device.online().filter(|state| *state != online) {
+                changes.push((device.get_id().to_string(), state));
+            }
+        }
+        changes
+    }
+
+    /**
+     * Binds an input or output to another midi port, the device keeps its id and connectors
+     */
+    pub fn rebind_device(&mut self, id: &str, port: &str) -> Result<(), String> {
+        let device = self.devices.iter_mut()
+            .find(|d| d.get_id() == id)
+            .ok_or(format!("Failed to find device {}", id))?;
+        if device.online().is_none() {
+            Err(format!("Device {} is not bound to a midi port", id))?
+        }
+        device.set_data("port".to_string(), Value::String(port.to_string()))
+    }
+
     pub fn remove_device(&mut self, id: &str) -> bool {
         if let Some(index) = self.devices.iter().position(|d| d.get_id() == id) {
-            self.devices.remove(index);
+            let mut device = self.devices.remove(index);
+            device.destroy();
+            scheduler::cancel(id);
             return true;
         }
         false
@@ -118,6 +379,20 @@ impl Hub {
         }
     }
 
+    pub fn start_device_data(&mut self, id: &str, key: &str, data: &Value) -> Result<Option<Job>, String> {
+        match self.devices.iter_mut().find(|d| d.get_id() == id) {
+            Some(device) => Ok(device.start_data(key, data)),
+            None => Err(format!("Failed to find device {}", id))
+        }
+    }
+
+    pub fn finish_device_data(&mut self, id: &str, key: &str, result: Box<dyn Any + Send>) -> Result<Option<Value>, String> {
+        match self.devices.iter_mut().find(|d| d.get_id() == id) {
+            Some(device) => device.finish_data(key, result),
+            None => Err(format!("Failed to find device {}", id))
+        }
+    }
+
     pub fn serialize_device(&self, id: &str) -> Option<Value> {
         if let Some(device) = self.devices.iter().find(|d| d.get_id() == id) {
             return device.serialize().ok();
@@ -145,15 +420,58 @@ impl Hub {
         self.devices.iter().any(|d| d.get_id() == id)
     }
 
+    fn defers_output(&self, id: &str, port: &str) -> bool {
+        self.devices.iter().any(|d| d.get_id() == id && d.defers_output(port))
+    }
+
+    /**
+     * Returns true if connecting from -> to closes a loop in the device graph that no
+     * message is deferred on, input ports are ignored since devices can route any input
+     * to any output. Feedback through delays is allowed and limited by max_hops
+     */
+    pub fn creates_cycle(&self, from: &str, to: &str, from_port: &str) -> bool {
+        if from == "*" || to == "*" { // wildcards are message sources, not devices
+            return false;
+        }
+        if self.defers_output(from, from_port) {
+            return false;
+        }
+        let mut visited = HashSet::new();
+        let mut stack = vec![to];
+        while let Some(id) = stack.pop() {
+            if id == from {
+                return true;
+            }
+            if visited.insert(id) {
+                for c in self.connectors.iter().filter(|c| c.from == id && !self.defers_output(&c.from, &c.from_port)) {
+                    stack.push(&c.to);
+                }
+            }
+        }
+        false
+    }
+
+    /**
+     * Returns None if the connector already exists or would create a cycle
+     */
     pub fn connect(&mut self, from: &str, to: &str, from_port: &str, to_port: &str) -> Option<Connector> {
         let connector = Connector::new(from, to, from_port, to_port);
-        if self.connectors.iter().any(|d| d.id == connector.id) {
+        if self.connectors.iter().any(|d| d.id == connector.id) || self.creates_cycle(from, to, from_port) {
             return None;
         }
         self.connectors.push(connector.clone());
         Some(connector.clone())
     }
 
+    pub fn set_connector_settings(&mut self, id: &str, settings: ConnectorSettings) -> Result<Connector, String> {
+        settings.validate()?;
+        let connector = self.connectors.iter_mut()
+            .find(|c| c.id == id)
+            .ok_or(format!("Failed to find connector {}", id))?;
+        connector.settings = settings;
+        Ok(connector.clone())
+    }
+
     pub fn disconnect(&mut self, from: &str, to: &str, from_port: &str, to_port: &str) -> bool {
         if let Some(index) = self.connectors.iter().position(|c|
             c.from == from && c.to == to &&
@@ -168,9 +486,35 @@ impl Hub {
         Recursively processes a midi message inside each connnected device
      */
     pub fn process(&mut self, ts: u64, bytes: &Vec<u8>, from: &str, to: &str, from_port: &str, to_port: &str) {
+        self.process_hops(ts, bytes, from, to, from_port, to_port, 0);
+    }
+
+    /*
+        Processes a message that has already travelled through a number of devices,
+        messages exceeding max_hops are dropped to protect against feedback loops
+     */
+    #[allow(clippy::too_many_arguments)]
+    fn process_hops(&mut self, ts: u64, bytes: &[u8], from: &str, to: &str, from_port: &str, to_port: &str, hops: usize) {
         if self.paused {
             return
         }
+        if hops > self.max_hops {
+            // reported once in a while, loops drop messages continuously
+            let key = (from.to_string(), to.to_string());
+            if self.dropped.get(&key).is_none_or(|last| last.elapsed() >= DROP_REPORT_INTERVAL) {
+                self.dropped.insert(key, Instant::now());
+                app::emit_error(&format!("Message dropped after {} hops from {} to {}, check for feedback loops", self.max_hops, from, to));
+            }
+            return
+        }
+        // devices expect single messages, packets may hold several or use running status
+        let messages = MidiMessage::split(bytes);
+        if messages.len() > 1 {
+            for message in messages {
+                self.process_hops(ts, &message, from, to, from_port, to_port, hops);
+            }
+            return
+        }
         app::emit(EVT_MIDI, MidiEvent {
             ts,
             from: from.into(),
@@ -180,16 +524,21 @@ impl Hub {
             bytes: bytes.to_vec()
         });
 
+        let msg = MidiMessage::from_bytes(bytes);
         let mut results = Vec::new();
         // fetch connectors from this source to destinations
         for c in self.connectors.iter().filter(|c|
             c.from == from && (to == "*" || c.to == to) && // to == "*" means to any connected device
             c.from_port == from_port && c.to_port == to_port)
         {
+            let Some(msg) = c.settings.apply(&msg) else {
+                continue;
+            };
             // fetch the matching device to this connector destination
             if let Some(device) = self.devices.iter_mut().find(|d| d.get_id() == c.to) {
                 // process the midi message inside the device,
-                let processed = device.process(&bytes.clone(), from, to, from_port, to_port);
+                // messages sent or scheduled by the device count as its outputs
+                let processed = with_hops(hops + 1, || device.process(ts, &msg, from, to, from_port, to_port));
                 for result in processed {
                     let target_port = result.0;
                     let payload = result.1;
@@ -217,7 +566,7 @@ impl Hub {
             }
         }
         for (payload, from, to, from_port, to_port) in results {
-            self.process(ts, &payload, &from, &to, &from_port, &to_port);
+            self.process_hops(ts, &payload, &from, &to, &from_port, &to_port, hops + 1);
         }
     }
 }
@@ -234,13 +583,22 @@ mod tests {
         id: String,
         bytes: Vec<u8>,
         class: String,
+        #[serde(default)]
+        ts: u64,
+        #[serde(default)]
+        count: usize,
+        #[serde(default)]
+        hops: usize,
     }
     impl MockDevice {
         fn new(id: &str) -> Self {
             MockDevice {
                 id: String::from(id),
                 bytes: vec![],
-                class: String::from("mock")
+                class: String::from("mock"),
+                ts: 0,
+                count: 0,
+                hops: 0,
             }
         }
     }
@@ -251,14 +609,20 @@ mod tests {
         fn get_class(&self) -> &str {
             return &self.class;
         }
-        fn process(&mut self, bytes: &Vec<u8>, _from: &str, _to: &str, _from_port: &str, _to_port: &str) -> Vec<(String, Vec<u8>)> {
+        fn process(&mut self, ts: u64, msg: &MidiMessage, _from: &str, _to: &str, _from_port: &str, _to_port: &str) -> Vec<(String, Vec<u8>)> {
+            self.ts = ts;
+            self.count += 1;
+            self.hops = current_hops();
             let mut result = vec![];
-            let mut b = bytes.clone();
-            b[0] = bytes[0] + 1;
+            let mut b = msg.to_bytes();
+            b[0] += 1;
             result.push(("*".to_string(), b.clone()));
             self.bytes = b;
             result
         }
+        fn defers_output(&self, port: &str) -> bool {
+            self.id == "delay" && port != "dry"
+        }
         fn init(&mut self) -> Result<(), Box<dyn std::error::Error>> {
             Ok(())
         }
@@ -271,12 +635,103 @@ mod tests {
         fn delete_data(&mut self, _key: String) -> Result<(), String> { Ok(()) }
     }
 
+    pub struct MockPort {
+        id: String,
+        port: String,
+        online: bool,
+        fail: bool,
+    }
+    impl MockPort {
+        fn new(id: &str, online: bool, fail: bool) -> Self {
+            MockPort { id: id.to_string(), port: id.to_string(), online, fail }
+        }
+    }
+    impl Device for MockPort {
+        fn get_id(&self) -> &str { &self.id }
+        fn get_class(&self) -> &str { "input" }
+        fn process(&mut self, _ts: u64, _msg: &MidiMessage, _from: &str, _to: &str, _from_port: &str, _to_port: &str) -> Vec<(String, Vec<u8>)> { vec![] }
+        fn init(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+            if self.fail {
+                Err("port busy")?
+            }
+            self.online = true;
+            Ok(())
+        }
+        fn destroy(&mut self) {
+            self.online = false;
+        }
+        fn serialize(&self) -> Result<Value, Error> { Ok(Value::Null) }
+        fn get_data(&mut self, _key: String) -> Result<Option<Value>, String> { Ok(None) }
+        fn set_data(&mut self, key: String, data:Value) -> Result<(), String> {
+            if key == "port" {
+                self.port = data.as_str().unwrap().to_string();
+                self.destroy();
+                let _ = self.init();
+            }
+            Ok(())
+        }
+        fn delete_data(&mut self, _key: String) -> Result<(), String> { Ok(()) }
+        fn online(&self) -> Option<bool> { Some(self.online) }
+        fn port_available(&self, ports: &MidiPorts) -> bool { ports.inputs.contains(&self.port) }
+        fn port_changed(&self, ports: &MidiPorts) -> bool {
+            self.online && !ports.input_ids.contains(&self.port)
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn sync_ports() {
+        let mut hub = Hub::new();
+        hub.add_device(Box::new(MockPort::new("a", true, false)));
+        hub.add_device(Box::new(MockPort::new("b", false, false)));
+        hub.add_device(Box::new(MockPort::new("c", false, true)));
+        hub.add_device(Box::new(MockDevice::new("d")));
+        let ports = |names: &[&str]| {
+            let inputs: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+            MidiPorts { input_ids: inputs.clone(), inputs, ..Default::default() }
+        };
+
+        assert_eq!(hub.sync_ports(&ports(&["a", "c"])), vec![]);
+        assert_eq!(hub.sync_ports(&ports(&["b", "c"])), vec![("a".to_string(), false), ("b".to_string(), true)]);
+        assert_eq!(hub.sync_ports(&ports(&["b", "c"])), vec![]);
+        assert_eq!(hub.sync_ports(&ports(&["a"])), vec![("a".to_string(), true), ("b".to_string(), false)]);
+    }
+
+    #[test]
+    #[serial]
+    fn sync_replaced_ports() {
+        let mut hub = Hub::new();
+        hub.add_device(Box::new(MockPort::new("a", true, false)));
+        // same name with another identity, like a device plugged in again between polls
+        let replaced = MidiPorts { inputs: vec!["a".to_string()], input_ids: vec!["a2".to_string()], ..Default::default() };
+        assert_eq!(hub.sync_ports(&replaced), vec![]);
+        assert!(hub.devices[0].online().unwrap());
+
+        hub.add_device(Box::new(MockPort::new("b", true, true)));
+        let replaced = MidiPorts { inputs: vec!["a".to_string(), "b".to_string()], input_ids: vec!["a".to_string()], ..Default::default() };
+        assert_eq!(hub.sync_ports(&replaced), vec![("b".to_string(), false)]);
+    }
+
+    #[test]
+    #[serial]
+    fn rebind_device() {
+        let mut hub = Hub::new();
+        hub.add_device(Box::new(MockPort::new("a", true, false)));
+        hub.add_device(Box::new(MockDevice::new("b")));
+        hub.connect("a", "b", "*", "*");
+        hub.rebind_device("a", "c").unwrap();
+        assert!(hub.rebind_device("b", "c").is_err());
+        assert!(hub.rebind_device("d", "c").is_err());
+        assert_eq!(hub.connectors.len(), 1);
+        let ports = MidiPorts { inputs: vec!["c".to_string()], input_ids: vec!["c".to_string()], ..Default::default() };
+        assert_eq!(hub.sync_ports(&ports), vec![]); // rebinding connected to the new port
+        assert!(hub.devices[0].port_available(&ports));
+    }
+
     #[test]
     #[serial]
     fn add_device() {
-        let hub_instance = Hub::get_instance();
-        let mut hub = hub_instance.lock().unwrap_or_else(|e| e.into_inner());
-        hub.destroy();
+        let mut hub = Hub::new();
         let d1: Box<dyn Device> = Box::new(MockDevice::new("1"));
         let d2: Box<dyn Device> = Box::new(MockDevice::new("1"));
         assert!(hub.add_device(d1));
@@ -286,9 +741,7 @@ mod tests {
    #[test]
    #[serial]
     fn remove_device() {
-        let hub_instance = Hub::get_instance();
-        let mut hub = hub_instance.lock().unwrap_or_else(|e| e.into_inner());
-        hub.destroy();
+        let mut hub = Hub::new();
         let d1: Box<dyn Device> = Box::new(MockDevice::new("1"));
         let d2: Box<dyn Device> = Box::new(MockDevice::new("2"));
         assert!(hub.add_device(d1));
@@ -300,18 +753,14 @@ mod tests {
     #[test]
     #[serial]
     fn connect() {
-        let hub_instance = Hub::get_instance();
-        let mut hub = hub_instance.lock().unwrap_or_else(|e| e.into_inner());
-        hub.destroy();
+        let mut hub = Hub::new();
         assert!(hub.connect("*", "*", "*", "*").is_some());
         assert!(hub.connect("*", "*", "*", "*").is_none());
     }
     #[test]
     #[serial]
     fn disconnect() {
-        let hub_instance = Hub::get_instance();
-        let mut hub = hub_instance.lock().unwrap_or_else(|e| e.into_inner());
-        hub.destroy();
+        let mut hub = Hub::new();
         hub.connect("*", "*", "*", "*");
         assert!(!hub.disconnect("*", "*", "*", "1"));
         assert!(hub.disconnect("*", "*", "*", "*"));
@@ -320,9 +769,7 @@ mod tests {
     #[test]
     #[serial]
     fn process() {
-        let hub_instance = Hub::get_instance();
-        let mut hub = hub_instance.lock().unwrap();
-        hub.destroy();
+        let mut hub = Hub::new();
         let d1: Box<dyn Device> = Box::new(MockDevice::new("1"));
         let d2: Box<dyn Device> = Box::new(MockDevice::new("2"));
         let d3: Box<dyn Device> = Box::new(MockDevice::new("3"));
@@ -333,7 +780,7 @@ mod tests {
         hub.connect("*", "1", "*", "*"); // connect non existing device to 1
         hub.connect("1", "2", "*", "*"); // connect 1 to 2
         hub.connect("2", "3", "*", "*"); // connect 2 to 3
-        hub.process(0, &bytes, "*", "1", "*", "*");
+        hub.process(42, &bytes, "*", "1", "*", "*");
         let get_device = |value: Option<Value>| serde_json::from_value(value.unwrap()).expect("Invalid JSON");
         let b1: MockDevice = get_device(hub.serialize_device("1"));
         let b2: MockDevice = get_device(hub.serialize_device("2"));
@@ -341,8 +788,157 @@ mod tests {
         assert_eq!(b1.bytes[0], 101);
         assert_eq!(b2.bytes[0], 102);
         assert_eq!(b3.bytes[0], 103);
+        assert_eq!(b3.ts, 42); // timestamps are carried through every hop
         hub.process(0, &b3.bytes, "2", "3", "*", "*");
         let bb3: MockDevice = get_device(hub.serialize_device("3"));
         assert_eq!(bb3.bytes[0], 104);
     }
-}
\ No newline at end of file
+    #[test]
+    fn connector_settings() {
+        let note = |channel, note, velocity| MidiMessage::NoteOn { channel, note, velocity };
+        let settings = ConnectorSettings::default();
+        assert_eq!(settings.apply(&note(0, 60, 100)), Some(note(0, 60, 100)));
+        assert_eq!(ConnectorSettings { muted: true, ..settings.clone() }.apply(&MidiMessage::Clock), None);
+
+        let settings = ConnectorSettings { channels: 0b10, ..ConnectorSettings::default() };
+        assert_eq!(settings.apply(&note(0, 60, 100)), None);
+        assert_eq!(settings.apply(&note(1, 60, 100)), Some(note(1, 60, 100)));
+        assert_eq!(settings.apply(&MidiMessage::Clock), Some(MidiMessage::Clock));
+
+        let settings = ConnectorSettings { types: ALL_TYPES & !(1 << 8), ..ConnectorSettings::default() };
+        assert_eq!(settings.apply(&MidiMessage::Clock), None);
+        assert_eq!(settings.apply(&MidiMessage::Unknown(vec![0xF4])), Some(MidiMessage::Unknown(vec![0xF4])));
+
+        let settings = ConnectorSettings { note_min: 48, note_max: 72, velocity_scale: 2.0, ..ConnectorSettings::default() };
+        assert_eq!(settings.apply(&note(0, 47, 100)), None);
+        assert_eq!(settings.apply(&MidiMessage::NoteOff { channel: 0, note: 73, velocity: 0 }), None);
+        assert_eq!(settings.apply(&note(0, 60, 40)), Some(note(0, 60, 80)));
+        assert_eq!(settings.apply(&note(0, 60, 100)), Some(note(0, 60, 127)));
+        assert_eq!(settings.apply(&note(0, 60, 0)), Some(note(0, 60, 0)));
+        let settings = ConnectorSettings { velocity_scale: 0.0, ..ConnectorSettings::default() };
+        assert_eq!(settings.apply(&note(0, 60, 100)), Some(note(0, 60, 1)));
+
+        assert!(ConnectorSettings { note_min: 80, note_max: 60, ..ConnectorSettings::default() }.validate().is_err());
+        assert!(ConnectorSettings { velocity_scale: f64::NAN, ..ConnectorSettings::default() }.validate().is_err());
+
+        let connector: Connector = serde_json::from_value(serde_json::json!({
+            "id": "a", "from": "a", "to": "b", "from_port": "*", "to_port": "*", "muted": true
+        })).unwrap();
+        assert!(connector.settings.muted);
+        assert_eq!(connector.settings.channels, ALL_CHANNELS);
+        let value = serde_json::to_value(&connector).unwrap();
+        assert_eq!(value["velocity_scale"], serde_json::json!(1.0));
+    }
+    #[test]
+    #[serial]
+    fn process_connector_settings() {
+        let mut hub = Hub::new();
+        hub.add_device(Box::new(MockDevice::new("1")));
+        hub.add_device(Box::new(MockDevice::new("2")));
+        let c1 = hub.connect("*", "1", "*", "*").unwrap();
+        let c2 = hub.connect("1", "2", "*", "*").unwrap();
+        let muted = ConnectorSettings { muted: true, ..ConnectorSettings::default() };
+        hub.set_connector_settings(&c2.id, muted).unwrap();
+        assert!(hub.set_connector_settings("missing", ConnectorSettings::default()).is_err());
+        hub.process(0, &vec![0x90, 60, 100], "*", "1", "*", "*");
+        let get_device = |value: Option<Value>| -> MockDevice { serde_json::from_value(value.unwrap()).expect("Invalid JSON") };
+        assert_eq!(get_device(hub.serialize_device("1")).bytes, vec![0x91, 60, 100]);
+        assert!(get_device(hub.serialize_device("2")).bytes.is_empty());
+
+        let settings = ConnectorSettings { velocity_scale: 0.5, ..ConnectorSettings::default() };
+        hub.set_connector_settings(&c1.id, settings).unwrap();
+        hub.set_connector_settings(&c2.id, ConnectorSettings::default()).unwrap();
+        hub.process(0, &vec![0x90, 60, 100], "*", "1", "*", "*");
+        assert_eq!(get_device(hub.serialize_device("1")).bytes, vec![0x91, 60, 50]);
+        assert_eq!(get_device(hub.serialize_device("2")).bytes, vec![0x92, 60, 50]);
+        assert_eq!(hub.serialize_connectors().unwrap()[0].settings.velocity_scale, 0.5);
+    }
+    #[test]
+    #[serial]
+    fn process_packets() {
+        let mut hub = Hub::new();
+        hub.add_device(Box::new(MockDevice::new("1")));
+        hub.connect("*", "1", "*", "*");
+        hub.process(0, &vec![0x90, 60, 100, 62, 100, 0x80, 60, 0], "*", "1", "*", "*");
+        let b1: MockDevice = serde_json::from_value(hub.serialize_device("1").unwrap()).expect("Invalid JSON");
+        assert_eq!(b1.count, 3); // running status note on and a note off
+        assert_eq!(b1.bytes, vec![0x81, 60, 0]);
+    }
+    #[test]
+    #[serial]
+    fn connect_cycle() {
+        let mut hub = Hub::new();
+        assert!(hub.connect("*", "1", "*", "*").is_some());
+        assert!(hub.connect("1", "2", "*", "*").is_some());
+        assert!(hub.connect("2", "3", "a", "*").is_some());
+        assert!(hub.connect("3", "1", "*", "*").is_none());
+        assert!(hub.connect("3", "2", "*", "b").is_none());
+        assert!(hub.connect("1", "1", "*", "*").is_none());
+        assert!(hub.connect("1", "3", "*", "*").is_some());
+
+        // feedback through a delay
+        hub.add_device(Box::new(MockDevice::new("delay")));
+        assert!(hub.connect("3", "delay", "*", "*").is_some());
+        assert!(hub.creates_cycle("delay", "1", "dry"));
+        assert!(hub.connect("delay", "1", "*", "*").is_some());
+        assert!(hub.connect("delay", "2", "dry", "*").is_none());
+        assert!(hub.connect("2", "delay", "*", "*").is_some()); // the loop back goes through the delay
+    }
+    #[test]
+    #[serial]
+    fn process_max_hops() {
+        let mut hub = Hub::new();
+        hub.set_max_hops(10);
+        hub.add_device(Box::new(MockDevice::new("1")));
+        hub.add_device(Box::new(MockDevice::new("2")));
+        hub.connect("*", "1", "*", "*");
+        // bypass connect() cycle detection to build a feedback loop
+        hub.connectors.push(Connector::new("1", "2", "*", "*"));
+        hub.connectors.push(Connector::new("2", "1", "*", "*"));
+        hub.process(0, &vec![100], "*", "1", "*", "*");
+        let get_device = |value: Option<Value>| serde_json::from_value(value.unwrap()).expect("Invalid JSON");
+        let b1: MockDevice = get_device(hub.serialize_device("1"));
+        let b2: MockDevice = get_device(hub.serialize_device("2"));
+        assert_eq!(b1.bytes[0], 111);
+        assert_eq!(b2.bytes[0], 110);
+        assert_eq!(b1.hops, 11);
+        assert_eq!(b2.hops, 10);
+        assert_eq!(current_hops(), 0);
+        hub.process(0, &vec![100], "*", "1", "*", "*");
+        assert_eq!(hub.dropped.len(), 1); // reported once per devices pair
+    }
+    #[test]
+    #[serial]
+    fn send_hops() {
+        Hub::call(|hub| {
+            hub.destroy();
+            hub.add_device(Box::new(MockDevice::new("1")));
+            hub.connect("*", "1", "*", "*");
+        }).expect("");
+        // sent by a device while processing, like a script send() in a loop
+        with_hops(DEFAULT_MAX_HOPS + 1, || Hub::send(0, vec![100], "*", "1", "*", "*"));
+        let value = Hub::call(|hub| hub.serialize_device("1")).expect("");
+        let b1: MockDevice = serde_json::from_value(value.unwrap()).expect("Invalid JSON");
+        assert_eq!(b1.count, 0);
+        with_hops(5, || Hub::send(0, vec![100], "*", "1", "*", "*"));
+        let value = Hub::call(|hub| hub.serialize_device("1")).expect("");
+        let b1: MockDevice = serde_json::from_value(value.unwrap()).expect("Invalid JSON");
+        assert_eq!(b1.hops, 6);
+        Hub::call(|hub| hub.destroy()).expect("");
+    }
+    #[test]
+    #[serial]
+    fn dispatch() {
+        Hub::call(|hub| {
+            hub.destroy();
+            hub.add_device(Box::new(MockDevice::new("1")));
+            hub.connect("*", "1", "*", "*");
+        }).expect("");
+        Hub::send(0, vec![100, 100, 100], "*", "1", "*", "*");
+        // control calls are queued after pending midi so the message is already processed
+        let value = Hub::call(|hub| hub.serialize_device("1")).expect("");
+        let b1: MockDevice = serde_json::from_value(value.unwrap()).expect("Invalid JSON");
+        assert_eq!(b1.bytes[0], 101);
+        Hub::call(|hub| hub.destroy()).expect("");
+    }
+}
@@ -15,6 +15,12 @@ pub mod globals;
 pub mod hub;
 pub mod app;
 pub mod utils;
+pub mod smf;
+pub mod lua_midi;
+pub mod lua_library;
+pub mod shared;
+pub mod port_watcher;
+pub mod scheduler;
 pub mod commands;
 pub mod devices {
     pub mod input;
@@ -28,6 +34,10 @@ pub mod devices {
     pub mod delay;
     pub mod script;
     pub mod trigger;
+    pub mod recorder;
+    pub mod player;
+    pub mod clock;
+    pub mod divider;
 }
 
 /**
@@ -58,7 +68,10 @@ pub struct Settings {
     pub monitor_out: Value,
     pub code_window: Value,
     pub hub_paused: bool,
+    pub hub_max_hops: u64, // 0 uses the hub default
     pub disable_grid_snap: bool,
+    pub script_library_path: String, // user lua modules folder
+    pub embed_script_library: bool, // copy library modules into saved projects
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -83,9 +96,13 @@ pub fn run() {
             }
 
             let settings = app::get_settings();
+            lua_library::set_dirs(lua_library::library_dirs(&settings.project_path, &settings.script_library_path));
             if settings.hub_paused {
                 app::set_hub_paused(true)?;
             }
+            if settings.hub_max_hops > 0 {
+                app::set_hub_max_hops(settings.hub_max_hops)?;
+            }
 
             // load previous session even if there is a project path
             // session will be allowed to save to project file path
@@ -100,6 +117,8 @@ pub fn run() {
                 commands::new_devices_project()?;
             }
 
+            port_watcher::start();
+
             // macOS window menu
             #[cfg(target_os = "macos")]
             {
@@ -155,14 +174,18 @@ pub fn run() {
             commands::get_project,
             commands::get_midi_ports,
             commands::set_hub_paused,
+            commands::set_hub_max_hops,
             commands::connect,
             commands::disconnect,
+            commands::set_connector_settings,
             commands::add_device,
             commands::set_device_data,
             commands::get_device_data,
             commands::get_device,
             commands::reconnect_device,
+            commands::rebind_device,
             commands::hub_process,
+            commands::get_clock_origin,
             commands::remove_device,
             commands::save_current_project,
             commands::set_project_path,
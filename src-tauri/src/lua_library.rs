@@ -0,0 +1,262 @@
+use std::{collections::{hash_map::DefaultHasher, BTreeMap, HashMap}, fs, hash::{Hash, Hasher}, path::{Path, PathBuf}, sync::Mutex, time::{Duration, Instant}};
+use mlua::{Function, Lua, Result, Table, Value};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::{Map, Value as JsonValue};
+
+/*
+ * Lua modules shared by scripts, loaded with require from the project and user
+ * library folders or from the copies embedded in the project file
+ */
+
+const MODULES: &str = "modules"; // registry table of loaded modules by name
+const FALLBACK: &str = "require"; // registry key of the standard require of full sandboxes
+const MAX_DEPTH: usize = 8; // nested folders scanned for modules
+pub const STAMP_TTL: Duration = Duration::from_millis(200); // between checks of a module file for changes
+pub const PROJECT_LIBRARY_DIR: &str = "lib";
+
+// dot separated module names like utils or chords.jazz, no path traversal
+static MODULE_NAME: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[\w-]+(\.[\w-]+)*$").unwrap());
+
+#[derive(Default)]
+struct Library {
+    dirs: Vec<PathBuf>,
+    embedded: BTreeMap<String, String>,
+    stamps: HashMap<String, Stamp>, // last lookup of each required module
+}
+
+#[derive(Clone)]
+struct Stamp {
+    stamp: String,
+    path: Option<PathBuf>, // none for embedded modules
+    checked: Instant,
+}
+
+static LIBRARY: Lazy<Mutex<Library>> = Lazy::new(|| Mutex::new(Library::default()));
+
+pub struct Module {
+    pub source: String,
+    pub stamp: String, // changes whenever the source changes
+}
+
+/**
+ * Library folders in lookup order, the lib folder next to the project file
+ * followed by the user library
+ */
+pub fn library_dirs(project_path: &str, user_path: &str) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    if let Some(dir) = Path::new(project_path).parent().filter(|_| !project_path.is_empty()) {
+        dirs.push(dir.join(PROJECT_LIBRARY_DIR));
+    }
+    if !user_path.is_empty() {
+        dirs.push(PathBuf::from(user_path));
+    }
+    dirs
+}
+
+pub fn set_dirs(dirs: Vec<PathBuf>) {
+    let mut library = LIBRARY.lock().unwrap();
+    library.dirs = dirs;
+    library.stamps.clear();
+}
+
+/**
+ * Replaces the modules embedded in the project, non string sources are ignored
+ */
+pub fn set_embedded(modules: &Map<String, JsonValue>) {
+    let mut library = LIBRARY.lock().unwrap();
+    library.embedded = modules.iter()
+        .filter(|(name, _)| MODULE_NAME.is_match(name))
+        .filter_map(|(name, source)| source.as_str().map(|s| (name.clone(), s.to_string())))
+        .collect();
+    library.stamps.clear();
+}
+
+/**
+ * Modules to embed in the project, folder modules replace previously embedded ones
+ */
+pub fn sources() -> Map<String, JsonValue> {
+    let library = LIBRARY.lock().unwrap();
+    let mut modules: BTreeMap<String, String> = library.embedded.clone();
+    for dir in library.dirs.iter().rev() {
+        collect_modules(dir, "", 0, &mut modules);
+    }
+    modules.into_iter().map(|(name, source)| (name, JsonValue::String(source))).collect()
+}
+
+pub fn embedded() -> Map<String, JsonValue> {
+    LIBRARY.lock().unwrap().embedded.iter()
+        .map(|(name, source)| (name.clone(), JsonValue::String(source.clone())))
+        .collect()
+}
+
+fn collect_modules(dir: &Path, prefix: &str, depth: usize, modules: &mut BTreeMap<String, String>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        let name = if prefix.is_empty() { stem.to_string() } else { format!("{}.{}", prefix, stem) };
+        if path.is_dir() {
+            if depth < MAX_DEPTH {
+                collect_modules(&path, &name, depth + 1, modules);
+            }
+        } else if path.extension().is_some_and(|e| e == "lua") && MODULE_NAME.is_match(&name) {
+            if let Ok(source) = fs::read_to_string(&path) {
+                modules.insert(name, source);
+            }
+        }
+    }
+}
+
+/**
+ * File of a module inside a library folder, chords.jazz is chords/jazz.lua
+ */
+pub fn module_path(dir: &Path, name: &str) -> Option<PathBuf> {
+    if !MODULE_NAME.is_match(name) {
+        return None;
+    }
+    let mut path = dir.to_path_buf();
+    path.extend(name.split('.'));
+    path.set_extension("lua");
+    Some(path)
+}
+
+/**
+ * Locates a module in the library folders and then in the embedded modules,
+ * files are checked again for changes once their stamp is older than STAMP_TTL
+ */
+fn stamp(name: &str) -> Option<Stamp> {
+    let dirs = {
+        let library = LIBRARY.lock().unwrap();
+        if let Some(stamp) = library.stamps.get(name).filter(|s| s.checked.elapsed() < STAMP_TTL) {
+            return Some(stamp.clone());
+        }
+        library.dirs.clone()
+    };
+    let found = dirs.iter().find_map(|dir| {
+        let path = module_path(dir, name)?;
+        let meta = fs::metadata(&path).ok().filter(|m| m.is_file())?;
+        let stamp = format!("{}:{:?}:{}", path.display(), meta.modified().ok(), meta.len());
+        Some(Stamp { stamp, path: Some(path), checked: Instant::now() })
+    });
+    let mut library = LIBRARY.lock().unwrap();
+    let stamp = found.or_else(|| library.embedded.get(name).map(|source| {
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        Stamp { stamp: format!("embedded:{:x}", hasher.finish()), path: None, checked: Instant::now() }
+    }));
+    match &stamp {
+        Some(stamp) => library.stamps.insert(name.to_string(), stamp.clone()),
+        None => library.stamps.remove(name),
+    };
+    stamp
+}
+
+/**
+ * Reads the source of a located module
+ */
+fn read(name: &str, stamp: Stamp) -> Option<Module> {
+    let source = match &stamp.path {
+        Some(path) => fs::read_to_string(path).ok(),
+        None => LIBRARY.lock().unwrap().embedded.get(name).cloned(),
+    };
+    if source.is_none() { // changed since it was located
+        LIBRARY.lock().unwrap().stamps.remove(name);
+    }
+    source.map(|source| Module { source, stamp: stamp.stamp })
+}
+
+/**
+ * Creates the require function of a script state, loaded modules are cached
+ * until their source changes and run with the script globals so the sandbox applies
+ */
+pub fn create_require(lua: &Lua) -> Result<Function> {
+    if let Some(require) = lua.globals().get::<Option<Function>>("require")? {
+        lua.set_named_registry_value(FALLBACK, require)?;
+    }
+    lua.set_named_registry_value(MODULES, lua.create_table()?)?;
+    lua.create_function(|lua, name: String| {
+        let not_found = || match lua.named_registry_value::<Option<Function>>(FALLBACK)? {
+            Some(require) => require.call::<Value>(name.as_str()),
+            None => Err(mlua::Error::RuntimeError(format!("Module {} not found in the script library", name)))
+        };
+        let Some(stamp) = stamp(&name) else {
+            return not_found();
+        };
+        let cache: Table = lua.named_registry_value(MODULES)?;
+        if let Some(entry) = cache.get::<Option<Table>>(name.as_str())? {
+            if entry.get::<bool>("loading")? {
+                return Err(mlua::Error::RuntimeError(format!("Circular require of module {}", name)));
+            }
+            if entry.get::<String>("stamp")? == stamp.stamp { // unchanged, no need to read it
+                return entry.get::<Value>("value");
+            }
+        }
+        let Some(module) = read(&name, stamp) else {
+            return not_found();
+        };
+        let entry = lua.create_table()?;
+        entry.set("loading", true)?;
+        cache.set(name.as_str(), &entry)?;
+        let result = lua.load(&module.source)
+            .set_name(format!("={}", name))
+            .call::<Value>(name.as_str());
+        let value = match result {
+            Ok(Value::Nil) => Value::Boolean(true),
+            Ok(value) => value,
+            Err(err) => {
+                cache.set(name.as_str(), Value::Nil)?;
+                return Err(err);
+            }
+        };
+        entry.set("loading", false)?;
+        entry.set("stamp", module.stamp)?;
+        entry.set("value", &value)?;
+        Ok(value)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+    use super::*;
+
+    #[test]
+    fn module_paths () {
+        let dir = Path::new("lib");
+        assert_eq!(module_path(dir, "utils"), Some(dir.join("utils.lua")));
+        assert_eq!(module_path(dir, "chords.jazz"), Some(dir.join("chords").join("jazz.lua")));
+        assert_eq!(module_path(dir, "../secrets"), None);
+        assert_eq!(module_path(dir, "/etc/passwd"), None);
+        assert_eq!(module_path(dir, "a..b"), None);
+        assert_eq!(module_path(dir, ""), None);
+        assert_eq!(library_dirs("", ""), Vec::<PathBuf>::new());
+        assert_eq!(library_dirs("/songs/live.json", "/home/lib"), vec![
+            PathBuf::from("/songs/lib"), PathBuf::from("/home/lib")
+        ]);
+    }
+
+    #[test]
+    #[serial]
+    fn stamps () {
+        let dir = std::env::temp_dir().join("mididash_stamps_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("utils.lua"), "return 1").unwrap();
+        set_dirs(vec![dir.clone()]);
+        let first = stamp("utils").unwrap();
+        assert_eq!(first.path, Some(dir.join("utils.lua")));
+        fs::write(dir.join("utils.lua"), "return 12").unwrap();
+        assert_eq!(stamp("utils").unwrap().stamp, first.stamp); // not checked again yet
+        std::thread::sleep(STAMP_TTL);
+        let second = stamp("utils").unwrap();
+        assert_ne!(second.stamp, first.stamp);
+        assert_eq!(read("utils", second).unwrap().source, "return 12");
+        assert!(stamp("missing").is_none());
+        set_dirs(vec![]);
+    }
+}
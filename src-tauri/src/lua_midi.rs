@@ -0,0 +1,243 @@
+use mlua::{Lua, Result, Table};
+use crate::utils::{MidiMessage, MIDI_AFTERTOUCH, MIDI_CC, MIDI_CHANNEL_AT, MIDI_EXT_ACTIVE_SNS, MIDI_EXT_CLOCK,
+    MIDI_EXT_CONTINUE, MIDI_EXT_MTC, MIDI_EXT_POSITION, MIDI_EXT_RESET, MIDI_EXT_SELECT, MIDI_EXT_START,
+    MIDI_EXT_STOP, MIDI_EXT_SYSEX, MIDI_EXT_TUNE, MIDI_NOTE_OFF, MIDI_NOTE_ON, MIDI_PITCH, MIDI_PROG_CHNG
+};
+
+/*
+ * midi module exposed to scripts, channels are 1 to 16 like the splitter ports
+ */
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+pub const SCALES: [(&str, &[u8]); 13] = [
+    ("major", &[0, 2, 4, 5, 7, 9, 11]),
+    ("minor", &[0, 2, 3, 5, 7, 8, 10]),
+    ("harmonic_minor", &[0, 2, 3, 5, 7, 8, 11]),
+    ("melodic_minor", &[0, 2, 3, 5, 7, 9, 11]),
+    ("dorian", &[0, 2, 3, 5, 7, 9, 10]),
+    ("phrygian", &[0, 1, 3, 5, 7, 8, 10]),
+    ("lydian", &[0, 2, 4, 6, 7, 9, 11]),
+    ("mixolydian", &[0, 2, 4, 5, 7, 9, 10]),
+    ("locrian", &[0, 1, 3, 5, 6, 8, 10]),
+    ("pentatonic_major", &[0, 2, 4, 7, 9]),
+    ("pentatonic_minor", &[0, 3, 5, 7, 10]),
+    ("blues", &[0, 3, 5, 6, 7, 10]),
+    ("chromatic", &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]),
+];
+
+pub const CHORDS: [(&str, &[u8]); 12] = [
+    ("major", &[0, 4, 7]),
+    ("minor", &[0, 3, 7]),
+    ("dim", &[0, 3, 6]),
+    ("aug", &[0, 4, 8]),
+    ("sus2", &[0, 2, 7]),
+    ("sus4", &[0, 5, 7]),
+    ("maj7", &[0, 4, 7, 11]),
+    ("min7", &[0, 3, 7, 10]),
+    ("dom7", &[0, 4, 7, 10]),
+    ("dim7", &[0, 3, 6, 9]),
+    ("min7b5", &[0, 3, 6, 10]),
+    ("add9", &[0, 4, 7, 14]),
+];
+
+/**
+ * Note name with octave where 60 is C4
+ */
+pub fn note_name(note: u8) -> String {
+    format!("{}{}", NOTE_NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+/**
+ * Parses names like C4, F#2, Bb-1 or c#3 into a note number
+ */
+pub fn note_number(name: &str) -> Option<u8> {
+    let mut chars = name.trim().chars().peekable();
+    let letter = chars.next()?.to_ascii_uppercase();
+    let mut pitch = NOTE_NAMES.iter().position(|n| n.len() == 1 && n.starts_with(letter))? as i32;
+    while let Some(&c) = chars.peek() {
+        match c {
+            '#' => pitch += 1,
+            'b' => pitch -= 1,
+            _ => break
+        }
+        chars.next();
+    }
+    let octave: i32 = chars.collect::<String>().parse().ok()?;
+    u8::try_from((octave + 1) * 12 + pitch).ok().filter(|n| *n < 128)
+}
+
+fn channel(ch: u8) -> Result<u8> {
+    if (1..=16).contains(&ch) {
+        Ok(ch - 1)
+    } else {
+        Err(mlua::Error::RuntimeError(format!("Invalid channel {}, expected 1 to 16", ch)))
+    }
+}
+
+fn data(value: u8, name: &str) -> Result<u8> {
+    if value < 128 {
+        Ok(value)
+    } else {
+        Err(mlua::Error::RuntimeError(format!("Invalid {} {}, expected 0 to 127", name, value)))
+    }
+}
+
+fn intervals<'a>(list: &'a [(&str, &'a [u8])], name: &str, kind: &str) -> Result<&'a [u8]> {
+    list.iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, intervals)| *intervals)
+        .ok_or_else(|| mlua::Error::RuntimeError(format!("Unknown {} {}", kind, name)))
+}
+
+/**
+ * Table with the message type, 1 based channel, raw bytes and named data fields
+ */
+pub fn message_table(lua: &Lua, msg: &MidiMessage) -> Result<Table> {
+    let table = lua.create_table()?;
+    table.set("type", msg.name())?;
+    table.set("channel", msg.channel().map(|c| c + 1))?;
+    table.set("bytes", lua.create_sequence_from(msg.to_bytes())?)?;
+    match *msg {
+        MidiMessage::NoteOff { note, velocity, .. } | MidiMessage::NoteOn { note, velocity, .. } => {
+            table.set("note", note)?;
+            table.set("velocity", velocity)?;
+        },
+        MidiMessage::Aftertouch { note, pressure, .. } => {
+            table.set("note", note)?;
+            table.set("pressure", pressure)?;
+        },
+        MidiMessage::ControlChange { controller, value, .. } => {
+            table.set("controller", controller)?;
+            table.set("value", value)?;
+        },
+        MidiMessage::ProgramChange { program, .. } => table.set("program", program)?,
+        MidiMessage::ChannelAftertouch { pressure, .. } => table.set("pressure", pressure)?,
+        MidiMessage::PitchBend { value, .. } | MidiMessage::SongPosition(value) => table.set("value", value)?,
+        MidiMessage::TimeCode(value) | MidiMessage::SongSelect(value) => table.set("value", value)?,
+        _ => {}
+    }
+    Ok(table)
+}
+
+pub fn create_module(lua: &Lua) -> Result<Table> {
+    let midi = lua.create_table()?;
+
+    for (key, name) in [
+        ("NOTE_OFF", MIDI_NOTE_OFF), ("NOTE_ON", MIDI_NOTE_ON), ("AFTERTOUCH", MIDI_AFTERTOUCH),
+        ("CC", MIDI_CC), ("PROGRAM", MIDI_PROG_CHNG), ("CHANNEL_AT", MIDI_CHANNEL_AT), ("PITCH", MIDI_PITCH),
+        ("SYSEX", MIDI_EXT_SYSEX), ("MTC", MIDI_EXT_MTC), ("POSITION", MIDI_EXT_POSITION), ("SELECT", MIDI_EXT_SELECT),
+        ("TUNE", MIDI_EXT_TUNE), ("CLOCK", MIDI_EXT_CLOCK), ("START", MIDI_EXT_START), ("CONTINUE", MIDI_EXT_CONTINUE),
+        ("STOP", MIDI_EXT_STOP), ("ACTIVE_SENSING", MIDI_EXT_ACTIVE_SNS), ("RESET", MIDI_EXT_RESET),
+    ] {
+        midi.set(key, name)?;
+    }
+
+    // constructors return byte arrays ready for send or res
+    midi.set("note_on", lua.create_function(|lua, (ch, note, velocity): (u8, u8, u8)| {
+        lua.create_sequence_from(MidiMessage::NoteOn { channel: channel(ch)?, note: data(note, "note")?, velocity: data(velocity, "velocity")? }.to_bytes())
+    })?)?;
+    midi.set("note_off", lua.create_function(|lua, (ch, note, velocity): (u8, u8, Option<u8>)| {
+        lua.create_sequence_from(MidiMessage::NoteOff { channel: channel(ch)?, note: data(note, "note")?, velocity: data(velocity.unwrap_or(0), "velocity")? }.to_bytes())
+    })?)?;
+    midi.set("aftertouch", lua.create_function(|lua, (ch, note, pressure): (u8, u8, u8)| {
+        lua.create_sequence_from(MidiMessage::Aftertouch { channel: channel(ch)?, note: data(note, "note")?, pressure: data(pressure, "pressure")? }.to_bytes())
+    })?)?;
+    midi.set("cc", lua.create_function(|lua, (ch, controller, value): (u8, u8, u8)| {
+        lua.create_sequence_from(MidiMessage::ControlChange { channel: channel(ch)?, controller: data(controller, "controller")?, value: data(value, "value")? }.to_bytes())
+    })?)?;
+    midi.set("program", lua.create_function(|lua, (ch, program): (u8, u8)| {
+        lua.create_sequence_from(MidiMessage::ProgramChange { channel: channel(ch)?, program: data(program, "program")? }.to_bytes())
+    })?)?;
+    midi.set("channel_pressure", lua.create_function(|lua, (ch, pressure): (u8, u8)| {
+        lua.create_sequence_from(MidiMessage::ChannelAftertouch { channel: channel(ch)?, pressure: data(pressure, "pressure")? }.to_bytes())
+    })?)?;
+    midi.set("pitch_bend", lua.create_function(|lua, (ch, value): (u8, u16)| { // 0 to 16383, 8192 is center
+        lua.create_sequence_from(MidiMessage::PitchBend { channel: channel(ch)?, value: value.min(0x3FFF) }.to_bytes())
+    })?)?;
+    midi.set("song_position", lua.create_function(|lua, value: u16| {
+        lua.create_sequence_from(MidiMessage::SongPosition(value.min(0x3FFF)).to_bytes())
+    })?)?;
+    for (key, msg) in [
+        ("clock", MidiMessage::Clock), ("start", MidiMessage::Start),
+        ("continue", MidiMessage::Continue), ("stop", MidiMessage::Stop),
+    ] {
+        midi.set(key, lua.create_function(move |lua, ()| lua.create_sequence_from(msg.to_bytes()))?)?;
+    }
+
+    midi.set("parse", lua.create_function(|lua, bytes: Vec<u8>| {
+        message_table(lua, &MidiMessage::from_bytes(&bytes))
+    })?)?;
+    midi.set("note_name", lua.create_function(|_, note: u8| Ok(note_name(note.min(127))))?)?;
+    midi.set("note_number", lua.create_function(|_, name: String| Ok(note_number(&name)))?)?;
+
+    let scales = lua.create_table()?;
+    for (name, intervals) in SCALES {
+        scales.set(name, lua.create_sequence_from(intervals.iter().copied())?)?;
+    }
+    midi.set("scales", scales)?;
+    let chords = lua.create_table()?;
+    for (name, intervals) in CHORDS {
+        chords.set(name, lua.create_sequence_from(intervals.iter().copied())?)?;
+    }
+    midi.set("chords", chords)?;
+
+    // notes of a chord built on root, notes above 127 are left out
+    midi.set("chord", lua.create_function(|lua, (root, name): (u8, String)| {
+        let notes = intervals(&CHORDS, &name, "chord")?.iter()
+            .map(|i| root as u16 + *i as u16)
+            .filter(|n| *n < 128);
+        lua.create_sequence_from(notes)
+    })?)?;
+    // nearest note of the scale with the key of root, ties resolve downwards
+    midi.set("quantize", lua.create_function(|_, (note, root, name): (u8, u8, String)| {
+        let scale = intervals(&SCALES, &name, "scale")?;
+        let in_scale = |n: i32| scale.contains(&((n - root as i32).rem_euclid(12) as u8));
+        let note = note.min(127) as i32;
+        let nearest = (0..12)
+            .flat_map(|d| [note - d, note + d])
+            .find(|n| (0..128).contains(n) && in_scale(*n))
+            .unwrap_or(note);
+        Ok(nearest)
+    })?)?;
+
+    Ok(midi)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_names () {
+        assert_eq!(note_name(60), "C4");
+        assert_eq!(note_name(0), "C-1");
+        assert_eq!(note_name(127), "G9");
+        assert_eq!(note_number("C4"), Some(60));
+        assert_eq!(note_number("c#4"), Some(61));
+        assert_eq!(note_number("Db4"), Some(61));
+        assert_eq!(note_number("C-1"), Some(0));
+        assert_eq!(note_number("Cb-1"), None);
+        assert_eq!(note_number("G#9"), None);
+        assert_eq!(note_number("H2"), None);
+        for note in 0..128 {
+            assert_eq!(note_number(&note_name(note)), Some(note));
+        }
+    }
+
+    #[test]
+    fn data_bytes () {
+        let lua = Lua::new();
+        lua.globals().set("midi", create_module(&lua).unwrap()).unwrap();
+        let bytes: Vec<u8> = lua.load("return midi.note_on(1, 127, 100)").eval().unwrap();
+        assert_eq!(bytes, vec![0x90, 127, 100]);
+        for call in [
+            "midi.note_on(1, 200, 100)", "midi.note_on(1, 60, 128)", "midi.note_off(1, 60, 255)",
+            "midi.aftertouch(1, 128, 1)", "midi.cc(1, 128, 0)", "midi.cc(1, 1, 200)",
+            "midi.program(1, 128)", "midi.channel_pressure(1, 128)",
+        ] {
+            let err = lua.load(call).exec().unwrap_err().to_string();
+            assert!(err.contains("expected 0 to 127"), "{}: {}", call, err);
+        }
+    }
+}
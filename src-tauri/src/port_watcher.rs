@@ -0,0 +1,38 @@
+use std::{sync::atomic::{AtomicBool, Ordering}, thread, time::Duration};
+use serde_json::json;
+use crate::{app, globals::EVT_DEVICE_STATUS, hub::Hub, utils};
+
+/*
+ * Background watcher that keeps inputs and outputs in sync with the available midi ports
+ */
+
+const POLL_INTERVAL: Duration = Duration::from_millis(1000);
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/**
+ * Starts polling the midi ports, only the first call spawns the watcher thread
+ */
+pub fn start() {
+    if STARTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    thread::spawn(|| loop {
+        thread::sleep(POLL_INTERVAL);
+        if let Err(err) = poll() {
+            eprintln!("Port watcher failed {}", err);
+        }
+    });
+}
+
+/**
+ * Reconnects or closes devices for the current ports and emits their new state
+ */
+pub fn poll() -> Result<(), String> {
+    let ports = utils::get_valid_midi_ports().map_err(|e| e.to_string())?;
+    let changes = Hub::call(move |hub| hub.sync_ports(&ports))?;
+    for (id, online) in changes {
+        app::emit(EVT_DEVICE_STATUS, json!({ "id": id, "online": online }));
+    }
+    Ok(())
+}
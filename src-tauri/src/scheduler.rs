@@ -0,0 +1,230 @@
+use std::{cmp::{Ordering, Reverse}, collections::BinaryHeap, sync::{Condvar, Mutex}, thread, time::Duration};
+use once_cell::sync::Lazy;
+use crate::{hub::{self, Hub}, utils};
+
+/*
+ * Sends messages at future hub timestamps from a single timer thread,
+ * devices schedule their output here instead of sleeping on their own tasks
+ */
+
+const SPIN_THRESHOLD: Duration = Duration::from_micros(1500); // busy wait the last moments before a message
+
+pub struct Scheduled {
+    pub ts: u64, // hub clock micros, also the timestamp of the sent message
+    pub bytes: Vec<u8>,
+    pub from: String,
+    pub from_port: String,
+    pub every: u64, // micros between repeats, 0 sends once
+    pub hops: usize, // hops of the message that scheduled this one, see Hub::send
+    seq: u64, // keeps messages with the same timestamp in scheduling order
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.ts, self.seq) == (other.ts, other.seq)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.ts, self.seq).cmp(&(other.ts, other.seq))
+    }
+}
+
+/**
+ * Pending messages ordered by timestamp
+ */
+#[derive(Default)]
+pub struct Queue {
+    heap: BinaryHeap<Reverse<Scheduled>>,
+    seq: u64,
+}
+
+impl Queue {
+    pub fn push(&mut self, ts: u64, bytes: Vec<u8>, from: &str, from_port: &str, hops: usize) {
+        self.push_every(ts, 0, bytes, from, from_port, hops);
+    }
+
+    pub fn push_every(&mut self, ts: u64, every: u64, bytes: Vec<u8>, from: &str, from_port: &str, hops: usize) {
+        self.seq += 1;
+        self.heap.push(Reverse(Scheduled {
+            ts,
+            bytes,
+            from: from.to_string(),
+            from_port: from_port.to_string(),
+            every,
+            hops,
+            seq: self.seq,
+        }));
+    }
+
+    pub fn next_ts(&self) -> Option<u64> {
+        self.heap.peek().map(|Reverse(s)| s.ts)
+    }
+
+    /**
+     * Removes the messages due at the given time, earliest first,
+     * repeating messages are queued again at their next time after now
+     */
+    pub fn pop_due(&mut self, now: u64) -> Vec<Scheduled> {
+        let mut due = vec![];
+        while self.next_ts().is_some_and(|ts| ts <= now) {
+            if let Some(Reverse(scheduled)) = self.heap.pop() {
+                if scheduled.every > 0 {
+                    // missed repeats are skipped instead of sent in a burst
+                    let next = scheduled.ts + scheduled.every * ((now - scheduled.ts) / scheduled.every + 1);
+                    self.push_every(next, scheduled.every, scheduled.bytes.clone(), &scheduled.from, &scheduled.from_port, scheduled.hops);
+                }
+                due.push(scheduled);
+            }
+        }
+        due
+    }
+
+    pub fn cancel(&mut self, from: &str) {
+        self.heap.retain(|Reverse(s)| s.from != from);
+    }
+
+    pub fn pending(&self, from: &str) -> usize {
+        self.heap.iter().filter(|Reverse(s)| s.from == from).count()
+    }
+}
+
+struct Scheduler {
+    queue: Mutex<Queue>,
+    wake: Condvar,
+}
+
+// the timer thread is spawned on first use
+static SCHEDULER: Lazy<Scheduler> = Lazy::new(|| {
+    thread::Builder::new()
+        .name("scheduler".into())
+        .spawn(|| run(&SCHEDULER))
+        .expect("Failed to spawn scheduler thread");
+    Scheduler {
+        queue: Mutex::new(Queue::default()),
+        wake: Condvar::new(),
+    }
+});
+
+/**
+ * Waits on the condvar until close to the next message and spins the remaining time,
+ * scheduling an earlier message wakes the thread
+ */
+fn run(scheduler: &Scheduler) {
+    let mut queue = scheduler.queue.lock().unwrap();
+    loop {
+        let Some(next) = queue.next_ts() else {
+            queue = scheduler.wake.wait(queue).unwrap();
+            continue;
+        };
+        let now = utils::now_micros();
+        if next <= now {
+            let due = queue.pop_due(now);
+            drop(queue);
+            for s in due {
+                Hub::send_hops(s.ts, s.bytes, &s.from, "*", &s.from_port, "*", s.hops);
+            }
+            queue = scheduler.queue.lock().unwrap();
+            continue;
+        }
+        let remaining = Duration::from_micros(next - now);
+        if remaining > SPIN_THRESHOLD {
+            queue = scheduler.wake.wait_timeout(queue, remaining - SPIN_THRESHOLD).unwrap().0;
+        } else {
+            drop(queue);
+            std::hint::spin_loop();
+            queue = scheduler.queue.lock().unwrap();
+        }
+    }
+}
+
+/**
+ * Sends a message from a device port at a hub timestamp, past timestamps are sent right away,
+ * the message keeps the hops of the one being processed
+ */
+pub fn schedule(ts: u64, bytes: Vec<u8>, from: &str, from_port: &str) {
+    SCHEDULER.queue.lock().unwrap().push(ts, bytes, from, from_port, hub::current_hops());
+    SCHEDULER.wake.notify_one();
+}
+
+/**
+ * Sends a message from a device port every interval starting at a hub timestamp,
+ * until the device messages are cancelled
+ */
+pub fn schedule_every(ts: u64, every: u64, bytes: Vec<u8>, from: &str, from_port: &str) {
+    SCHEDULER.queue.lock().unwrap().push_every(ts, every.max(1), bytes, from, from_port, hub::current_hops());
+    SCHEDULER.wake.notify_one();
+}
+
+/**
+ * Drops the pending messages of a device
+ */
+pub fn cancel(from: &str) {
+    SCHEDULER.queue.lock().unwrap().cancel(from);
+}
+
+pub fn pending(from: &str) -> usize {
+    SCHEDULER.queue.lock().unwrap().pending(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue () {
+        let mut queue = Queue::default();
+        queue.push(300, vec![3], "a", "*", 0);
+        queue.push(100, vec![1], "a", "*", 0);
+        queue.push(200, vec![2], "b", "out", 0);
+        queue.push(100, vec![4], "b", "*", 0);
+        assert_eq!(queue.next_ts(), Some(100));
+        let due: Vec<Vec<u8>> = queue.pop_due(200).into_iter().map(|s| s.bytes).collect();
+        assert_eq!(due, vec![vec![1], vec![4], vec![2]]); // same timestamps keep their order
+        queue.push(250, vec![5], "b", "*", 0);
+        queue.cancel("a");
+        assert_eq!(queue.pending("a"), 0);
+        assert_eq!(queue.pending("b"), 1);
+        assert!(queue.pop_due(249).is_empty());
+        assert_eq!(queue.pop_due(u64::MAX).len(), 1);
+        assert_eq!(queue.next_ts(), None);
+
+        queue.push_every(100, 50, vec![6], "c", "*", 3);
+        assert_eq!(queue.pop_due(100).len(), 1);
+        assert_eq!(queue.next_ts(), Some(150));
+        let due = queue.pop_due(320);
+        assert_eq!(due.len(), 1); // late, the missed repeats are skipped
+        assert_eq!(due[0].hops, 3); // repeats keep the hops of the scheduling message
+        assert_eq!(queue.next_ts(), Some(350));
+        queue.cancel("c");
+        assert_eq!(queue.next_ts(), None);
+    }
+
+    #[test]
+    fn schedule_and_cancel () {
+        let ts = utils::now_micros() + 60_000_000;
+        schedule(ts, vec![0xF8], "scheduler-test", "*");
+        schedule(ts + 1, vec![0xF8], "scheduler-test", "*");
+        assert_eq!(pending("scheduler-test"), 2);
+        cancel("scheduler-test");
+        assert_eq!(pending("scheduler-test"), 0);
+
+        schedule(0, vec![0xF8], "scheduler-test", "*"); // already due
+        for _ in 0..100 {
+            if pending("scheduler-test") == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(pending("scheduler-test"), 0);
+    }
+}
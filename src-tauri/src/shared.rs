@@ -0,0 +1,94 @@
+use std::{collections::HashMap, sync::Mutex};
+use once_cell::sync::Lazy;
+use serde_json::{json, Map, Value};
+use crate::{app, globals::EVT_SHARED_CHANGE};
+
+/*
+ * Project wide key/value store shared between devices, saved with the project
+ */
+
+pub type Watcher = Box<dyn Fn(&str, &Value) + Send>;
+
+static VALUES: Lazy<Mutex<Map<String, Value>>> = Lazy::new(|| Mutex::new(Map::new()));
+static WATCHERS: Lazy<Mutex<HashMap<String, Watcher>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn get(key: &str) -> Option<Value> {
+    VALUES.lock().unwrap().get(key).cloned()
+}
+
+/**
+ * Sets or removes with null a value, watchers other than the origin device are notified
+ */
+pub fn set(key: &str, value: Value, origin: &str) {
+    {
+        let mut values = VALUES.lock().unwrap();
+        let previous = if value.is_null() {
+            values.remove(key)
+        } else {
+            values.insert(key.to_string(), value.clone())
+        };
+        if previous.as_ref() == Some(&value) || (previous.is_none() && value.is_null()) {
+            return;
+        }
+    }
+    for (id, watcher) in WATCHERS.lock().unwrap().iter() {
+        if id != origin {
+            watcher(key, &value);
+        }
+    }
+    app::emit(EVT_SHARED_CHANGE, json!({ "key": key, "value": value, "origin": origin }));
+}
+
+pub fn keys() -> Vec<String> {
+    VALUES.lock().unwrap().keys().cloned().collect()
+}
+
+pub fn snapshot() -> Map<String, Value> {
+    VALUES.lock().unwrap().clone()
+}
+
+/**
+ * Replaces all values without notifying, used when projects are loaded or created
+ */
+pub fn replace(values: Map<String, Value>) {
+    *VALUES.lock().unwrap() = values;
+}
+
+/**
+ * Registers the change watcher of a device, watchers must not block
+ */
+pub fn watch(id: &str, watcher: Watcher) {
+    WATCHERS.lock().unwrap().insert(id.to_string(), watcher);
+}
+
+pub fn unwatch(id: &str) {
+    WATCHERS.lock().unwrap().remove(id);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+    use super::*;
+
+    #[test]
+    fn set_and_watch () {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        watch("shared-test-b", Box::new(move |key, _| {
+            if key == "shared-test" {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        }));
+        set("shared-test", json!(1), "shared-test-a");
+        set("shared-test", json!(1), "shared-test-a"); // unchanged
+        set("shared-test", json!(2), "shared-test-b"); // own change
+        assert_eq!(get("shared-test"), Some(json!(2)));
+        assert!(keys().contains(&"shared-test".to_string()));
+        set("shared-test", Value::Null, "shared-test-a");
+        assert_eq!(get("shared-test"), None);
+        unwatch("shared-test-b");
+        set("shared-test", json!(3), "shared-test-a");
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        set("shared-test", Value::Null, "shared-test-a");
+    }
+}
@@ -0,0 +1,434 @@
+/*
+ * Standard MIDI File encoding and decoding
+ */
+
+pub const DEFAULT_PPQ: u16 = 480;
+pub const DEFAULT_BPM: f64 = 120.0;
+
+pub struct SmfEvent {
+    pub tick: u64,
+    pub bytes: Vec<u8>, // midi message as sent on the wire
+}
+
+#[derive(Default)]
+pub struct SmfTrack {
+    pub name: String,
+    pub events: Vec<SmfEvent>,
+}
+
+/**
+ * Decoded midi file, tempo changes from all tracks are merged into a single map
+ */
+pub struct Smf {
+    pub format: u16,
+    pub division: u16, // ticks per quarter note, or SMPTE frames and ticks per frame when the high bit is set
+    pub tracks: Vec<SmfTrack>,
+    pub tempos: Vec<(u64, u32)>, // tick, microseconds per quarter note
+}
+
+/**
+ * Event of a decoded file with its absolute time
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimedEvent {
+    pub tick: u64,
+    pub micros: u64,
+    pub track: usize,
+    pub bytes: Vec<u8>,
+}
+
+pub fn bpm_to_tempo(bpm: f64) -> u32 {
+    (60_000_000.0 / bpm.max(1.0)).round() as u32
+}
+
+pub fn micros_to_ticks(micros: u64, ppq: u16, tempo: u32) -> u64 {
+    (micros as u128 * ppq as u128 / tempo.max(1) as u128) as u64
+}
+
+pub fn micros_from_ticks(ticks: u64, ppq: u16, tempo: u32) -> u64 {
+    (ticks as u128 * tempo as u128 / ppq.max(1) as u128) as u64
+}
+
+fn write_vlq(out: &mut Vec<u8>, value: u64) {
+    let mut value = value.min(0x0FFF_FFFF); // max length allowed by the spec
+    let mut buf = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        buf.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.reverse();
+    out.extend(buf);
+}
+
+fn read_vlq(data: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let mut value: u64 = 0;
+    for _ in 0..4 {
+        let byte = *data.get(*pos).ok_or("Unexpected end of track")?;
+        *pos += 1;
+        value = (value << 7) | (byte & 0x7F) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("Invalid variable length quantity".to_string())
+}
+
+fn read_bytes<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], String> {
+    let bytes = data.get(*pos..*pos + len).ok_or("Unexpected end of track")?;
+    *pos += len;
+    Ok(bytes)
+}
+
+/**
+ * Encodes a message as a track event, realtime and common messages are written
+ * as escaped events since their status bytes conflict with meta events
+ */
+fn write_message(out: &mut Vec<u8>, bytes: &[u8]) -> bool {
+    match bytes.first() {
+        Some(0x80..=0xEF) => out.extend_from_slice(bytes),
+        Some(0xF0) => {
+            out.push(0xF0);
+            write_vlq(out, bytes.len() as u64 - 1);
+            out.extend_from_slice(&bytes[1..]);
+        },
+        Some(0xF1..=0xFC) => {
+            out.push(0xF7);
+            write_vlq(out, bytes.len() as u64);
+            out.extend_from_slice(bytes);
+        },
+        _ => return false // active sensing, reset and unknown messages are not stored
+    }
+    true
+}
+
+fn write_meta(out: &mut Vec<u8>, kind: u8, data: &[u8]) {
+    out.push(0xFF);
+    out.push(kind);
+    write_vlq(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+fn write_track(out: &mut Vec<u8>, track: &SmfTrack, tempo: Option<u32>) {
+    let mut data = Vec::new();
+    if !track.name.is_empty() {
+        write_vlq(&mut data, 0);
+        write_meta(&mut data, 0x03, track.name.as_bytes());
+    }
+    if let Some(tempo) = tempo {
+        write_vlq(&mut data, 0);
+        write_meta(&mut data, 0x51, &tempo.to_be_bytes()[1..]);
+    }
+    let mut last_tick = 0;
+    for event in &track.events {
+        let mut message = Vec::new();
+        if write_message(&mut message, &event.bytes) {
+            write_vlq(&mut data, event.tick.saturating_sub(last_tick));
+            data.extend(message);
+            last_tick = event.tick.max(last_tick);
+        }
+    }
+    write_vlq(&mut data, 0);
+    write_meta(&mut data, 0x2F, &[]);
+
+    out.extend_from_slice(b"MTrk");
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend(data);
+}
+
+/**
+ * Writes a format 0 file with all tracks merged into one, or a format 1 file
+ * with the tempo stored in a conductor track before the others
+ */
+pub fn write(format: u16, tracks: Vec<SmfTrack>, ppq: u16, tempo: u32) -> Vec<u8> {
+    let tracks = if format == 0 {
+        let mut events: Vec<SmfEvent> = tracks.into_iter().flat_map(|t| t.events).collect();
+        events.sort_by_key(|e| e.tick);
+        vec![SmfTrack { name: "".to_string(), events }]
+    } else {
+        tracks
+    };
+    let ntracks = if format == 0 { 1 } else { tracks.len() + 1 };
+    let mut out = Vec::new();
+    out.extend_from_slice(b"MThd");
+    out.extend_from_slice(&6u32.to_be_bytes());
+    out.extend_from_slice(&format.min(1).to_be_bytes());
+    out.extend_from_slice(&(ntracks as u16).to_be_bytes());
+    out.extend_from_slice(&ppq.to_be_bytes());
+    if format == 0 {
+        write_track(&mut out, &tracks[0], Some(tempo));
+    } else {
+        write_track(&mut out, &SmfTrack::default(), Some(tempo));
+        for track in &tracks {
+            write_track(&mut out, track, None);
+        }
+    }
+    out
+}
+
+fn read_track(data: &[u8], tempos: &mut Vec<(u64, u32)>) -> Result<SmfTrack, String> {
+    let mut track = SmfTrack::default();
+    let mut pos = 0;
+    let mut tick = 0;
+    let mut running_status: Option<u8> = None;
+    while pos < data.len() {
+        tick += read_vlq(data, &mut pos)?;
+        let mut status = *data.get(pos).ok_or("Unexpected end of track")?;
+        if status < 0x80 {
+            status = running_status.ok_or("Missing running status")?;
+        } else {
+            pos += 1;
+        }
+        match status {
+            0xFF => {
+                let kind = *data.get(pos).ok_or("Unexpected end of track")?;
+                pos += 1;
+                let len = read_vlq(data, &mut pos)? as usize;
+                let meta = read_bytes(data, &mut pos, len)?;
+                match kind {
+                    0x03 if track.name.is_empty() => track.name = String::from_utf8_lossy(meta).to_string(),
+                    0x51 if len == 3 => tempos.push((tick, u32::from_be_bytes([0, meta[0], meta[1], meta[2]]))),
+                    0x2F => break,
+                    _ => {}
+                }
+                running_status = None;
+            },
+            0xF0 | 0xF7 => {
+                let len = read_vlq(data, &mut pos)? as usize;
+                let mut bytes = if status == 0xF0 { vec![0xF0] } else { vec![] }; // 0xF7 escapes raw bytes
+                bytes.extend_from_slice(read_bytes(data, &mut pos, len)?);
+                track.events.push(SmfEvent { tick, bytes });
+                running_status = None;
+            },
+            0x80..=0xEF => {
+                let len = if (0xC0..=0xDF).contains(&status) { 1 } else { 2 };
+                let mut bytes = vec![status];
+                bytes.extend_from_slice(read_bytes(data, &mut pos, len)?);
+                track.events.push(SmfEvent { tick, bytes });
+                running_status = Some(status);
+            },
+            _ => Err(format!("Invalid status byte {:#04x}", status))?
+        }
+    }
+    Ok(track)
+}
+
+pub fn read(data: &[u8]) -> Result<Smf, String> {
+    let mut pos = 0;
+    let mut smf = Smf { format: 0, division: DEFAULT_PPQ, tracks: vec![], tempos: vec![] };
+    let mut has_header = false;
+    while pos + 8 <= data.len() {
+        let kind = &data[pos..pos + 4];
+        let len = u32::from_be_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]) as usize;
+        pos += 8;
+        let chunk = data.get(pos..pos + len).ok_or("Truncated file")?;
+        pos += len;
+        match kind {
+            b"MThd" if len >= 6 => {
+                smf.format = u16::from_be_bytes([chunk[0], chunk[1]]);
+                smf.division = u16::from_be_bytes([chunk[4], chunk[5]]);
+                has_header = true;
+            },
+            b"MTrk" if has_header => smf.tracks.push(read_track(chunk, &mut smf.tempos)?),
+            _ => {} // unknown chunks are ignored as required by the spec
+        }
+    }
+    if !has_header {
+        Err("Not a midi file")?
+    }
+    smf.tempos.sort_by_key(|t| t.0);
+    Ok(smf)
+}
+
+impl Smf {
+    /**
+     * Events of all tracks sorted by time, format 2 files are treated as format 1
+     */
+    pub fn timeline(&self) -> Vec<TimedEvent> {
+        let mut events: Vec<TimedEvent> = self.tracks.iter().enumerate()
+            .flat_map(|(track, t)| t.events.iter().map(move |e| TimedEvent { tick: e.tick, micros: 0, track, bytes: e.bytes.clone() }))
+            .collect();
+        events.sort_by_key(|e| e.tick);
+
+        if self.division & 0x8000 != 0 { // SMPTE timing
+            let fps = ((self.division >> 8) as i8).unsigned_abs() as u64;
+            let ticks_per_frame = (self.division & 0xFF) as u64;
+            let ticks_per_second = (fps * ticks_per_frame).max(1);
+            for event in events.iter_mut() {
+                event.micros = event.tick * 1_000_000 / ticks_per_second;
+            }
+            return events;
+        }
+
+        let ppq = self.division.max(1);
+        let mut tempos = self.tempos.iter().peekable();
+        let (mut tempo, mut tempo_tick, mut tempo_micros) = (bpm_to_tempo(DEFAULT_BPM), 0, 0);
+        for event in events.iter_mut() {
+            while let Some(&&(tick, next)) = tempos.peek() {
+                if tick > event.tick {
+                    break;
+                }
+                tempo_micros += micros_from_ticks(tick - tempo_tick, ppq, tempo);
+                tempo_tick = tick;
+                tempo = next;
+                tempos.next();
+            }
+            event.micros = tempo_micros + micros_from_ticks(event.tick - tempo_tick, ppq, tempo);
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vlq () {
+        let cases: Vec<(u64, Vec<u8>)> = vec![
+            (0, vec![0x00]),
+            (0x40, vec![0x40]),
+            (0x7F, vec![0x7F]),
+            (0x80, vec![0x81, 0x00]),
+            (0x2000, vec![0xC0, 0x00]),
+            (0x3FFF, vec![0xFF, 0x7F]),
+            (0x0FFF_FFFF, vec![0xFF, 0xFF, 0xFF, 0x7F]),
+        ];
+        for (value, expected) in cases {
+            let mut out = vec![];
+            write_vlq(&mut out, value);
+            assert_eq!(out, expected);
+        }
+    }
+
+    #[test]
+    fn ticks () {
+        let tempo = bpm_to_tempo(120.0);
+        assert_eq!(tempo, 500_000);
+        assert_eq!(micros_to_ticks(500_000, 480, tempo), 480);
+        assert_eq!(micros_to_ticks(250_000, 96, tempo), 48);
+    }
+
+    #[test]
+    fn write_format_0 () {
+        let track = SmfTrack {
+            name: "".to_string(),
+            events: vec![
+                SmfEvent { tick: 0, bytes: vec![0x90, 60, 100] },
+                SmfEvent { tick: 0x80, bytes: vec![0x80, 60, 0] },
+                SmfEvent { tick: 0x80, bytes: vec![0xFE] },
+            ]
+        };
+        let file = write(0, vec![track], 96, 500_000);
+        assert_eq!(file[..14], [b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96]);
+        assert_eq!(file[14..22], [b'M', b'T', b'r', b'k', 0, 0, 0, 20]);
+        assert_eq!(file[22..], [
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+            0x00, 0x90, 60, 100,
+            0x81, 0x00, 0x80, 60, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn write_format_1 () {
+        let tracks = vec![
+            SmfTrack { name: "a".to_string(), events: vec![SmfEvent { tick: 10, bytes: vec![0xF0, 1, 2, 0xF7] }] },
+            SmfTrack { name: "b".to_string(), events: vec![SmfEvent { tick: 20, bytes: vec![0xF8] }] },
+        ];
+        let file = write(1, tracks, 480, 500_000);
+        assert_eq!(file[8..14], [0, 1, 0, 3, 0x01, 0xE0]);
+        let first = 14 + 8 + 11; // conductor track with tempo and end of track
+        assert_eq!(file[first..first + 8], [b'M', b'T', b'r', b'k', 0, 0, 0, 15]);
+        assert_eq!(file[first + 8..first + 23], [
+            0x00, 0xFF, 0x03, 0x01, b'a',
+            0x0A, 0xF0, 0x03, 1, 2, 0xF7,
+            0x00, 0xFF, 0x2F, 0x00,
+        ]);
+        assert_eq!(file[file.len() - 8..], [0x14, 0xF7, 0x01, 0xF8, 0x00, 0xFF, 0x2F, 0x00]);
+    }
+
+    #[test]
+    fn write_format_0_merges_tracks () {
+        let tracks = vec![
+            SmfTrack { name: "a".to_string(), events: vec![SmfEvent { tick: 20, bytes: vec![0xC0, 1] }] },
+            SmfTrack { name: "b".to_string(), events: vec![SmfEvent { tick: 10, bytes: vec![0xC1, 2] }] },
+        ];
+        let file = write(0, tracks, 480, 500_000);
+        assert_eq!(file[8..12], [0, 0, 0, 1]);
+        assert_eq!(file[file.len() - 10..], [0x0A, 0xC1, 2, 0x0A, 0xC0, 1, 0x00, 0xFF, 0x2F, 0x00]);
+    }
+
+    #[test]
+    fn read_write () {
+        let tracks = vec![
+            SmfTrack { name: "a".to_string(), events: vec![
+                SmfEvent { tick: 0, bytes: vec![0x90, 60, 100] },
+                SmfEvent { tick: 480, bytes: vec![0x80, 60, 0] },
+            ] },
+            SmfTrack { name: "b".to_string(), events: vec![
+                SmfEvent { tick: 240, bytes: vec![0xF0, 1, 2, 0xF7] },
+                SmfEvent { tick: 960, bytes: vec![0xF8] },
+            ] },
+        ];
+        let smf = read(&write(1, tracks, 480, 250_000)).unwrap();
+        assert_eq!(smf.format, 1);
+        assert_eq!(smf.division, 480);
+        assert_eq!(smf.tracks.len(), 3);
+        assert_eq!(smf.tracks[1].name, "a");
+        assert_eq!(smf.tempos, vec![(0, 250_000)]);
+        let timeline = smf.timeline();
+        assert_eq!(timeline, vec![
+            TimedEvent { tick: 0, micros: 0, track: 1, bytes: vec![0x90, 60, 100] },
+            TimedEvent { tick: 240, micros: 125_000, track: 2, bytes: vec![0xF0, 1, 2, 0xF7] },
+            TimedEvent { tick: 480, micros: 250_000, track: 1, bytes: vec![0x80, 60, 0] },
+            TimedEvent { tick: 960, micros: 500_000, track: 2, bytes: vec![0xF8] },
+        ]);
+    }
+
+    #[test]
+    fn read_running_status_and_tempo_changes () {
+        let track = [
+            0x00, 0x90, 60, 100, // note on
+            0x60, 62, 100, // running status at tick 96
+            0x00, 0xFF, 0x51, 0x03, 0x03, 0xD0, 0x90, // tempo 250000 at tick 96
+            0x60, 0x80, 60, 0, // tick 192
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let mut file = vec![b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96];
+        file.extend_from_slice(b"MTrk");
+        file.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        file.extend_from_slice(&track);
+        let smf = read(&file).unwrap();
+        let timeline = smf.timeline();
+        assert_eq!(timeline.len(), 3);
+        assert_eq!(timeline[1].bytes, vec![0x90, 62, 100]);
+        assert_eq!(timeline[1].micros, 500_000);
+        assert_eq!(timeline[2].micros, 750_000);
+    }
+
+    #[test]
+    fn read_invalid () {
+        assert!(read(&[]).is_err());
+        assert!(read(b"MTrk\0\0\0\0").is_err());
+        let mut file = vec![b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96];
+        file.extend_from_slice(&[b'M', b'T', b'r', b'k', 0, 0, 0, 2, 0x00, 0x40]);
+        assert!(read(&file).is_err());
+    }
+
+    #[test]
+    fn read_truncated_track () {
+        let mut file = vec![b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96];
+        file.extend_from_slice(&[b'M', b'T', b'r', b'k', 0, 0, 0, 1, 0x00]); // delta time without event
+        assert_eq!(read(&file).err(), Some("Unexpected end of track".to_string()));
+    }
+
+    #[test]
+    fn smpte_division () {
+        let track = SmfTrack { name: "".to_string(), events: vec![SmfEvent { tick: 100, bytes: vec![0xF8] }] };
+        let mut smf = Smf { format: 0, division: 0xE728, tracks: vec![track], tempos: vec![] }; // 25 fps, 40 ticks per frame
+        assert_eq!(smf.timeline()[0].micros, 100_000);
+        smf.division = 0x8028; // out of range frame rate
+        assert_eq!(smf.timeline()[0].micros, 100 * 1_000_000 / (128 * 40));
+    }
+}
@@ -1,8 +1,10 @@
 use std::collections::HashMap;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use midir::{MidiInput, MidiOutput};
 use once_cell::sync::Lazy;
-use serde::Serialize;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 #[cfg(target_os = "macos")]
 use coremidi::{Destinations, Sources};
 
@@ -25,10 +27,102 @@ static PREFIX_VI: &str = PREFIX_INPUT;
 #[cfg(windows)]
 static PREFIX_VO: &str = PREFIX_OUTPUT;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Default, Clone)]
 pub struct MidiPorts {
     pub inputs: Vec<String>,
-    pub outputs: Vec<String>
+    pub outputs: Vec<String>,
+    #[serde(skip_serializing)]
+    pub input_ids: Vec<String>, // port_identity of each input
+    #[serde(skip_serializing)]
+    pub output_ids: Vec<String>,
+}
+
+/**
+ * Identity of a connected port from its position in the system port list and its
+ * backend id, either changes when a device is plugged in again
+ */
+pub fn port_identity(index: usize, id: &str) -> String {
+    format!("{}/{}", index, id)
+}
+
+// shared timebase of routed messages, the unix time is captured together with the instant
+static CLOCK_ORIGIN: Lazy<(Instant, u64)> = Lazy::new(|| {
+    let unix = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (Instant::now(), unix.as_millis() as u64)
+});
+
+/**
+ * Monotonic microseconds since the clock origin, every routed message is stamped with it
+ */
+pub fn now_micros() -> u64 {
+    CLOCK_ORIGIN.0.elapsed().as_micros() as u64
+}
+
+/**
+ * Unix time in milliseconds of timestamp zero, converts message timestamps to dates
+ */
+pub fn clock_origin_millis() -> u64 {
+    CLOCK_ORIGIN.1
+}
+
+// alsa client:port numbers at the end of port names, they change between boots
+static ALSA_PORT_NUMBERS: Lazy<Regex> = Lazy::new(|| Regex::new(r"\s+\d+:\d+$").unwrap());
+
+/**
+ * Port name without the alsa client and port numbers
+ */
+pub fn port_base_name(name: &str) -> &str {
+    ALSA_PORT_NUMBERS.find(name).map_or(name, |m| &name[..m.start()])
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PortMatchMode {
+    #[default]
+    Exact,
+    Prefix,
+    Regex,
+    Nth, // nth port with the same name ignoring alsa numbers
+}
+
+/**
+ * How inputs and outputs find their midi port among the available ports
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct PortMatch {
+    pub mode: PortMatchMode,
+    pub pattern: String, // empty uses the device port name
+    pub index: usize, // nth mode occurrence starting at 1
+}
+
+impl PortMatch {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.mode == PortMatchMode::Regex {
+            Regex::new(&self.pattern).map_err(|e| format!("Invalid port pattern {}", e))?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Position of the first matching port name
+     */
+    pub fn find(&self, names: &[String], default_name: &str) -> Option<usize> {
+        let pattern = if self.pattern.is_empty() { default_name } else { &self.pattern };
+        match self.mode {
+            PortMatchMode::Exact => names.iter().position(|n| n == pattern),
+            PortMatchMode::Prefix => names.iter().position(|n| n.starts_with(pattern)),
+            PortMatchMode::Regex => {
+                let regex = Regex::new(pattern).ok()?;
+                names.iter().position(|n| regex.is_match(n))
+            },
+            PortMatchMode::Nth => names.iter()
+                .enumerate()
+                .filter(|(_, n)| port_base_name(n) == port_base_name(pattern))
+                .nth(self.index.max(1) - 1)
+                .map(|(i, _)| i),
+        }
+    }
 }
 
 pub const MIDI_NOTE_OFF: &str = "Note Off";
@@ -81,6 +175,300 @@ static MIDI_EXT_TYPES: Lazy<HashMap<u8, &'static str>> = Lazy::new(|| {
     map
 });
 
+/**
+ * Typed midi message, decoding and encoding is lossless
+ * so malformed or unsupported packets are kept as Unknown
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum MidiMessage {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    Aftertouch { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelAftertouch { channel: u8, pressure: u8 },
+    PitchBend { channel: u8, value: u16 }, // 14 bit, 8192 is center
+    SysEx(Vec<u8>), // packet starting with 0xF0, may be the first chunk of a longer message
+    TimeCode(u8),
+    SongPosition(u16),
+    SongSelect(u8),
+    TuneRequest,
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+    Unknown(Vec<u8>),
+}
+
+impl MidiMessage {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let unknown = || MidiMessage::Unknown(bytes.to_vec());
+        let Some(&status) = bytes.first() else {
+            return unknown();
+        };
+        if status == 0xF0 {
+            return MidiMessage::SysEx(bytes.to_vec());
+        }
+        let data = &bytes[1..];
+        if status < 0x80 || data.iter().any(|&b| b >= 0x80) {
+            return unknown();
+        }
+        let channel = status & 0x0F;
+        match (status, data) {
+            (0x80..=0x8F, &[note, velocity]) => MidiMessage::NoteOff { channel, note, velocity },
+            (0x90..=0x9F, &[note, velocity]) => MidiMessage::NoteOn { channel, note, velocity },
+            (0xA0..=0xAF, &[note, pressure]) => MidiMessage::Aftertouch { channel, note, pressure },
+            (0xB0..=0xBF, &[controller, value]) => MidiMessage::ControlChange { channel, controller, value },
+            (0xC0..=0xCF, &[program]) => MidiMessage::ProgramChange { channel, program },
+            (0xD0..=0xDF, &[pressure]) => MidiMessage::ChannelAftertouch { channel, pressure },
+            (0xE0..=0xEF, &[lsb, msb]) => MidiMessage::PitchBend { channel, value: (msb as u16) << 7 | lsb as u16 },
+            (0xF1, &[value]) => MidiMessage::TimeCode(value),
+            (0xF2, &[lsb, msb]) => MidiMessage::SongPosition((msb as u16) << 7 | lsb as u16),
+            (0xF3, &[song]) => MidiMessage::SongSelect(song),
+            (0xF6, &[]) => MidiMessage::TuneRequest,
+            (0xF8, &[]) => MidiMessage::Clock,
+            (0xFA, &[]) => MidiMessage::Start,
+            (0xFB, &[]) => MidiMessage::Continue,
+            (0xFC, &[]) => MidiMessage::Stop,
+            (0xFE, &[]) => MidiMessage::ActiveSensing,
+            (0xFF, &[]) => MidiMessage::Reset,
+            _ => unknown(),
+        }
+    }
+
+    /**
+     * Splits a packet into single messages and expands running status, realtime
+     * bytes inside other messages come first. Sysex lasts until its end byte or the
+     * end of the packet, stray data bytes like sysex continuations are kept together
+     */
+    pub fn split(bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut messages = vec![];
+        let mut current: Vec<u8> = vec![];
+        let mut running = None;
+        for &byte in bytes {
+            if byte >= 0xF8 {
+                messages.push(vec![byte]);
+                continue;
+            }
+            if byte == 0xF7 {
+                current.push(byte);
+                messages.push(std::mem::take(&mut current));
+                running = None;
+                continue;
+            }
+            if byte >= 0x80 {
+                if !current.is_empty() {
+                    messages.push(std::mem::take(&mut current));
+                }
+                running = (byte < 0xF0).then_some(byte); // system common cancels running status
+            } else if current.is_empty() {
+                current.extend(running);
+            }
+            current.push(byte);
+            if current.first().and_then(|&status| message_len(status)).is_some_and(|len| current.len() >= len) {
+                messages.push(std::mem::take(&mut current));
+            }
+        }
+        if !current.is_empty() {
+            messages.push(current);
+        }
+        messages
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            MidiMessage::NoteOff { channel, note, velocity } => vec![0x80 | channel & 0x0F, note & 0x7F, velocity & 0x7F],
+            MidiMessage::NoteOn { channel, note, velocity } => vec![0x90 | channel & 0x0F, note & 0x7F, velocity & 0x7F],
+            MidiMessage::Aftertouch { channel, note, pressure } => vec![0xA0 | channel & 0x0F, note & 0x7F, pressure & 0x7F],
+            MidiMessage::ControlChange { channel, controller, value } => vec![0xB0 | channel & 0x0F, controller & 0x7F, value & 0x7F],
+            MidiMessage::ProgramChange { channel, program } => vec![0xC0 | channel & 0x0F, program & 0x7F],
+            MidiMessage::ChannelAftertouch { channel, pressure } => vec![0xD0 | channel & 0x0F, pressure & 0x7F],
+            MidiMessage::PitchBend { channel, value } => vec![0xE0 | channel & 0x0F, (value & 0x7F) as u8, (value >> 7 & 0x7F) as u8],
+            MidiMessage::TimeCode(value) => vec![0xF1, value & 0x7F],
+            MidiMessage::SongPosition(value) => vec![0xF2, (value & 0x7F) as u8, (value >> 7 & 0x7F) as u8],
+            MidiMessage::SongSelect(song) => vec![0xF3, song & 0x7F],
+            MidiMessage::TuneRequest => vec![0xF6],
+            MidiMessage::Clock => vec![0xF8],
+            MidiMessage::Start => vec![0xFA],
+            MidiMessage::Continue => vec![0xFB],
+            MidiMessage::Stop => vec![0xFC],
+            MidiMessage::ActiveSensing => vec![0xFE],
+            MidiMessage::Reset => vec![0xFF],
+            MidiMessage::SysEx(bytes) | MidiMessage::Unknown(bytes) => bytes.clone(),
+        }
+    }
+
+    /**
+     * Zero based channel for channel voice messages
+     */
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            MidiMessage::NoteOff { channel, .. } |
+            MidiMessage::NoteOn { channel, .. } |
+            MidiMessage::Aftertouch { channel, .. } |
+            MidiMessage::ControlChange { channel, .. } |
+            MidiMessage::ProgramChange { channel, .. } |
+            MidiMessage::ChannelAftertouch { channel, .. } |
+            MidiMessage::PitchBend { channel, .. } => Some(channel),
+            _ => None
+        }
+    }
+
+    /**
+     * Display name matching the ones returned by parse_midi
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            MidiMessage::NoteOff { .. } => MIDI_NOTE_OFF,
+            MidiMessage::NoteOn { .. } => MIDI_NOTE_ON,
+            MidiMessage::Aftertouch { .. } => MIDI_AFTERTOUCH,
+            MidiMessage::ControlChange { .. } => MIDI_CC,
+            MidiMessage::ProgramChange { .. } => MIDI_PROG_CHNG,
+            MidiMessage::ChannelAftertouch { .. } => MIDI_CHANNEL_AT,
+            MidiMessage::PitchBend { .. } => MIDI_PITCH,
+            MidiMessage::SysEx(_) => MIDI_EXT_SYSEX,
+            MidiMessage::TimeCode(_) => MIDI_EXT_MTC,
+            MidiMessage::SongPosition(_) => MIDI_EXT_POSITION,
+            MidiMessage::SongSelect(_) => MIDI_EXT_SELECT,
+            MidiMessage::TuneRequest => MIDI_EXT_TUNE,
+            MidiMessage::Clock => MIDI_EXT_CLOCK,
+            MidiMessage::Start => MIDI_EXT_START,
+            MidiMessage::Continue => MIDI_EXT_CONTINUE,
+            MidiMessage::Stop => MIDI_EXT_STOP,
+            MidiMessage::ActiveSensing => MIDI_EXT_ACTIVE_SNS,
+            MidiMessage::Reset => MIDI_EXT_RESET,
+            MidiMessage::Unknown(_) => "Unknown",
+        }
+    }
+}
+
+/**
+ * Kind of a logical parameter, the decoder only assembles the 14 bit kinds
+ * while cc7 stands for plain control changes
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamKind {
+    Cc7,
+    Cc14,
+    Nrpn,
+    Rpn,
+}
+
+/**
+ * Parameter change assembled from a sequence of control changes, value is 14 bit
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamEvent {
+    pub kind: ParamKind,
+    pub channel: u8,
+    pub param: u16, // controller 0 to 31 for cc14, 14 bit parameter number for nrpn and rpn
+    pub value: u16,
+}
+
+impl ParamEvent {
+    /**
+     * Complete sequence of control changes that sets this parameter
+     */
+    pub fn to_messages(&self) -> Vec<Vec<u8>> {
+        let status = 0xB0 | self.channel & 0x0F;
+        let (msb, lsb) = ((self.value >> 7 & 0x7F) as u8, (self.value & 0x7F) as u8);
+        let (param_msb, param_lsb) = ((self.param >> 7 & 0x7F) as u8, (self.param & 0x7F) as u8);
+        match self.kind {
+            ParamKind::Cc7 => vec![vec![status, param_lsb, msb]],
+            ParamKind::Cc14 => vec![vec![status, param_lsb & 0x1F, msb], vec![status, (param_lsb & 0x1F) + 32, lsb]],
+            ParamKind::Nrpn => vec![vec![status, 99, param_msb], vec![status, 98, param_lsb], vec![status, 6, msb], vec![status, 38, lsb]],
+            ParamKind::Rpn => vec![vec![status, 101, param_msb], vec![status, 100, param_lsb], vec![status, 6, msb], vec![status, 38, lsb]],
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct ParamChannel {
+    msb: [Option<u8>; 32], // last MSB of controllers 0 to 31
+    registered: bool,
+    param_msb: Option<u8>,
+    param_lsb: Option<u8>,
+    data_msb: Option<u8>,
+    fine: bool, // data entry LSBs were seen, so data MSBs wait for them
+}
+
+/**
+ * Stateful decoder that assembles MSB/LSB controller pairs and NRPN/RPN data entry
+ * into parameter events, one per completed value. Controllers 0 to 31 only become
+ * 14 bit once their LSB arrives, data entry MSBs emit alone until the sender uses LSBs
+ */
+#[derive(Default)]
+pub struct ParamDecoder {
+    channels: [ParamChannel; 16],
+}
+
+impl ParamDecoder {
+    pub fn decode(&mut self, msg: &MidiMessage) -> Option<ParamEvent> {
+        let MidiMessage::ControlChange { channel, controller, value } = *msg else {
+            return None;
+        };
+        let state = &mut self.channels[channel as usize & 0x0F];
+        let event = |kind, param, value| Some(ParamEvent { kind, channel, param, value });
+        match controller {
+            98..=101 => { // nrpn and rpn parameter select
+                state.registered = controller >= 100;
+                if controller % 2 == 1 {
+                    state.param_msb = Some(value);
+                } else {
+                    state.param_lsb = Some(value);
+                }
+                state.data_msb = None;
+                None
+            },
+            6 | 38 => {
+                let (Some(param_msb), Some(param_lsb)) = (state.param_msb, state.param_lsb) else {
+                    return None;
+                };
+                if state.registered && param_msb == 127 && param_lsb == 127 { // rpn null
+                    return None;
+                }
+                let kind = if state.registered { ParamKind::Rpn } else { ParamKind::Nrpn };
+                let param = (param_msb as u16) << 7 | param_lsb as u16;
+                if controller == 6 {
+                    state.data_msb = Some(value);
+                    if state.fine {
+                        return None;
+                    }
+                    event(kind, param, (value as u16) << 7)
+                } else {
+                    let msb = state.data_msb?;
+                    state.fine = true;
+                    event(kind, param, (msb as u16) << 7 | value as u16)
+                }
+            },
+            0..=31 => {
+                state.msb[controller as usize] = Some(value);
+                None
+            },
+            32..=63 => {
+                let msb = state.msb[controller as usize - 32]?;
+                event(ParamKind::Cc14, controller as u16 - 32, (msb as u16) << 7 | value as u16)
+            },
+            _ => None
+        }
+    }
+}
+
+/**
+ * Length of a message with its status byte, None for sysex and data bytes
+ */
+fn message_len(status: u8) -> Option<usize> {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => Some(3),
+        0xC0..=0xDF | 0xF1 | 0xF3 => Some(2),
+        0xF4..=0xF6 => Some(1),
+        _ => None
+    }
+}
+
 pub fn parse_midi(bytes: Vec<u8>, sysex: &mut Vec<u8>) -> Vec<(&'static str, u8, Vec<u8>)> {
     let mut events = Vec::new();
 
@@ -152,24 +540,30 @@ pub fn get_valid_midi_ports() -> Result<MidiPorts, Box<dyn std::error::Error>> {
 
     let mut res_ins = Vec::new();
     let mut res_outs = Vec::new();
+    let mut in_ids = Vec::new();
+    let mut out_ids = Vec::new();
 
-    for p in in_ports {
-        let pname = input.port_name(&p)?;
+    for (i, p) in in_ports.iter().enumerate() {
+        let pname = input.port_name(p)?;
         if !pname.starts_with(PREFIX_OUTPUT) && !pname.starts_with(PREFIX_O) && !pname.starts_with(PREFIX_VO) {
             res_ins.push(pname);
+            in_ids.push(port_identity(i, &p.id()));
         }
     }
 
-    for p in out_ports {
-        let pname = output.port_name(&p)?;
+    for (i, p) in out_ports.iter().enumerate() {
+        let pname = output.port_name(p)?;
         if !pname.starts_with(PREFIX_INPUT) && !pname.starts_with(PREFIX_I) && !pname.starts_with(PREFIX_VI) {
             res_outs.push(pname);
+            out_ids.push(port_identity(i, &p.id()));
         }
     }
 
     Ok(MidiPorts {
         inputs: res_ins,
-        outputs: res_outs
+        outputs: res_outs,
+        input_ids: in_ids,
+        output_ids: out_ids,
     })
 }
 
@@ -177,24 +571,31 @@ pub fn get_valid_midi_ports() -> Result<MidiPorts, Box<dyn std::error::Error>> {
 pub fn get_valid_midi_ports() -> Result<MidiPorts, Box<dyn std::error::Error>> {
     let mut res_ins = Vec::new();
     let mut res_outs = Vec::new();
+    let mut in_ids = Vec::new();
+    let mut out_ids = Vec::new();
 
-    for destination in Sources.into_iter() {
+    // same ids as midir ports
+    for (i, destination) in Sources.into_iter().enumerate() {
         let pname = destination.display_name().unwrap_or_default();
         if !pname.is_empty() && !pname.starts_with(PREFIX_OUTPUT) && !pname.starts_with(PREFIX_O) && !pname.starts_with(PREFIX_VO) {
             res_ins.push(pname);
+            in_ids.push(port_identity(i, &destination.unique_id().unwrap_or(0).to_string()));
         }
     }
 
-    for source in Destinations.into_iter() {
+    for (i, source) in Destinations.into_iter().enumerate() {
         let pname = source.display_name().unwrap_or_default();
         if !pname.is_empty() && !pname.starts_with(PREFIX_INPUT) && !pname.starts_with(PREFIX_I) && !pname.starts_with(PREFIX_VI) {
             res_outs.push(pname);
+            out_ids.push(port_identity(i, &source.unique_id().unwrap_or(0).to_string()));
         }
     }
 
     Ok(MidiPorts {
         inputs: res_ins,
-        outputs: res_outs
+        outputs: res_outs,
+        input_ids: in_ids,
+        output_ids: out_ids,
     })
 }
 
@@ -202,6 +603,24 @@ pub fn get_valid_midi_ports() -> Result<MidiPorts, Box<dyn std::error::Error>> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn port_match () {
+        let names: Vec<String> = ["Midi Through 14:0", "nanoKEY2 MIDI 1 24:0", "nanoKEY2 MIDI 1 28:0", "Launchpad 32:0"]
+            .iter().map(|n| n.to_string()).collect();
+        let find = |mode, pattern: &str, index| PortMatch { mode, pattern: pattern.to_string(), index }.find(&names, "Launchpad 32:0");
+        assert_eq!(find(PortMatchMode::Exact, "", 0), Some(3));
+        assert_eq!(find(PortMatchMode::Exact, "Launchpad 33:0", 0), None);
+        assert_eq!(find(PortMatchMode::Prefix, "nanoKEY2", 0), Some(1));
+        assert_eq!(find(PortMatchMode::Regex, r"^nano.* 28:\d+$", 0), Some(2));
+        assert_eq!(find(PortMatchMode::Regex, "(", 0), None);
+        assert_eq!(find(PortMatchMode::Nth, "nanoKEY2 MIDI 1 20:0", 2), Some(2));
+        assert_eq!(find(PortMatchMode::Nth, "nanoKEY2 MIDI 1", 0), Some(1));
+        assert_eq!(find(PortMatchMode::Nth, "nanoKEY2 MIDI 1", 3), None);
+        assert_eq!(find(PortMatchMode::Nth, "", 1), Some(3));
+        assert!(PortMatch { mode: PortMatchMode::Regex, pattern: "(".to_string(), index: 0 }.validate().is_err());
+        assert_eq!(port_base_name("IAC Driver Bus 1"), "IAC Driver Bus 1");
+    }
+
     #[test]
     fn parse_midi_note_off () {
         let mut sysex_buffer = Vec::new();
@@ -270,4 +689,87 @@ mod tests {
         assert_eq!(events[5].0, MIDI_NOTE_ON);
         assert_eq!(events[4].2, vec![0xF0, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0xF7]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn midi_message_decode () {
+        assert_eq!(MidiMessage::from_bytes(&[0x93, 60, 100]), MidiMessage::NoteOn { channel: 3, note: 60, velocity: 100 });
+        assert_eq!(MidiMessage::from_bytes(&[0xB0, 7, 127]), MidiMessage::ControlChange { channel: 0, controller: 7, value: 127 });
+        assert_eq!(MidiMessage::from_bytes(&[0xCF, 5]), MidiMessage::ProgramChange { channel: 15, program: 5 });
+        assert_eq!(MidiMessage::from_bytes(&[0xE1, 0x00, 0x40]), MidiMessage::PitchBend { channel: 1, value: 8192 });
+        assert_eq!(MidiMessage::from_bytes(&[0xE1, 0x7F, 0x7F]), MidiMessage::PitchBend { channel: 1, value: 16383 });
+        assert_eq!(MidiMessage::from_bytes(&[0xF2, 0x01, 0x01]), MidiMessage::SongPosition(129));
+        assert_eq!(MidiMessage::from_bytes(&[0xF8]), MidiMessage::Clock);
+        assert_eq!(MidiMessage::from_bytes(&[0xF0, 0x41, 0xF7]), MidiMessage::SysEx(vec![0xF0, 0x41, 0xF7]));
+        assert_eq!(MidiMessage::from_bytes(&[0x81]), MidiMessage::Unknown(vec![0x81]));
+        assert_eq!(MidiMessage::from_bytes(&[0x90, 60, 200]), MidiMessage::Unknown(vec![0x90, 60, 200]));
+        assert_eq!(MidiMessage::from_bytes(&[]), MidiMessage::Unknown(vec![]));
+    }
+
+    #[test]
+    fn midi_message_lossless () {
+        let packets: Vec<Vec<u8>> = vec![
+            vec![0x80, 1, 2], vec![0x9F, 127, 0], vec![0xA2, 3, 4], vec![0xB5, 0, 127],
+            vec![0xC0, 9], vec![0xD3, 64], vec![0xE0, 0x12, 0x34], vec![0xF1, 0x21],
+            vec![0xF2, 0x7F, 0x00], vec![0xF3, 2], vec![0xF6], vec![0xF8], vec![0xFA],
+            vec![0xFB], vec![0xFC], vec![0xFE], vec![0xFF], vec![0xF0, 1, 2, 3],
+            vec![0x44, 0x45], vec![0x80, 1, 2, 3], vec![0xF7], vec![0xF4],
+        ];
+        for packet in packets {
+            assert_eq!(MidiMessage::from_bytes(&packet).to_bytes(), packet);
+        }
+    }
+
+    #[test]
+    fn split_packets () {
+        let split = |bytes: &[u8]| MidiMessage::split(bytes);
+        assert_eq!(split(&[0x90, 60, 1, 62, 1]), vec![vec![0x90, 60, 1], vec![0x90, 62, 1]]); // running status
+        assert_eq!(split(&[0x90, 60, 1, 0x80, 60, 0]), vec![vec![0x90, 60, 1], vec![0x80, 60, 0]]);
+        assert_eq!(split(&[0xC0, 1, 2]), vec![vec![0xC0, 1], vec![0xC0, 2]]);
+        assert_eq!(split(&[0xB0, 1, 2, 0xF8, 3, 4]), vec![vec![0xB0, 1, 2], vec![0xF8], vec![0xB0, 3, 4]]);
+        assert_eq!(split(&[0x90, 60, 0xF8, 1]), vec![vec![0xF8], vec![0x90, 60, 1]]);
+        assert_eq!(split(&[0xF0, 1, 0xFE, 2, 0xF7, 0x90, 1, 2]), vec![vec![0xFE], vec![0xF0, 1, 2, 0xF7], vec![0x90, 1, 2]]);
+        assert_eq!(split(&[0xF2, 1, 2, 3]), vec![vec![0xF2, 1, 2], vec![3]]); // no running status after system common
+        assert_eq!(split(&[0xF6, 0xFA]), vec![vec![0xF6], vec![0xFA]]);
+        for packet in [&[0xF0, 1, 2][..], &[0x44, 0x45, 0xF7], &[0x90, 60], &[0x90, 60, 1]] {
+            assert_eq!(split(packet), vec![packet.to_vec()]);
+        }
+        assert!(split(&[]).is_empty());
+    }
+
+    #[test]
+    fn param_decode_cc14 () {
+        let mut decoder = ParamDecoder::default();
+        let cc = |controller, value| MidiMessage::ControlChange { channel: 2, controller, value };
+        assert_eq!(decoder.decode(&cc(33, 5)), None); // lsb without msb
+        assert_eq!(decoder.decode(&cc(1, 0x40)), None); // could be a 7 bit controller
+        let event = decoder.decode(&cc(33, 5)).unwrap();
+        assert_eq!((event.kind, event.param, event.value), (ParamKind::Cc14, 1, 0x2005));
+        assert_eq!(event.to_messages(), vec![vec![0xB2, 1, 0x40], vec![0xB2, 33, 5]]);
+        assert_eq!(decoder.decode(&cc(74, 5)), None);
+        assert_eq!(decoder.decode(&MidiMessage::NoteOn { channel: 0, note: 1, velocity: 1 }), None);
+    }
+
+    #[test]
+    fn param_decode_nrpn () {
+        let mut decoder = ParamDecoder::default();
+        let cc = |controller, value| MidiMessage::ControlChange { channel: 0, controller, value };
+        assert_eq!(decoder.decode(&cc(6, 1)), None); // data entry without parameter
+        let packets = ParamEvent { kind: ParamKind::Nrpn, channel: 0, param: 0x0102, value: 0x1234 }.to_messages();
+        let events: Vec<ParamEvent> = packets.iter().filter_map(|p| decoder.decode(&MidiMessage::from_bytes(p))).collect();
+        assert_eq!(events.len(), 2); // the first msb is sent before lsbs are known
+        assert_eq!((events[0].param, events[0].value), (0x0102, 0x1200));
+        assert_eq!((events[1].kind, events[1].value), (ParamKind::Nrpn, 0x1234));
+        let events: Vec<ParamEvent> = packets.iter().filter_map(|p| decoder.decode(&MidiMessage::from_bytes(p))).collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].value, 0x1234);
+
+        let mut decoder = ParamDecoder::default(); // sender without lsbs
+        decoder.decode(&cc(101, 0));
+        decoder.decode(&cc(100, 0));
+        let event = decoder.decode(&cc(6, 12)).unwrap(); // pitch bend range
+        assert_eq!((event.kind, event.param, event.value), (ParamKind::Rpn, 0, 12 << 7));
+        decoder.decode(&cc(101, 127));
+        decoder.decode(&cc(100, 127));
+        assert_eq!(decoder.decode(&cc(6, 12)), None);
+    }
+}
@@ -10,6 +10,8 @@ export const EVT_FILE_SAVE_AS = 'save-file-as'
 export const EVT_FILE_SAVE = 'save-file'
 export const EVT_SCRIPT_ERROR = 'script-error'
 export const EVT_SCRIPT_LOG = 'script-log'
+export const EVT_SHARED_CHANGE = 'shared-change'
+export const EVT_DEVICE_STATUS = 'device-status'
 export const EVT_SHOW_ABOUT = 'show-about'
 
 // APP EVENTS
@@ -34,6 +36,11 @@ export const PORT_STOP = 'stop';
 export const PORT_COMMON = 'CM';
 export const PORT_SYSEX = 'sysex';
 export const PORT_UNKNOWN = 'unknown';
+export const PORT_PARAM = 'param';
+// mapper non matching messages port, see mapper.rs
+export const PORT_THRU = 'thru';
+// delay input without delay, see delay.rs
+export const PORT_DRY = 'dry';
 
 export const PREFIX_INPUT = 'Mdash In - ';
 export const PREFIX_OUTPUT = 'Mdash Out - ';
@@ -52,17 +59,21 @@ export const DEFAULT_PORTS = {
     in: ['*'],
     out: [
       '*', '1', '2', '3', '4', '5', '6', '7', '8', '9', '10', '11', '12', '13', '14', '15', '16', PORT_NOTE_ON, PORT_NOTE_OFF, PORT_AFTERTOUCH,
-      PORT_CC, PORT_PROGRAM, PORT_PITCH, PORT_CHANNEL_AT, PORT_REALTIME, PORT_START, PORT_CONTINUE, PORT_STOP, PORT_COMMON, PORT_SYSEX, PORT_UNKNOWN
+      PORT_CC, PORT_PROGRAM, PORT_PITCH, PORT_CHANNEL_AT, PORT_REALTIME, PORT_START, PORT_CONTINUE, PORT_STOP, PORT_COMMON, PORT_SYSEX, PORT_PARAM, PORT_UNKNOWN
     ],
     visibleOut: [
       '*', '1', '2', PORT_CC, PORT_PROGRAM, PORT_PITCH
     ]},
-  map: { in: ['*'], out: ['*'] },
-  delay: { in: ['*'], out: ['*'] },
+  map: { in: ['*'], out: ['*', PORT_THRU], visibleOut: ['*'] },
+  delay: { in: ['*'], out: ['*', PORT_DRY], visibleOut: ['*'] },
   monitor: { in: ['*'], out: ['*'] },
   note: {},
-  trigger: { out: ['*'] },
-  script: { in: ['*'] }
+  trigger: { in: ['*'], out: ['*'] },
+  script: { in: ['*'] },
+  recorder: { in: ['*'], out: ['*'] },
+  player: { in: ['*'], out: ['*'] },
+  clock: { out: ['*'] },
+  divider: { in: ['*'], out: ['*'] }
 }
 
 export const PORT_NAMES = {
@@ -76,9 +87,11 @@ export const PORT_NAMES = {
   'RT': 'Realtime',
   'CM': 'Common',
   'sysex': 'Sysex',
+  'param': '14-bit Param',
   'start': 'Start',
   'continue': 'Continue',
   'stop': 'Stop',
+  'dry': 'Dry',
   '1': 'Channel 1',
   '2': 'Channel 2',
   '3': 'Channel 3',
@@ -134,7 +147,35 @@ from: the source node
 from_port: the source port
 to: the destination node
 to_port: the destination port
+send(port, bytes): sends bytes right away
+after(ms, fn): calls fn once after ms, returns a timer id
+every(ms, fn): calls fn every ms, returns a timer id
+cancel(id): cancels a timer
+midi: helpers like midi.note_on(ch, note, vel),
+  midi.parse(bytes).type, midi.note_name(60),
+  midi.chord(60, "minor") and midi.scales,
+  channels are 1 to 16
+store: table saved with the project and
+  kept when the state is reset
+shared.get(key), shared.set(key, value),
+  shared.keys(): values shared by all scripts
+require(name): loads name.lua from the lib
+  folder next to the project or the script
+  library folder set in the settings,
+  reloaded when the file changes
 
+Timers are cancelled when the script
+state is reset or the node is removed.
+]]
+
+--[[ Callbacks
+
+Scripts that define on_midi(msg),
+on_start(), on_stop() or
+on_shared(key, value) run once when
+saved and then only call these
+functions, see the Callbacks template.
+Others only run for every message.
 ]]
 
 --[[ Sending bytes
@@ -198,5 +239,65 @@ end
 table.insert(res, {
   port="out", bytes=bytes
 })`
+  },
+  {
+    id: 'mdash-callbacks',
+    name: 'Callbacks',
+    outPorts: ['out'],
+    script:
+`-- runs once when the script is saved
+count = 0
+
+function on_start()
+  log("started")
+end
+
+-- msg has type, channel, bytes, from, from_port,
+-- to, to_port and fields like note or value
+-- returns the same entries as res
+function on_midi(msg)
+  count = count + 1
+  return {
+    { port="out", bytes=msg.bytes }
+  }
+end
+
+-- called when another script changes
+-- a shared value
+function on_shared(key, value)
+  log(key .. " changed")
+end
+
+-- called when the script is replaced,
+-- reset or removed
+function on_stop()
+  log("processed " .. count .. " messages")
+end`
+  },
+  {
+    id: 'mdash-echo',
+    name: 'Echo',
+    outPorts: ['out'],
+    script:
+`--[[
+Repeats notes three times with
+decreasing velocity every 250ms.
+]]
+
+table.insert(res, {
+  port="out", bytes=bytes
+})
+
+local status = bytes[1] or 0
+if status >> 4 ~= 0x09 and status >> 4 ~= 0x08 then
+  return
+end
+
+for i = 1, 3 do
+  local echo = { bytes[1], bytes[2], bytes[3] // (i + 1) }
+  after(i * 250, function()
+    send("out", echo)
+  end)
+end`
   }
 ]
\ No newline at end of file
@@ -5,7 +5,6 @@ import { invoke } from "@tauri-apps/api/core";
 import Emitter from 'tiny-emitter'
 import { camelCase, snakeCase } from '../utils';
 import graphStore from './graph';
-import { stripPrefix } from '../utils';
 import { DEFAULT_SCRIPT_TEMPLATES } from '../globals';
 import { saveWindowState, StateFlags } from '@tauri-apps/plugin-window-state'
 
@@ -13,6 +12,7 @@ export default defineStore('app', {
   state: () => ({
     emitter: new Emitter(),
     version: '',
+    clockOrigin: 0, // unix millis of backend timestamp zero, midi event timestamps are micros since then
     os: __TAURI_OS_PLUGIN_INTERNALS__.os_type, // windows, linux or macos
     showAbout: false, // about popup
     showSettings: false, // settings popup
@@ -23,6 +23,8 @@ export default defineStore('app', {
       scriptShowLineNumbers: false,
       startMinimized: false,
       hubPaused: false,
+      scriptLibraryPath: '', // user lua modules folder, projects also load modules from lib/ next to the project file
+      embedScriptLibrary: false,
       monitorIn: {},
       monitorOut: {}
     },
@@ -42,6 +44,7 @@ export default defineStore('app', {
   actions: {
     async init () {
       this.version = await getVersion()
+      this.clockOrigin = await invoke('get_clock_origin')
       await this.getSettings()
       if (!this.settings.scriptTemplates?.length) {
         this.settings.scriptTemplates = JSON.parse(JSON.stringify(DEFAULT_SCRIPT_TEMPLATES))
@@ -133,27 +136,22 @@ export default defineStore('app', {
       try {
         const res = await invoke('get_midi_ports');
         this.midiPorts = res;
-        const nodes = graphStore().nodes
-        for (let node of nodes) {
-          if (
-            node.class === 'input' && !this.midiPorts.inputs.includes(stripPrefix(node.id)) ||
-            node.class === 'output' && !this.midiPorts.outputs.includes(stripPrefix(node.id))
-          ) {
-            node.disconnected = true // io not in the list of available ports, disconnect it
-          } else if (node.disconnected && (node.class === 'input' || node.class === 'output')) {
-            try {
-              await invoke('reconnect_device', { id: node.id }) // io now available on ports list, reconnect it
-              node.disconnected = false
-            } catch (err) {
-              this.handleError(err)
-            }
-          }
-        }
       } catch (e) {
         return this.handleError(e);
       }
     },
 
+    /**
+     * Inputs and outputs are reconnected by the backend port watcher
+     */
+    onDeviceStatus ({ payload }) {
+      const node = graphStore().getNode(payload.id)
+      if (node) {
+        node.online = payload.online
+        node.disconnected = !payload.online
+      }
+    },
+
     async onProjectNew () {
       const graph = graphStore()
       graph.selected = ''
@@ -176,6 +174,10 @@ export default defineStore('app', {
           }
         })
 
+        graph.nodes
+          .filter(node => node.class === 'input' || node.class === 'output')
+          .forEach(node => { node.disconnected = node.online === false })
+
         project.connectors.forEach(connector => {
           if (typeof connector.type === 'string') {
             graph.edges.push(connector)
@@ -239,6 +241,13 @@ export default defineStore('app', {
       })})
     },
 
+    async chooseScriptLibrary() {
+      const path = await open({ directory: true })
+      if (path) {
+        await this.setSettings({ scriptLibraryPath: path })
+      }
+    },
+
     async openFile() {
       const path = await open({
         filters: [{ name: 'JSON', extensions: ['json'] }]
@@ -252,7 +261,7 @@ export default defineStore('app', {
       }
     },
 
-    async hubProcess(opts = {ts, bytes, from, to, fromPort, toPort}) {
+    async hubProcess(opts = {bytes, from, to, fromPort, toPort}) {
       try {
         await invoke('hub_process', opts)
       } catch (err) {
@@ -2,7 +2,7 @@ import { defineStore } from 'pinia'
 import { invoke } from "@tauri-apps/api/core";
 import { graph } from '../lib/vnodes'
 import { appStore } from '.'
-import { stripPrefix, camelCase, snakeCase, snakeCaseStr, millisToSecondsStr, capitalize } from '../utils';
+import { stripPrefix, portOf, camelCase, snakeCase, snakeCaseStr, millisToSecondsStr, capitalize } from '../utils';
 import Emitter from 'tiny-emitter'
 import { createDAG } from '../lib/vnodes/src/util'
 import { DEFAULT_PORTS, FIT_NODE, PORT_NAMES } from '../globals';
@@ -50,6 +50,27 @@ export default defineStore('graph', {
         }).catch(appStore().handleError)
     },
 
+    /**
+     * Updates connector filters like mute, channel and type masks, note range and velocity scale
+     */
+    async setConnectorSettings(edge, settings) {
+      try {
+        const connector = await invoke('set_connector_settings', {
+          id: edge.id,
+          settings: snakeCase({ ...this.getConnectorSettings(edge), ...settings })
+        })
+        Object.assign(edge, camelCase(connector))
+      } catch (err) {
+        appStore().handleError(err)
+        throw err
+      }
+    },
+
+    getConnectorSettings(edge) {
+      const { muted, channels, types, noteMin, noteMax, velocityScale } = edge
+      return { muted, channels, types, noteMin, noteMax, velocityScale }
+    },
+
     getEdge({ from, to, fromPort, toPort }) {
       return this.edges.find(c =>
         c.from === from && c.to === to &&
@@ -78,8 +99,9 @@ export default defineStore('graph', {
       const node = this.graph.createNode(device)
       node.name = stripPrefix(node.id)
       if (node.class === 'output' || node.class === 'input') {
-        const split = node.name.split(':')
-        node.name = split[0]
+        // ids of new inputs and outputs are not their port, the label is shown instead
+        const label = node.label || portOf(node)
+        node.name = label === portOf(node) ? label.split(':')[0] : label
       }
       if (node.class === 'delay') {
         node.name = millisToSecondsStr(node.delay)
@@ -137,27 +159,31 @@ export default defineStore('graph', {
       const node = this.getNode(id)
       if (!node) return
       node.name = name.trim()
+      if (node.class === 'input' || node.class === 'output') {
+        node.label = node.name
+        invoke('set_device_data', { id, key: 'label', data: node.name })
+          .catch(err => appStore().handleError(err))
+      }
       this.fitNode(id)
     },
 
     async createDeviceAt(x, y, centerDevice, opts = { class: 'Unknown' }) {
+      // inputs and outputs ids are prefixed on the backend
       const makeUniqueId = classname => {
         const nodes = this.nodes.filter(n => n.class === classname)
         let i = nodes.length + 1
-        while (nodes.some(n => n.id === capitalize(classname) + ' ' + i)) { i++ }
+        while (nodes.some(n => stripPrefix(n.id) === capitalize(classname) + ' ' + i)) { i++ }
         return capitalize(classname) + ' ' + i
       }
 
-      let id = ['input', 'output'].includes(opts.class)
-        ? opts.id
-        : makeUniqueId(opts.class)
+      let id = makeUniqueId(opts.class)
 
       try {
         let device
         if (opts.class === 'note') {
           device = Object.assign({ inPorts: [], outPorts: [], note: 'Double click to edit' }, opts, { id }) // no need to create notes on the backend
         } else {
-          device = await invoke('add_device', { id, class: opts.class })
+          device = await invoke('add_device', { id, class: opts.class, port: opts.port })
         }
 
         device.x = x
@@ -219,23 +245,15 @@ export default defineStore('graph', {
       this.emitter.emit(FIT_NODE, id)
     },
     /**
-     * Replaces input or output device with another new device from available midi ports list
+     * Binds an input or output device to another port from the available midi ports list,
+     * the device id and its edges are kept
      */
-    async replaceIO(device, id, newname = '') {
-      const inputs = this.edges.filter(e => e.from === device.id)
-      const outputs = this.edges.filter(e => e.to === device.id)
+    async replaceIO(device, port, newname = '') {
       try {
-        await this.removeDevice(device.id) // remove old device
-        const newdevice = await this.createDeviceAt(device.x, device.y, false, { id, class: device.class }) // create new device
-        this.setDeviceName(newdevice.id, newname)
-        for (let input of inputs) { // reconnect new device
-          input.from = newdevice.id
-          await this.connect(input)
-        }
-        for (let output of outputs) { // reconnect new device
-          output.to = newdevice.id
-          await this.connect(output)
-        }
+        const res = await invoke('rebind_device', { id: device.id, port })
+        Object.assign(device, camelCase(res))
+        device.disconnected = !device.online
+        this.setDeviceName(device.id, newname)
       } catch (err) {
         appStore().handleError(err)
       }
@@ -299,6 +317,21 @@ export default defineStore('graph', {
       this.fitNode(deviceId)
     },
 
+    /**
+     * Replaces the out ports of devices with ports depending on their settings,
+     * edges from ports that no longer exist are disconnected
+     */
+    async setOutports(deviceId, ports = [{ id, name }]) {
+      const device = this.getNode(deviceId)
+      if (!device) return
+      const edges = this.edges.filter(e => e.from === deviceId && !ports.some(p => p.id === e.fromPort))
+      for (let edge of edges) {
+        await this.disconnect(edge)
+      }
+      device.outPorts = ports.map(p => ({ ...device.outPorts.find(o => o.id === p.id), ...p }))
+      this.fitNode(deviceId)
+    },
+
     async removeOutport(deviceId, id) {
       const device = this.getNode(deviceId)
       if (!device) return
@@ -229,6 +229,13 @@ export function stripPrefix(id) {
   return prefix ? id.slice(prefix.length) : id
 }
 
+/**
+ * Midi port bound to an input or output, older projects only have it in the id
+ */
+export function portOf(device) {
+  return device.port || stripPrefix(device.id)
+}
+
 export function camelCaseStr(str) {
   return str.replace(/_([a-z])/g, (_, letter) => letter.toUpperCase());
 }